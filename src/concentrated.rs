@@ -0,0 +1,619 @@
+use solana_program::program_error::ProgramError;
+use std::convert::TryFrom;
+
+use crate::{
+    curve::TradeDirection,
+    error::AMMErrors,
+    math,
+    state::{ConcentratedPool, Position, Tick, MAX_TICKS},
+};
+
+// Concentrated liquidity as in Uniswap v3. Prices are kept as the square
+// root of the Y per X price in Q64.64 fixed point, tick `i` is the price
+// 1.0001^i. Amounts paid to the pool are rounded up and amounts paid out
+// are rounded down.
+
+pub const MIN_TICK: i32 = -443_636;
+pub const MAX_TICK: i32 = 443_636;
+
+/// Sqrt prices of `MIN_TICK` and `MAX_TICK`
+pub const MIN_SQRT_PRICE: u128 = 4_295_048_016;
+pub const MAX_SQRT_PRICE: u128 = 79_226_673_521_066_979_257_578_248_091;
+
+pub const MAX_TICK_SPACING: u16 = 16_384;
+
+/// Least liquidity a position can hold unless it is empty. A position must
+/// also be worth, at the pool price, what this much liquidity holds over a
+/// range as wide at the price, see `check_position_value`.
+pub const MIN_POSITION_LIQUIDITY: u128 = 1_000_000_000;
+
+/// `2^64 / 1.0001^(2^i / 2)` for every bit `i` of a tick index
+const TICK_RATIOS: [u128; 19] = [
+    0xfffcb933bd6fad37,
+    0xfff97272373d4132,
+    0xfff2e50f5f656932,
+    0xffe5caca7e10e4e6,
+    0xffcb9843d60f6159,
+    0xff973b41fa98c081,
+    0xff2ea16466c96a38,
+    0xfe5dee046a99a2a8,
+    0xfcbe86c7900a88ae,
+    0xf987a7253ac41317,
+    0xf3392b0822b70005,
+    0xe7159475a2c29b74,
+    0xd097f3bdfd2022b8,
+    0xa9f746462d870fdf,
+    0x70d869a156d2a1b8,
+    0x31be135f97d08fd9,
+    0x9aa508b5b7a84e1,
+    0x5d6af8dedb8119,
+    0x2216e584f5fa,
+];
+
+/// `sqrt(1.0001^tick)` in Q64.64.
+pub fn sqrt_price_at_tick(tick: i32) -> Result<u128, ProgramError> {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
+        return Err(AMMErrors::InvalidTickRange.into());
+    }
+
+    // Both factors are at most 2^64, so the products fit into u128
+    let abs_tick = tick.unsigned_abs();
+    let mut ratio = if abs_tick & 1 == 1 {
+        TICK_RATIOS[0]
+    } else {
+        math::Q64
+    };
+    for (bit, tick_ratio) in TICK_RATIOS.iter().enumerate().skip(1) {
+        if abs_tick & (1 << bit) != 0 {
+            ratio = (ratio * tick_ratio) >> 64;
+        }
+    }
+
+    if tick > 0 {
+        ratio = u128::MAX / ratio;
+    }
+
+    Ok(ratio)
+}
+
+/// Greatest tick whose sqrt price is not above `sqrt_price`.
+pub fn tick_at_sqrt_price(sqrt_price: u128) -> Result<i32, ProgramError> {
+    if !(MIN_SQRT_PRICE..=MAX_SQRT_PRICE).contains(&sqrt_price) {
+        return Err(AMMErrors::InvalidSqrtPrice.into());
+    }
+
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let middle = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(middle)? <= sqrt_price {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+
+    Ok(low)
+}
+
+/// X held by `liquidity` between two sqrt prices,
+/// `L * (sqrt_b - sqrt_a) / (sqrt_a * sqrt_b)`.
+pub fn x_amount_delta(
+    sqrt_price_a: u128,
+    sqrt_price_b: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<u128, ProgramError> {
+    let (lower, upper) = (
+        sqrt_price_a.min(sqrt_price_b),
+        sqrt_price_a.max(sqrt_price_b),
+    );
+    if round_up {
+        math::mul_div_wide_ceil(
+            math::mul_div_wide_ceil(liquidity, upper - lower, upper)?,
+            math::Q64,
+            lower,
+        )
+    } else {
+        math::mul_div_wide(
+            math::mul_div_wide(liquidity, upper - lower, upper)?,
+            math::Q64,
+            lower,
+        )
+    }
+}
+
+/// Y held by `liquidity` between two sqrt prices, `L * (sqrt_b - sqrt_a)`.
+pub fn y_amount_delta(
+    sqrt_price_a: u128,
+    sqrt_price_b: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<u128, ProgramError> {
+    let (lower, upper) = (
+        sqrt_price_a.min(sqrt_price_b),
+        sqrt_price_a.max(sqrt_price_b),
+    );
+    if round_up {
+        math::mul_div_wide_ceil(liquidity, upper - lower, math::Q64)
+    } else {
+        math::mul_div_wide(liquidity, upper - lower, math::Q64)
+    }
+}
+
+/// Sqrt price after `amount_in` is added to the pool, moved as little as
+/// the rounding allows.
+fn next_sqrt_price_from_input(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount_in: u128,
+    direction: TradeDirection,
+) -> Result<u128, ProgramError> {
+    match direction {
+        // L / (L / sqrt_price + amount_in)
+        TradeDirection::XtoY => {
+            let denominator = math::mul_div_wide(liquidity, math::Q64, sqrt_price)?
+                .checked_add(amount_in)
+                .ok_or(AMMErrors::MathOverflow)?;
+            math::mul_div_wide_ceil(liquidity, math::Q64, denominator)
+        }
+        // sqrt_price + amount_in / L
+        TradeDirection::YtoX => sqrt_price
+            .checked_add(math::mul_div_wide(amount_in, math::Q64, liquidity)?)
+            .ok_or_else(|| AMMErrors::MathOverflow.into()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SwapStep {
+    pub sqrt_price_next: u128,
+    pub amount_in: u128,
+    pub amount_out: u128,
+    pub fee_amount: u128,
+}
+
+/// Trades `amount_remaining` of input, fee included, within one range of
+/// constant liquidity, stopping at `sqrt_price_target` at most.
+pub fn compute_swap_step(
+    sqrt_price_current: u128,
+    sqrt_price_target: u128,
+    liquidity: u128,
+    amount_remaining: u128,
+    (fee_numerator, fee_denominator): (u64, u64),
+    direction: TradeDirection,
+) -> Result<SwapStep, ProgramError> {
+    let fee_complement = u128::from(math::checked_sub(fee_denominator, fee_numerator)?);
+    let amount_remaining_less_fee =
+        math::mul_div_wide(amount_remaining, fee_complement, fee_denominator.into())?;
+
+    let amount_in_to_target = match direction {
+        TradeDirection::XtoY => {
+            x_amount_delta(sqrt_price_target, sqrt_price_current, liquidity, true)?
+        }
+        TradeDirection::YtoX => {
+            y_amount_delta(sqrt_price_current, sqrt_price_target, liquidity, true)?
+        }
+    };
+
+    let sqrt_price_next = if amount_remaining_less_fee >= amount_in_to_target {
+        sqrt_price_target
+    } else {
+        next_sqrt_price_from_input(
+            sqrt_price_current,
+            liquidity,
+            amount_remaining_less_fee,
+            direction,
+        )?
+    };
+    let reached_target = sqrt_price_next == sqrt_price_target;
+
+    let (amount_in, amount_out) = match direction {
+        TradeDirection::XtoY => (
+            if reached_target {
+                amount_in_to_target
+            } else {
+                x_amount_delta(sqrt_price_next, sqrt_price_current, liquidity, true)?
+            },
+            y_amount_delta(sqrt_price_next, sqrt_price_current, liquidity, false)?,
+        ),
+        TradeDirection::YtoX => (
+            if reached_target {
+                amount_in_to_target
+            } else {
+                y_amount_delta(sqrt_price_current, sqrt_price_next, liquidity, true)?
+            },
+            x_amount_delta(sqrt_price_current, sqrt_price_next, liquidity, false)?,
+        ),
+    };
+
+    // Whatever is left of an input that stops short of the target is fee
+    let fee_amount = if reached_target {
+        math::mul_div_wide_ceil(amount_in, fee_numerator.into(), fee_complement)?
+    } else {
+        amount_remaining
+            .checked_sub(amount_in)
+            .ok_or(AMMErrors::MathOverflow)?
+    };
+
+    Ok(SwapStep {
+        sqrt_price_next,
+        amount_in,
+        amount_out,
+        fee_amount,
+    })
+}
+
+/// Sells `amount_in` into the pool, crossing initialized ticks on the way,
+/// and returns the output amount. The whole input has to be traded, running
+/// out of liquidity is an error.
+pub fn swap(
+    pool: &mut ConcentratedPool,
+    amount_in: u64,
+    direction: TradeDirection,
+) -> Result<u64, ProgramError> {
+    let mut amount_remaining = u128::from(amount_in);
+    let mut amount_out = 0u128;
+
+    while amount_remaining > 0 {
+        let next_tick = match direction {
+            TradeDirection::XtoY => pool
+                .ticks
+                .iter()
+                .rposition(|tick| tick.index <= pool.tick_current),
+            TradeDirection::YtoX => pool
+                .ticks
+                .iter()
+                .position(|tick| tick.index > pool.tick_current),
+        };
+        let tick_target = match (next_tick, direction) {
+            (Some(position), _) => pool.ticks[position].index,
+            (None, TradeDirection::XtoY) => MIN_TICK,
+            (None, TradeDirection::YtoX) => MAX_TICK,
+        };
+        let sqrt_price_target = sqrt_price_at_tick(tick_target)?;
+
+        if next_tick.is_none() && pool.sqrt_price == sqrt_price_target {
+            return Err(AMMErrors::InsufficientLiquidity.into());
+        }
+
+        let step = compute_swap_step(
+            pool.sqrt_price,
+            sqrt_price_target,
+            pool.liquidity,
+            amount_remaining,
+            (pool.fee_numerator, pool.fee_denominator),
+            direction,
+        )?;
+
+        amount_remaining = step
+            .amount_in
+            .checked_add(step.fee_amount)
+            .and_then(|spent| amount_remaining.checked_sub(spent))
+            .ok_or(AMMErrors::MathOverflow)?;
+        amount_out = amount_out
+            .checked_add(step.amount_out)
+            .ok_or(AMMErrors::MathOverflow)?;
+
+        if pool.liquidity > 0 {
+            let fee_growth = math::mul_div_wide(step.fee_amount, math::Q64, pool.liquidity)?;
+            match direction {
+                TradeDirection::XtoY => {
+                    pool.fee_growth_global_x = pool.fee_growth_global_x.wrapping_add(fee_growth)
+                }
+                TradeDirection::YtoX => {
+                    pool.fee_growth_global_y = pool.fee_growth_global_y.wrapping_add(fee_growth)
+                }
+            }
+        }
+
+        pool.sqrt_price = step.sqrt_price_next;
+        if step.sqrt_price_next == sqrt_price_target {
+            if let Some(position) = next_tick {
+                cross_tick(pool, position, direction)?;
+            }
+            pool.tick_current = match direction {
+                TradeDirection::XtoY => tick_target - 1,
+                TradeDirection::YtoX => tick_target,
+            };
+        } else {
+            pool.tick_current = tick_at_sqrt_price(pool.sqrt_price)?;
+        }
+    }
+
+    math::to_u64(amount_out)
+}
+
+/// Moves the pool to the other side of an initialized tick.
+fn cross_tick(
+    pool: &mut ConcentratedPool,
+    position: usize,
+    direction: TradeDirection,
+) -> Result<(), ProgramError> {
+    let tick = &mut pool.ticks[position];
+    tick.fee_growth_outside_x = pool
+        .fee_growth_global_x
+        .wrapping_sub(tick.fee_growth_outside_x);
+    tick.fee_growth_outside_y = pool
+        .fee_growth_global_y
+        .wrapping_sub(tick.fee_growth_outside_y);
+
+    let liquidity_delta = match direction {
+        TradeDirection::XtoY => tick
+            .liquidity_net
+            .checked_neg()
+            .ok_or(AMMErrors::MathOverflow)?,
+        TradeDirection::YtoX => tick.liquidity_net,
+    };
+    pool.liquidity = add_liquidity_delta(pool.liquidity, liquidity_delta)?;
+
+    Ok(())
+}
+
+/// Adds `liquidity_delta` to a position and to the ticks it spans, settling
+/// the fees the position earned so far. Returns the X and Y amounts the
+/// position gains, rounded up, or loses, rounded down.
+pub fn modify_position(
+    pool: &mut ConcentratedPool,
+    position: &mut Position,
+    liquidity_delta: i128,
+) -> Result<(u64, u64), ProgramError> {
+    let position_liquidity = add_liquidity_delta(position.liquidity, liquidity_delta)?;
+    if position_liquidity != 0 && position_liquidity < MIN_POSITION_LIQUIDITY {
+        return Err(AMMErrors::LiquidityBelowMinimum.into());
+    }
+    if position_liquidity != 0 && liquidity_delta != 0 {
+        check_position_value(
+            pool,
+            position.tick_lower,
+            position.tick_upper,
+            position_liquidity,
+        )?;
+    }
+
+    if liquidity_delta != 0 {
+        update_tick(pool, position.tick_lower, liquidity_delta, false)?;
+        update_tick(pool, position.tick_upper, liquidity_delta, true)?;
+    }
+
+    let (fee_growth_inside_x, fee_growth_inside_y) =
+        fee_growth_inside(pool, position.tick_lower, position.tick_upper);
+    position.tokens_owed_x = math::checked_add(
+        position.tokens_owed_x,
+        math::to_u64(math::mul_div_wide(
+            position.liquidity,
+            fee_growth_inside_x.wrapping_sub(position.fee_growth_inside_x_last),
+            math::Q64,
+        )?)?,
+    )?;
+    position.tokens_owed_y = math::checked_add(
+        position.tokens_owed_y,
+        math::to_u64(math::mul_div_wide(
+            position.liquidity,
+            fee_growth_inside_y.wrapping_sub(position.fee_growth_inside_y_last),
+            math::Q64,
+        )?)?,
+    )?;
+    position.fee_growth_inside_x_last = fee_growth_inside_x;
+    position.fee_growth_inside_y_last = fee_growth_inside_y;
+    position.liquidity = position_liquidity;
+
+    // Ticks no position refers to any more are dropped
+    pool.ticks.retain(|tick| tick.liquidity_gross > 0);
+
+    if (position.tick_lower..position.tick_upper).contains(&pool.tick_current) {
+        pool.liquidity = add_liquidity_delta(pool.liquidity, liquidity_delta)?;
+    }
+
+    let (x_amount, y_amount) = range_amounts(
+        pool,
+        position.tick_lower,
+        position.tick_upper,
+        liquidity_delta.unsigned_abs(),
+        liquidity_delta > 0,
+    )?;
+
+    Ok((math::to_u64(x_amount)?, math::to_u64(y_amount)?))
+}
+
+/// X and Y held by `liquidity` between two ticks at the pool price.
+fn range_amounts(
+    pool: &ConcentratedPool,
+    tick_lower: i32,
+    tick_upper: i32,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<(u128, u128), ProgramError> {
+    let sqrt_price_lower = sqrt_price_at_tick(tick_lower)?;
+    let sqrt_price_upper = sqrt_price_at_tick(tick_upper)?;
+
+    Ok(if pool.tick_current < tick_lower {
+        (
+            x_amount_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up)?,
+            0,
+        )
+    } else if pool.tick_current < tick_upper {
+        (
+            x_amount_delta(pool.sqrt_price, sqrt_price_upper, liquidity, round_up)?,
+            y_amount_delta(sqrt_price_lower, pool.sqrt_price, liquidity, round_up)?,
+        )
+    } else {
+        (
+            0,
+            y_amount_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up)?,
+        )
+    })
+}
+
+/// Checks that a position is worth, at the pool price, at least what
+/// `MIN_POSITION_LIQUIDITY` holds over a range as wide starting at the
+/// price. A range far from the price holds next to nothing per unit of
+/// liquidity, so it needs that much more of it, and the `MAX_TICKS` slots
+/// of a pool cannot be taken up by cheap far away positions.
+fn check_position_value(
+    pool: &ConcentratedPool,
+    tick_lower: i32,
+    tick_upper: i32,
+    liquidity: u128,
+) -> Result<(), ProgramError> {
+    let width = tick_upper - tick_lower;
+    let reference_lower = pool.tick_current.max(MIN_TICK).min(MAX_TICK - width);
+    let minimum = range_amounts(
+        pool,
+        reference_lower,
+        reference_lower + width,
+        MIN_POSITION_LIQUIDITY,
+        false,
+    )?;
+
+    if value_at_price(
+        pool,
+        range_amounts(pool, tick_lower, tick_upper, liquidity, false)?,
+    ) < value_at_price(pool, minimum)
+    {
+        return Err(AMMErrors::LiquidityBelowMinimum.into());
+    }
+
+    Ok(())
+}
+
+/// X and Y amounts counted in the pricier of the two tokens at the pool
+/// price, so that the sum keeps its precision. A value too large to count
+/// is above any minimum.
+fn value_at_price(pool: &ConcentratedPool, (x_amount, y_amount): (u128, u128)) -> u128 {
+    let sqrt_price = pool.sqrt_price;
+    let (amount, other_amount) = if sqrt_price >= math::Q64 {
+        // X in Y is `x * sqrt_price^2`
+        (
+            y_amount,
+            math::mul_div_wide(x_amount, sqrt_price, math::Q64)
+                .and_then(|amount| math::mul_div_wide(amount, sqrt_price, math::Q64)),
+        )
+    } else {
+        // Y in X is `y / sqrt_price^2`
+        (
+            x_amount,
+            math::mul_div_wide(y_amount, math::Q64, sqrt_price)
+                .and_then(|amount| math::mul_div_wide(amount, math::Q64, sqrt_price)),
+        )
+    };
+
+    other_amount.map_or(u128::MAX, |other_amount| {
+        amount.saturating_add(other_amount)
+    })
+}
+
+/// Checks that a position range is ordered, in bounds and on the spacing.
+pub fn check_tick_range(
+    tick_lower: i32,
+    tick_upper: i32,
+    tick_spacing: u16,
+) -> Result<(), ProgramError> {
+    let spacing = i32::from(tick_spacing);
+    if tick_lower >= tick_upper
+        || tick_lower < MIN_TICK
+        || tick_upper > MAX_TICK
+        || tick_lower % spacing != 0
+        || tick_upper % spacing != 0
+    {
+        return Err(AMMErrors::InvalidTickRange.into());
+    }
+
+    Ok(())
+}
+
+fn update_tick(
+    pool: &mut ConcentratedPool,
+    index: i32,
+    liquidity_delta: i128,
+    upper: bool,
+) -> Result<(), ProgramError> {
+    let position = match pool.ticks.binary_search_by_key(&index, |tick| tick.index) {
+        Ok(position) => position,
+        Err(position) => {
+            if pool.ticks.len() == MAX_TICKS {
+                return Err(AMMErrors::TickListFull.into());
+            }
+
+            // Fees so far are counted below the tick by convention
+            let (fee_growth_outside_x, fee_growth_outside_y) = if index <= pool.tick_current {
+                (pool.fee_growth_global_x, pool.fee_growth_global_y)
+            } else {
+                (0, 0)
+            };
+            pool.ticks.insert(
+                position,
+                Tick {
+                    index,
+                    fee_growth_outside_x,
+                    fee_growth_outside_y,
+                    ..Tick::default()
+                },
+            );
+            position
+        }
+    };
+
+    let tick = &mut pool.ticks[position];
+    tick.liquidity_gross = add_liquidity_delta(tick.liquidity_gross, liquidity_delta)?;
+    tick.liquidity_net = if upper {
+        tick.liquidity_net.checked_sub(liquidity_delta)
+    } else {
+        tick.liquidity_net.checked_add(liquidity_delta)
+    }
+    .ok_or(AMMErrors::MathOverflow)?;
+
+    Ok(())
+}
+
+/// Fee growth per unit of liquidity between two ticks, Q64.64.
+fn fee_growth_inside(pool: &ConcentratedPool, tick_lower: i32, tick_upper: i32) -> (u128, u128) {
+    let outside = |index: i32| {
+        pool.ticks
+            .binary_search_by_key(&index, |tick| tick.index)
+            .map(|position| {
+                let tick = &pool.ticks[position];
+                (tick.fee_growth_outside_x, tick.fee_growth_outside_y)
+            })
+            .unwrap_or((0, 0))
+    };
+    let (global_x, global_y) = (pool.fee_growth_global_x, pool.fee_growth_global_y);
+
+    let (lower_x, lower_y) = outside(tick_lower);
+    let (below_x, below_y) = if pool.tick_current >= tick_lower {
+        (lower_x, lower_y)
+    } else {
+        (
+            global_x.wrapping_sub(lower_x),
+            global_y.wrapping_sub(lower_y),
+        )
+    };
+
+    let (upper_x, upper_y) = outside(tick_upper);
+    let (above_x, above_y) = if pool.tick_current < tick_upper {
+        (upper_x, upper_y)
+    } else {
+        (
+            global_x.wrapping_sub(upper_x),
+            global_y.wrapping_sub(upper_y),
+        )
+    };
+
+    (
+        global_x.wrapping_sub(below_x).wrapping_sub(above_x),
+        global_y.wrapping_sub(below_y).wrapping_sub(above_y),
+    )
+}
+
+fn add_liquidity_delta(liquidity: u128, liquidity_delta: i128) -> Result<u128, ProgramError> {
+    if liquidity_delta >= 0 {
+        liquidity.checked_add(liquidity_delta.unsigned_abs())
+    } else {
+        liquidity.checked_sub(liquidity_delta.unsigned_abs())
+    }
+    .ok_or_else(|| AMMErrors::MathOverflow.into())
+}
+
+/// Liquidity delta of a `u128` instruction amount, negated for withdrawals.
+pub fn liquidity_delta(liquidity: u128, decrease: bool) -> Result<i128, ProgramError> {
+    let delta = i128::try_from(liquidity).map_err(|_| AMMErrors::MathOverflow)?;
+    Ok(if decrease { -delta } else { delta })
+}
@@ -0,0 +1,462 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::program_error::ProgramError;
+use std::convert::TryFrom;
+
+use crate::{error::AMMErrors, math, state};
+
+/// Bounds of the StableSwap amplification coefficient.
+pub const MIN_AMP: u64 = 1;
+pub const MAX_AMP: u64 = 1_000_000;
+
+/// Weights of a weighted pool are percentages of the pool value
+pub const WEIGHT_TOTAL: u64 = 100;
+pub const MIN_WEIGHT: u64 = 1;
+
+const N_COINS: u128 = 2;
+const MAX_ITERATIONS: usize = 256;
+
+/// Invariant a pool trades on, stored in `state::AMM::curve_type`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveType {
+    /// `x * y = k`
+    ConstantProduct,
+    /// Curve-style invariant for pegged pairs, flat around the peg
+    StableSwap,
+    /// Balancer-style `x^w_x * y^w_y = k` with uneven weights
+    Weighted,
+}
+
+impl TryFrom<u8> for CurveType {
+    type Error = ProgramError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::ConstantProduct),
+            1 => Ok(Self::StableSwap),
+            2 => Ok(Self::Weighted),
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+}
+
+/// Curve chosen at `Initialize` together with its parameters. Encoded in
+/// the instruction with its `CurveType` as the tag.
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum CurveParameters {
+    ConstantProduct,
+    StableSwap {
+        /// Initial amplification coefficient, `MIN_AMP..=MAX_AMP`
+        amp: u64,
+    },
+    Weighted {
+        /// Share of the pool value held in X, e.g. 80 for an 80/20 pool
+        x_weight: u64,
+        /// Share of the pool value held in Y, adds up to `WEIGHT_TOTAL`
+        y_weight: u64,
+    },
+}
+
+impl CurveParameters {
+    pub fn curve_type(&self) -> CurveType {
+        match self {
+            Self::ConstantProduct => CurveType::ConstantProduct,
+            Self::StableSwap { .. } => CurveType::StableSwap,
+            Self::Weighted { .. } => CurveType::Weighted,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeDirection {
+    XtoY,
+    YtoX,
+}
+
+/// Pricing of a pool. Amounts passed in are already net of the trading fee,
+/// which the processor handles the same way for every curve.
+pub trait SwapCurve {
+    /// Output for `amount_in`, rounded down.
+    fn swap_out(
+        &self,
+        amount_in: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError>;
+
+    /// Input needed to receive `amount_out`, rounded up.
+    fn swap_in(
+        &self,
+        amount_out: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError>;
+
+    /// Marginal price of X in Y at the given reserves, Q64.64.
+    fn spot_price(&self, x_reserve: u64, y_reserve: u64) -> Result<u128, ProgramError>;
+
+    /// Reserves to deposit for `lp_amount` new LP tokens, rounded up.
+    fn deposit_amounts(
+        &self,
+        lp_amount: u64,
+        lp_supply: u64,
+        x_reserve: u64,
+        y_reserve: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        Ok((
+            math::mul_div_ceil(lp_amount.into(), x_reserve.into(), lp_supply.into())?,
+            math::mul_div_ceil(lp_amount.into(), y_reserve.into(), lp_supply.into())?,
+        ))
+    }
+
+    /// Reserves to withdraw for `lp_amount` burnt LP tokens, rounded down.
+    fn withdraw_amounts(
+        &self,
+        lp_amount: u64,
+        lp_supply: u64,
+        x_reserve: u64,
+        y_reserve: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        Ok((
+            math::mul_div_floor(lp_amount.into(), x_reserve.into(), lp_supply.into())?,
+            math::mul_div_floor(lp_amount.into(), y_reserve.into(), lp_supply.into())?,
+        ))
+    }
+}
+
+pub struct ConstantProductCurve;
+
+impl SwapCurve for ConstantProductCurve {
+    fn swap_out(
+        &self,
+        amount_in: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        _direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        math::mul_div_floor(
+            reserve_out.into(),
+            amount_in.into(),
+            u128::from(reserve_in) + u128::from(amount_in),
+        )
+    }
+
+    // Solves (in + amount_in) * (out - amount_out) = in * out for amount_in
+    fn swap_in(
+        &self,
+        amount_out: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        _direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        math::mul_div_ceil(
+            reserve_in.into(),
+            amount_out.into(),
+            math::checked_sub(reserve_out, amount_out)?.into(),
+        )
+    }
+
+    fn spot_price(&self, x_reserve: u64, y_reserve: u64) -> Result<u128, ProgramError> {
+        math::mul_div_wide(y_reserve.into(), math::Q64, x_reserve.into())
+    }
+}
+
+/// StableSwap invariant for two coins,
+/// `A * n^n * (x + y) + D = A * D * n^n + D^(n + 1) / (n^n * x * y)`.
+///
+/// Reserves are scaled to the larger of the two mint decimals first, so a
+/// pegged pair trades 1:1 whatever the precision of each mint.
+pub struct StableSwapCurve {
+    pub amp: u64,
+    pub x_decimals: u8,
+    pub y_decimals: u8,
+}
+
+impl StableSwapCurve {
+    fn multipliers(&self, direction: TradeDirection) -> Result<(u128, u128), ProgramError> {
+        let decimals = self.x_decimals.max(self.y_decimals);
+        let multiplier = |mint_decimals: u8| {
+            10u128
+                .checked_pow((decimals - mint_decimals).into())
+                .ok_or(AMMErrors::MathOverflow)
+        };
+        let (x_multiplier, y_multiplier) =
+            (multiplier(self.x_decimals)?, multiplier(self.y_decimals)?);
+
+        Ok(match direction {
+            TradeDirection::XtoY => (x_multiplier, y_multiplier),
+            TradeDirection::YtoX => (y_multiplier, x_multiplier),
+        })
+    }
+
+    fn normalize(amount: u64, multiplier: u128) -> Result<u128, ProgramError> {
+        u128::from(amount)
+            .checked_mul(multiplier)
+            .ok_or_else(|| AMMErrors::MathOverflow.into())
+    }
+
+    fn times_n(value: u128) -> Result<u128, ProgramError> {
+        value
+            .checked_mul(N_COINS)
+            .ok_or_else(|| AMMErrors::MathOverflow.into())
+    }
+
+    fn leverage(&self) -> u128 {
+        u128::from(self.amp) * N_COINS
+    }
+
+    /// Invariant `D` of the normalized reserves, one unit above the
+    /// iteration result so that rounding never shrinks the pool.
+    fn compute_d(&self, x: u128, y: u128) -> Result<u128, ProgramError> {
+        let sum = x.checked_add(y).ok_or(AMMErrors::MathOverflow)?;
+        if sum == 0 {
+            return Ok(0);
+        }
+
+        let leverage = self.leverage();
+        let mut d = sum;
+        for _ in 0..MAX_ITERATIONS {
+            let mut d_product = d;
+            d_product = math::mul_div_wide(d_product, d, Self::times_n(x)?)?;
+            d_product = math::mul_div_wide(d_product, d, Self::times_n(y)?)?;
+
+            let d_previous = d;
+            let numerator = leverage
+                .checked_mul(sum)
+                .and_then(|n| n.checked_add(d_product.checked_mul(N_COINS)?))
+                .ok_or(AMMErrors::MathOverflow)?;
+            let denominator = (leverage - 1)
+                .checked_mul(d)
+                .and_then(|n| n.checked_add(d_product.checked_mul(N_COINS + 1)?))
+                .ok_or(AMMErrors::MathOverflow)?;
+            d = math::mul_div_wide(numerator, d, denominator)?;
+
+            if d.max(d_previous) - d.min(d_previous) <= 1 {
+                break;
+            }
+        }
+
+        d.checked_add(1)
+            .ok_or_else(|| AMMErrors::MathOverflow.into())
+    }
+
+    /// Reserve of one coin that keeps `D` given the other coin's reserve.
+    ///
+    /// Newton's method approaches the root from above, rounding every step
+    /// up keeps it there, so the pool is never left with less than `D` needs.
+    fn compute_y(&self, x: u128, d: u128) -> Result<u128, ProgramError> {
+        let leverage = self.leverage();
+        let mut c = d;
+        c = math::mul_div_wide_ceil(c, d, Self::times_n(x)?)?;
+        c = math::mul_div_wide_ceil(c, d, Self::times_n(leverage)?)?;
+        let b = x.checked_add(d / leverage).ok_or(AMMErrors::MathOverflow)?;
+
+        let mut y = d;
+        for _ in 0..MAX_ITERATIONS {
+            let y_previous = y;
+            // y = (y^2 + c) / (2y + b - D)
+            let denominator = Self::times_n(y)?
+                .checked_add(b)
+                .and_then(|n| n.checked_sub(d))
+                .ok_or(AMMErrors::MathOverflow)?;
+            y = math::mul_div_wide_ceil(y, y, denominator)?
+                .checked_add(math::mul_div_wide_ceil(c, 1, denominator)?)
+                .ok_or(AMMErrors::MathOverflow)?;
+
+            if y.max(y_previous) - y.min(y_previous) <= 1 {
+                break;
+            }
+        }
+
+        Ok(y)
+    }
+}
+
+impl SwapCurve for StableSwapCurve {
+    fn swap_out(
+        &self,
+        amount_in: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        let (multiplier_in, multiplier_out) = self.multipliers(direction)?;
+        let reserve_in = Self::normalize(reserve_in, multiplier_in)?;
+        let reserve_out = Self::normalize(reserve_out, multiplier_out)?;
+        let amount_in = Self::normalize(amount_in, multiplier_in)?;
+
+        let d = self.compute_d(reserve_in, reserve_out)?;
+        // One unit more left in the pool covers the rounding of the iteration
+        let new_reserve_in = reserve_in
+            .checked_add(amount_in)
+            .ok_or(AMMErrors::MathOverflow)?;
+        let new_reserve_out = self.compute_y(new_reserve_in, d)? + 1;
+
+        math::mul_div_floor(
+            reserve_out.saturating_sub(new_reserve_out),
+            1,
+            multiplier_out,
+        )
+    }
+
+    fn swap_in(
+        &self,
+        amount_out: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        let (multiplier_in, multiplier_out) = self.multipliers(direction)?;
+        let reserve_in = Self::normalize(reserve_in, multiplier_in)?;
+        let reserve_out = Self::normalize(reserve_out, multiplier_out)?;
+        let amount_out = Self::normalize(amount_out, multiplier_out)?;
+
+        let d = self.compute_d(reserve_in, reserve_out)?;
+        let new_reserve_out = reserve_out
+            .checked_sub(amount_out)
+            .ok_or(AMMErrors::MathOverflow)?;
+        let new_reserve_in = self.compute_y(new_reserve_out, d)? + 1;
+
+        math::mul_div_ceil(new_reserve_in.saturating_sub(reserve_in), 1, multiplier_in)
+    }
+
+    // -dy/dx of the invariant, (A n^n + D^3 / (4 x^2 y)) / (A n^n + D^3 / (4 x y^2))
+    // on normalized reserves, scaled back to the precision of the mints
+    fn spot_price(&self, x_reserve: u64, y_reserve: u64) -> Result<u128, ProgramError> {
+        let (x_multiplier, y_multiplier) = self.multipliers(TradeDirection::XtoY)?;
+        let x = Self::normalize(x_reserve, x_multiplier)?;
+        let y = Self::normalize(y_reserve, y_multiplier)?;
+
+        let d = self.compute_d(x, y)?;
+        let mut d_product = d;
+        d_product = math::mul_div_wide(d_product, d, Self::times_n(x)?)?;
+        d_product = math::mul_div_wide(d_product, d, Self::times_n(y)?)?;
+
+        let leverage = self.leverage();
+        let numerator = leverage
+            .checked_add(d_product / x)
+            .ok_or(AMMErrors::MathOverflow)?;
+        let denominator = leverage
+            .checked_add(d_product / y)
+            .ok_or(AMMErrors::MathOverflow)?;
+        let price = math::mul_div_wide(numerator, math::Q64, denominator)?;
+
+        math::mul_div_wide(price, x_multiplier, y_multiplier)
+    }
+}
+
+/// Weighted constant mean invariant `x^w_x * y^w_y = k`.
+///
+/// The spot price is `(y / w_y) / (x / w_x)`, so an 80/20 pool is balanced
+/// with four times more value in X than in Y. Deposits and withdrawals stay
+/// pro-rata, which keeps the price. The power is only precise near 1, so a
+/// trade may take at most half of the input reserve and a third of the
+/// output reserve, as in Balancer.
+pub struct WeightedCurve {
+    pub x_weight: u64,
+    pub y_weight: u64,
+}
+
+impl WeightedCurve {
+    fn weights(&self, direction: TradeDirection) -> (u64, u64) {
+        match direction {
+            TradeDirection::XtoY => (self.x_weight, self.y_weight),
+            TradeDirection::YtoX => (self.y_weight, self.x_weight),
+        }
+    }
+}
+
+impl SwapCurve for WeightedCurve {
+    // out = reserve_out * (1 - (reserve_in / (reserve_in + amount_in))^(w_in / w_out))
+    fn swap_out(
+        &self,
+        amount_in: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        if amount_in > reserve_in / 2 {
+            return Err(AMMErrors::InsufficientLiquidity.into());
+        }
+
+        let (weight_in, weight_out) = self.weights(direction);
+        let base = math::mul_div_wide_ceil(
+            reserve_in.into(),
+            math::FIXED_ONE,
+            u128::from(reserve_in) + u128::from(amount_in),
+        )?;
+        let power = math::pow_ceil(base, weight_in, weight_out)?.min(math::FIXED_ONE);
+
+        math::mul_div_floor(reserve_out.into(), math::FIXED_ONE - power, math::FIXED_ONE)
+    }
+
+    // in = reserve_in * ((reserve_out / (reserve_out - amount_out))^(w_out / w_in) - 1)
+    fn swap_in(
+        &self,
+        amount_out: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        if amount_out > reserve_out / 3 {
+            return Err(AMMErrors::InsufficientLiquidity.into());
+        }
+
+        let (weight_in, weight_out) = self.weights(direction);
+        let base = math::mul_div_wide_ceil(
+            reserve_out.into(),
+            math::FIXED_ONE,
+            (reserve_out - amount_out).into(),
+        )?;
+        let power = math::pow_ceil(base, weight_out, weight_in)?;
+
+        math::mul_div_ceil(reserve_in.into(), power - math::FIXED_ONE, math::FIXED_ONE)
+    }
+
+    fn spot_price(&self, x_reserve: u64, y_reserve: u64) -> Result<u128, ProgramError> {
+        math::mul_div_wide(
+            u128::from(y_reserve) * u128::from(self.x_weight),
+            math::Q64,
+            u128::from(x_reserve) * u128::from(self.y_weight),
+        )
+    }
+}
+
+/// StableSwap amplification at `now`, linearly ramped by `RampAmplification`.
+pub fn amplification(amm_data: &state::AMM, now: i64) -> u64 {
+    let (initial, target) = (amm_data.initial_amp, amm_data.target_amp);
+
+    if now >= amm_data.ramp_stop_ts || amm_data.ramp_stop_ts <= amm_data.ramp_start_ts {
+        return target;
+    }
+    if now <= amm_data.ramp_start_ts {
+        return initial;
+    }
+
+    let elapsed = (now - amm_data.ramp_start_ts) as u128;
+    let duration = (amm_data.ramp_stop_ts - amm_data.ramp_start_ts) as u128;
+
+    // Both bounds are u64, so the interpolated value fits as well
+    if target >= initial {
+        initial + (u128::from(target - initial) * elapsed / duration) as u64
+    } else {
+        initial - (u128::from(initial - target) * elapsed / duration) as u64
+    }
+}
+
+/// Curve the pool was initialized with, as of the `now` unix timestamp.
+pub fn swap_curve(amm_data: &state::AMM, now: i64) -> Result<Box<dyn SwapCurve>, ProgramError> {
+    Ok(match CurveType::try_from(amm_data.curve_type)? {
+        CurveType::ConstantProduct => Box::new(ConstantProductCurve),
+        CurveType::StableSwap => Box::new(StableSwapCurve {
+            amp: amplification(amm_data, now),
+            x_decimals: amm_data.x_decimals,
+            y_decimals: amm_data.y_decimals,
+        }),
+        CurveType::Weighted => Box::new(WeightedCurve {
+            x_weight: amm_data.x_weight,
+            y_weight: amm_data.y_weight,
+        }),
+    })
+}
@@ -1,3 +1,7 @@
+// entrypoint! checks the custom-heap and custom-panic features of the
+// program crate, which this program does not define
+#![allow(unexpected_cfgs)]
+
 use solana_program::{
     account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, pubkey::Pubkey,
 };
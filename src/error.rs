@@ -15,6 +15,87 @@ pub enum AMMErrors {
 
     #[error("Token mint mismatch")]
     TokenMintMismatch,
+
+    #[error("Vault account mismatch")]
+    VaultAccountMismatch,
+
+    #[error("Slippage limit exceeded")]
+    ExceededSlippage,
+
+    #[error("Output amount is below the minimum")]
+    InsufficientOutputAmount,
+
+    #[error("Input amount is above the maximum")]
+    ExcessiveInputAmount,
+
+    #[error("Not enough liquidity in the pool")]
+    InsufficientLiquidity,
+
+    #[error("Invalid fee")]
+    InvalidFee,
+
+    #[error("Signer is not the pool authority")]
+    Unauthorized,
+
+    #[error("Pool is paused")]
+    PoolPaused,
+
+    #[error("Account must be a signer")]
+    AccountNotSigner,
+
+    #[error("Account must be writable")]
+    AccountNotWritable,
+
+    #[error("Account has an unexpected owner")]
+    InvalidAccountOwner,
+
+    #[error("Unexpected program account")]
+    InvalidProgramAccount,
+
+    #[error("Math overflow")]
+    MathOverflow,
+
+    #[error("Amplification coefficient out of bounds")]
+    InvalidAmplification,
+
+    #[error("Not supported by the pool curve")]
+    InvalidCurve,
+
+    #[error("Invalid pool weights")]
+    InvalidWeights,
+
+    #[error("Invalid tick range")]
+    InvalidTickRange,
+
+    #[error("Sqrt price out of bounds")]
+    InvalidSqrtPrice,
+
+    #[error("Pool has no room for another initialized tick")]
+    TickListFull,
+
+    #[error("Position account mismatch")]
+    PositionMismatch,
+
+    #[error("Observations account mismatch")]
+    ObservationsMismatch,
+
+    #[error("Observations account is not larger than the current one")]
+    InvalidObservationsCapacity,
+
+    #[error("Flash loan was not repaid with its fee")]
+    FlashLoanNotRepaid,
+
+    #[error("Account state layout is outdated, run MigrateState")]
+    OutdatedState,
+
+    #[error("Transaction expired")]
+    Expired,
+
+    #[error("Position liquidity is below the minimum")]
+    LiquidityBelowMinimum,
+
+    #[error("Deposit amount is zero")]
+    ZeroDeposit,
 }
 
 impl From<AMMErrors> for ProgramError {
@@ -1,17 +1,30 @@
 use solana_program::{
+    bpf_loader_upgradeable,
     instruction::{AccountMeta, Instruction},
     program_error::ProgramError,
     pubkey::Pubkey,
     system_program, sysvar,
 };
 
-use crate::error::AMMErrors::{InvalidInstructionData, InvalidInstructionMethodID};
-use std::mem::size_of;
+use borsh::{BorshDeserialize, BorshSerialize};
 
-#[derive(Debug)]
+use crate::{
+    curve::CurveParameters,
+    error::AMMErrors::{InvalidInstructionData, InvalidInstructionMethodID},
+    pda,
+};
+
+/// Instructions are Borsh encoded, the tag byte being the index of the
+/// variant, so new instructions go at the end.
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum AMMInstruction {
     /// Initialize AMM
     ///
+    /// The AMM data account and the vaults are PDAs derived from the sorted
+    /// mint pair and `pool_index`, so one program can host many pools.
+    /// The pool LP mint is created here as well and the owner receives
+    /// `state::INITIAL_LP_SUPPLY` shares into a new LP token account.
+    /// Both initial token accounts must hold a non-zero amount.
     ///
     /// Accounts expected:
     ///
@@ -23,62 +36,563 @@ pub enum AMMInstruction {
     /// 5. `[writable]` Initial Y token account
     /// 6. `[writable]` Y token vault
     /// 7. `[]` Y token mint
-    /// 8. `[]` System program`
-    /// 9. `[]` Rent sysvar`
-    /// 10. `[]` Token program`
-    Initialize,
+    /// 8. `[writable]` LP token mint
+    /// 9. `[writable, signer]` Owner LP token account, uninitialized
+    /// 10. `[]` System program`
+    /// 11. `[]` Rent sysvar`
+    /// 12. `[]` Token program`
+    Initialize {
+        /// Distinguishes pools created for the same mint pair
+        pool_index: u16,
+        /// Trading fee numerator, e.g. 30 for 0.3% in basis points
+        fee_numerator: u64,
+        /// Trading fee denominator, e.g. 10_000 for basis points
+        fee_denominator: u64,
+        /// Share of the trading fee accrued to the protocol, e.g. 1 of 6
+        protocol_fee_numerator: u64,
+        /// Protocol fee share denominator
+        protocol_fee_denominator: u64,
+        /// Invariant the pool trades on and its parameters
+        curve: CurveParameters,
+    },
 
     /// Exchange
     ///
+    /// `amount_in` is taken straight from the user's token account, which
+    /// keeps the rest of its balance.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` User
+    /// 1. `[writable]` AMM data account
+    /// 2. `[writable]` First token user account
+    /// 3. `[writable]` Second token user account
+    /// 4. `[writable]` X token vault
+    /// 5. `[writable]` Y token vault
+    /// 6. `[]` Token program`
+    /// 7. `[writable]` Optional: observations account of the pool, a price
+    ///    sample is recorded in it
+    Exchange {
+        /// Amount of the first token to sell
+        amount_in: u64,
+        /// Minimum amount of the second token to receive
+        minimum_amount_out: u64,
+        /// Unix timestamp after which the exchange fails, never if none
+        expires_at: Option<i64>,
+    },
+
+    /// Deposit both tokens in the current pool ratio and mint LP shares
+    ///
+    /// A drained pool without LP tokens left takes the maximum amounts as
+    /// they are, like a new pool.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` User
+    /// 1. `[writable]` AMM data account
+    /// 2. `[writable]` X token user account
+    /// 3. `[writable]` Y token user account
+    /// 4. `[writable]` X token vault
+    /// 5. `[writable]` Y token vault
+    /// 6. `[writable]` LP token mint
+    /// 7. `[writable]` LP token user account
+    /// 8. `[]` Token program`
+    /// 9. `[writable]` Optional: observations account of the pool, a price
+    ///    sample is recorded in it
+    AddLiquidity {
+        /// Amount of LP tokens to mint
+        lp_amount: u64,
+        /// Maximum X tokens to deposit
+        maximum_x_amount: u64,
+        /// Maximum Y tokens to deposit
+        maximum_y_amount: u64,
+    },
+
+    /// Burn LP shares and withdraw the pro-rata part of both reserves
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` User
+    /// 1. `[writable]` AMM data account
+    /// 2. `[writable]` X token user account
+    /// 3. `[writable]` Y token user account
+    /// 4. `[writable]` X token vault
+    /// 5. `[writable]` Y token vault
+    /// 6. `[writable]` LP token mint
+    /// 7. `[writable]` LP token user account
+    /// 8. `[]` Token program`
+    /// 9. `[writable]` Optional: observations account of the pool, a price
+    ///    sample is recorded in it
+    RemoveLiquidity {
+        /// Amount of LP tokens to burn
+        lp_amount: u64,
+        /// Minimum X tokens to receive
+        minimum_x_amount: u64,
+        /// Minimum Y tokens to receive
+        minimum_y_amount: u64,
+    },
+
+    /// Exchange for an exact amount of the second token
+    ///
+    /// Only the input required by the pool curve is taken from
+    /// the user's first token account, never more than `maximum_amount_in`.
     ///
     /// Accounts expected:
     ///
     /// 0. `[signer]` User
     /// 1. `[writable]` AMM data account
-    /// 2. `[writable]` First token temp account
+    /// 2. `[writable]` First token user account
+    /// 3. `[writable]` Second token user account
+    /// 4. `[writable]` X token vault
+    /// 5. `[writable]` Y token vault
+    /// 6. `[]` Token program`
+    /// 7. `[writable]` Optional: observations account of the pool, a price
+    ///    sample is recorded in it
+    ExchangeExactOut {
+        /// Amount of the second token to receive
+        amount_out: u64,
+        /// Maximum amount of the first token to sell
+        maximum_amount_in: u64,
+    },
+
+    /// Send the accrued protocol fees to the treasury token accounts
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    /// 2. `[writable]` X token vault
+    /// 3. `[writable]` Y token vault
+    /// 4. `[writable]` X token treasury account
+    /// 5. `[writable]` Y token treasury account
+    /// 6. `[]` Token program`
+    CollectProtocolFees,
+
+    /// Propose a new pool authority, which takes over once it signs
+    /// `AcceptAuthority`. Proposing the default pubkey cancels the transfer.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    SetAuthority {
+        /// Account that has to accept the authority
+        new_authority: Pubkey,
+    },
+
+    /// Accept the authority proposed by `SetAuthority`
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pending pool authority
+    /// 1. `[writable]` AMM data account
+    AcceptAuthority,
+
+    /// Update the trading and protocol fees of the pool
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    SetFees {
+        /// Trading fee numerator
+        fee_numerator: u64,
+        /// Trading fee denominator
+        fee_denominator: u64,
+        /// Protocol fee share numerator
+        protocol_fee_numerator: u64,
+        /// Protocol fee share denominator
+        protocol_fee_denominator: u64,
+    },
+
+    /// Halt exchanges and deposits, withdrawals stay open. Concentrated
+    /// pools have no authority and cannot be halted.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    Pause,
+
+    /// Resume exchanges and deposits
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    Unpause,
+
+    /// Move the StableSwap amplification linearly from its current value
+    /// to `target_amp`, reached at `ramp_stop_ts`. A stop time in the past
+    /// applies the target at once.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    RampAmplification {
+        /// Amplification at the end of the ramp, `MIN_AMP..=MAX_AMP`
+        target_amp: u64,
+        /// Unix timestamp the target is reached at
+        ramp_stop_ts: i64,
+    },
+
+    /// Initialize a concentrated liquidity pool
+    ///
+    /// A separate pool type next to the `Initialize` one, with its data
+    /// account derived from the `concentrated` seed. It starts empty,
+    /// liquidity comes from positions.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` Owner, pays for the accounts
+    /// 1. `[writable]` Concentrated pool account
+    /// 2. `[writable]` X token vault
+    /// 3. `[]` X token mint
+    /// 4. `[writable]` Y token vault
+    /// 5. `[]` Y token mint
+    /// 6. `[]` System program`
+    /// 7. `[]` Rent sysvar`
+    /// 8. `[]` Token program`
+    InitializeConcentrated {
+        /// Distinguishes pools created for the same mint pair
+        pool_index: u16,
+        /// Trading fee numerator
+        fee_numerator: u64,
+        /// Trading fee denominator
+        fee_denominator: u64,
+        /// Position bounds must be multiples of it, `1..=MAX_TICK_SPACING`
+        tick_spacing: u16,
+        /// Square root of the initial Y per X price, Q64.64
+        initial_sqrt_price: u128,
+    },
+
+    /// Create an empty position of the owner between two ticks
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` Owner, pays for the account
+    /// 1. `[]` Concentrated pool account
+    /// 2. `[writable]` Position account
+    /// 3. `[]` System program`
+    OpenPosition {
+        /// Lower bound of the price range
+        tick_lower: i32,
+        /// Upper bound of the price range
+        tick_upper: i32,
+    },
+
+    /// Deposit the X and Y amounts backing `liquidity` in the position range
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Position owner
+    /// 1. `[writable]` Concentrated pool account
+    /// 2. `[writable]` Position account
+    /// 3. `[writable]` X token owner account
+    /// 4. `[writable]` Y token owner account
+    /// 5. `[writable]` X token vault
+    /// 6. `[writable]` Y token vault
+    /// 7. `[]` Token program`
+    IncreaseLiquidity {
+        /// Liquidity to add to the position
+        liquidity: u128,
+        /// Maximum X tokens to deposit
+        maximum_x_amount: u64,
+        /// Maximum Y tokens to deposit
+        maximum_y_amount: u64,
+    },
+
+    /// Withdraw the X and Y amounts backing `liquidity` of the position.
+    /// Earned fees stay in the position until `CollectPositionFees`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Position owner
+    /// 1. `[writable]` Concentrated pool account
+    /// 2. `[writable]` Position account
+    /// 3. `[writable]` X token owner account
+    /// 4. `[writable]` Y token owner account
+    /// 5. `[writable]` X token vault
+    /// 6. `[writable]` Y token vault
+    /// 7. `[]` Token program`
+    DecreaseLiquidity {
+        /// Liquidity to remove from the position
+        liquidity: u128,
+        /// Minimum X tokens to receive
+        minimum_x_amount: u64,
+        /// Minimum Y tokens to receive
+        minimum_y_amount: u64,
+    },
+
+    /// Send the fees earned by a position to its owner
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Position owner
+    /// 1. `[writable]` Concentrated pool account
+    /// 2. `[writable]` Position account
+    /// 3. `[writable]` X token owner account
+    /// 4. `[writable]` Y token owner account
+    /// 5. `[writable]` X token vault
+    /// 6. `[writable]` Y token vault
+    /// 7. `[]` Token program`
+    CollectPositionFees,
+
+    /// Exchange against a concentrated liquidity pool, crossing as many
+    /// initialized ticks as the input needs
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` User
+    /// 1. `[writable]` Concentrated pool account
+    /// 2. `[writable]` First token user account
     /// 3. `[writable]` Second token user account
     /// 4. `[writable]` X token vault
     /// 5. `[writable]` Y token vault
     /// 6. `[]` Token program`
-    Exchange,
+    ExchangeConcentrated {
+        /// Amount of the first token to sell
+        amount_in: u64,
+        /// Minimum amount of the second token to receive
+        minimum_amount_out: u64,
+    },
+
+    /// Exchange through a route of `AMM` pools in one go
+    ///
+    /// Every hop sells the output of the previous one, moved straight from
+    /// vault to vault. Only the final output is checked against
+    /// `minimum_amount_out`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` User
+    /// 1. `[writable]` Source token user account
+    /// 2. `[writable]` Destination token user account
+    /// 3. `[]` Token program`
+    ///
+    /// Then for every hop, in route order:
+    ///
+    /// 0. `[writable]` AMM data account
+    /// 1. `[writable]` X token vault
+    /// 2. `[writable]` Y token vault
+    /// 3. `[writable]` Optional: observations account of the pool, a price
+    ///    sample is recorded in it
+    RouteExchange {
+        /// Amount of the source token to sell
+        amount_in: u64,
+        /// Minimum amount of the destination token to receive
+        minimum_amount_out: u64,
+    },
+
+    /// Start recording price samples of the pool into an observations
+    /// account, see `state::Observations`
+    ///
+    /// The account is created beforehand by the client, owned by this
+    /// program, rent exempt and large enough for at least one sample.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` Pool authority
+    /// 1. `[writable]` AMM data account
+    /// 2. `[writable]` Observations account, uninitialized
+    InitializeObservations,
+
+    /// Move the samples to a larger observations account and close the
+    /// current one. Accounts cannot be resized in place.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` Pool authority, receives the lamports of
+    ///    the current observations account
+    /// 1. `[writable]` AMM data account
+    /// 2. `[writable]` Current observations account
+    /// 3. `[writable]` New observations account, uninitialized
+    GrowObservations,
+
+    /// Lend `amount` of a pool token for the rest of the instruction
+    ///
+    /// The loan is sent to the borrower token account, then the receiver
+    /// program is called with `FlashLoanCallback` and must pay the amount
+    /// back into the vault together with the pool trading fee on it, which
+    /// is added to the reserves.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` AMM data account
+    /// 1. `[writable]` X token vault
+    /// 2. `[writable]` Y token vault
+    /// 3. `[writable]` Borrower token account
+    /// 4. `[]` Receiver program
+    /// 5. `[]` Token program`
+    /// 6. `[writable]` Observations account of the pool, the default
+    ///    pubkey if it records none. A price sample is recorded in it.
+    ///    Not optional here, the receiver accounts follow it.
+    /// 7. ..7+N `[]` N accounts passed on to the receiver program
+    FlashLoan {
+        /// Amount to borrow
+        amount: u64,
+        /// Mint of the pool token to borrow
+        token: Pubkey,
+    },
+
+    /// Set the stored reserves to the vault balances, less the protocol fees
+    /// held in them, taking in tokens sent to the vaults directly
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` AMM data account
+    /// 1. `[writable]` X token vault
+    /// 2. `[writable]` Y token vault
+    /// 3. `[writable]` Optional: observations account of the pool, a price
+    ///    sample is recorded in it
+    Sync,
+
+    /// Send whatever the vaults hold above the stored reserves and protocol
+    /// fees to the given token accounts
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` AMM data account
+    /// 1. `[writable]` X token vault
+    /// 2. `[writable]` Y token vault
+    /// 3. `[writable]` X token destination account
+    /// 4. `[writable]` Y token destination account
+    /// 5. `[]` Token program`
+    Skim,
+
+    /// Upgrade an AMM data account of an older state layout version to the
+    /// current one. Does nothing to accounts already current.
+    ///
+    /// The only older layout is version 1, which is too small for the
+    /// current one. Accounts cannot grow in this runtime, so instead of being
+    /// reallocated in place its pool moves: into a new AMM data account, the
+    /// `pool_index`-th of its mint pair, with new vaults and an LP mint. The
+    /// tokens of the old vaults are moved over and the old accounts closed.
+    /// Nobody held a share of a version 1 pool, so its `INITIAL_LP_SUPPLY`
+    /// LP tokens go to an account owned by the new pool, which never signs
+    /// for it. Only the upgrade authority of the program can move the pool,
+    /// and becomes its authority. The pool starts without fees.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` AMM data account
+    ///
+    /// and for version 1 accounts:
+    ///
+    /// 1. `[writable, signer]` Upgrade authority of the program, pays for the
+    ///    new accounts and gets the lamports of the closed ones
+    /// 2. `[writable]` New AMM data account
+    /// 3. `[writable]` X token vault of version 1
+    /// 4. `[writable]` Y token vault of version 1
+    /// 5. `[writable]` New X token vault
+    /// 6. `[writable]` New Y token vault
+    /// 7. `[]` X token mint
+    /// 8. `[]` Y token mint
+    /// 9. `[writable]` LP token mint
+    /// 10. `[writable, signer]` LP token account to create, owned by the new pool
+    /// 11. `[]` System program`
+    /// 12. `[]` Rent sysvar`
+    /// 13. `[]` Token program`
+    /// 14. `[]` Program data account of the program
+    MigrateState {
+        /// Index of the new pool of a version 1 account, unused otherwise
+        pool_index: u16,
+    },
 }
 
 impl AMMInstruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (tag, _) = input.split_first().ok_or(InvalidInstructionData)?;
+        let tag = input.get(..1).ok_or(InvalidInstructionData)?;
+
+        // Reading the tag alone fails on missing fields, and on an unknown
+        // variant only when the tag is the unknown one, not a nested enum
+        // like `CurveParameters`
+        if let Err(err) = Self::deserialize(&mut &tag[..]) {
+            if err.to_string().starts_with(UNKNOWN_VARIANT_ERROR) {
+                return Err(InvalidInstructionMethodID.into());
+            }
+        }
 
-        Ok(match tag {
-            0 => Self::Initialize,
-            1 => Self::Exchange,
-            _ => return Err(InvalidInstructionMethodID.into()),
-        })
+        unpack_exact(input)
     }
 
     pub fn pack(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(size_of::<Self>());
-        match self {
-            Self::Initialize => buf.push(0),
-            Self::Exchange => buf.push(1),
-        }
-        buf
+        self.try_to_vec().unwrap()
+    }
+}
+
+/// Instruction data `FlashLoan` calls the receiver program with.
+///
+/// The receiver gets the borrower token account, the vault lent from and
+/// the token program, followed by the extra accounts of `FlashLoan`, and has
+/// to transfer `amount + fee` back into the vault before it returns.
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct FlashLoanCallback {
+    /// Amount lent
+    pub amount: u64,
+    /// Fee owed on top of it
+    pub fee: u64,
+}
+
+impl FlashLoanCallback {
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        unpack_exact(input)
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
     }
 }
 
+/// Start of the message of the error the derived `BorshDeserialize` of an
+/// enum returns for an unknown tag
+const UNKNOWN_VARIANT_ERROR: &str = "Unexpected variant index";
+
+/// Deserializes instruction data that must be consumed whole, so that
+/// truncated and trailing bytes are both `InvalidInstructionData`.
+fn unpack_exact<T: BorshDeserialize>(input: &[u8]) -> Result<T, ProgramError> {
+    T::try_from_slice(input).map_err(|_| InvalidInstructionData.into())
+}
+
 /// Creates a `Initialize` instruction.
+#[allow(clippy::too_many_arguments)]
 pub fn initialize_amm(
     owner_pubkey: &Pubkey,
     temp_x_token: &Pubkey,
     x_mint: &Pubkey,
     temp_y_token: &Pubkey,
     y_mint: &Pubkey,
+    owner_lp_token: &Pubkey,
+    pool_index: u16,
+    (fee_numerator, fee_denominator): (u64, u64),
+    (protocol_fee_numerator, protocol_fee_denominator): (u64, u64),
+    curve: CurveParameters,
     amm_program_id: &Pubkey,
     token_program_id: &Pubkey,
 ) -> Instruction {
-    let data = AMMInstruction::Initialize.pack();
+    let data = AMMInstruction::Initialize {
+        pool_index,
+        fee_numerator,
+        fee_denominator,
+        protocol_fee_numerator,
+        protocol_fee_denominator,
+        curve,
+    }
+    .pack();
 
-    let (amm_data_account, _) = Pubkey::find_program_address(&[b"data"], amm_program_id);
-    let (x_vault_address, _) = Pubkey::find_program_address(&[b"x_vault"], amm_program_id);
-    let (y_vault_address, _) = Pubkey::find_program_address(&[b"y_vault"], amm_program_id);
+    let (amm_data_account, _) = pda::find_pool_address(amm_program_id, x_mint, y_mint, pool_index);
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, &amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, &amm_data_account);
+    let (lp_mint_address, _) = pda::find_lp_mint_address(amm_program_id, &amm_data_account);
 
     let accounts = vec![
         AccountMeta::new(*owner_pubkey, true),
@@ -89,6 +603,8 @@ pub fn initialize_amm(
         AccountMeta::new(*temp_y_token, false),
         AccountMeta::new(y_vault_address, false),
         AccountMeta::new_readonly(*y_mint, false),
+        AccountMeta::new(lp_mint_address, false),
+        AccountMeta::new(*owner_lp_token, true),
         AccountMeta::new_readonly(system_program::id(), false),
         AccountMeta::new_readonly(sysvar::rent::id(), false),
         AccountMeta::new_readonly(*token_program_id, false),
@@ -101,28 +617,235 @@ pub fn initialize_amm(
     }
 }
 
+/// Creates an `Exchange` instruction against the pool at `amm_data_account`.
+#[allow(clippy::too_many_arguments)]
 pub fn exchange(
     user_pubkey: &Pubkey,
-    temp_first_token_account: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    user_first_token_account: &Pubkey,
     user_second_token_account: &Pubkey,
+    amount_in: u64,
+    minimum_amount_out: u64,
+    expires_at: Option<i64>,
     token_program_id: &Pubkey,
     amm_program_id: &Pubkey,
 ) -> Instruction {
-    let data = AMMInstruction::Exchange.pack();
+    let data = AMMInstruction::Exchange {
+        amount_in,
+        minimum_amount_out,
+        expires_at,
+    }
+    .pack();
 
-    let (amm_data_account, _) = Pubkey::find_program_address(&[b"data"], amm_program_id);
-    let (x_vault_address, _) = Pubkey::find_program_address(&[b"x_vault"], amm_program_id);
-    let (y_vault_address, _) = Pubkey::find_program_address(&[b"y_vault"], amm_program_id);
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(*user_pubkey, true),
-        AccountMeta::new(amm_data_account, false),
-        AccountMeta::new(*temp_first_token_account, false),
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(*user_first_token_account, false),
+        AccountMeta::new(*user_second_token_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    push_observations_account(&mut accounts, observations_account);
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates an `ExchangeExactOut` instruction against the pool at `amm_data_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_exact_out(
+    user_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    user_first_token_account: &Pubkey,
+    user_second_token_account: &Pubkey,
+    amount_out: u64,
+    maximum_amount_in: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::ExchangeExactOut {
+        amount_out,
+        maximum_amount_in,
+    }
+    .pack();
+
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+
+    let mut accounts = vec![
+        AccountMeta::new(*user_pubkey, true),
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(*user_first_token_account, false),
         AccountMeta::new(*user_second_token_account, false),
         AccountMeta::new(x_vault_address, false),
         AccountMeta::new(y_vault_address, false),
         AccountMeta::new_readonly(*token_program_id, false),
     ];
+    push_observations_account(&mut accounts, observations_account);
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates an `AddLiquidity` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn add_liquidity(
+    user_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    user_x_token_account: &Pubkey,
+    user_y_token_account: &Pubkey,
+    user_lp_token_account: &Pubkey,
+    lp_amount: u64,
+    maximum_x_amount: u64,
+    maximum_y_amount: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::AddLiquidity {
+        lp_amount,
+        maximum_x_amount,
+        maximum_y_amount,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: liquidity_accounts(
+            user_pubkey,
+            amm_data_account,
+            observations_account,
+            user_x_token_account,
+            user_y_token_account,
+            user_lp_token_account,
+            token_program_id,
+            amm_program_id,
+        ),
+        data,
+    }
+}
+
+/// Creates a `RemoveLiquidity` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_liquidity(
+    user_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    user_x_token_account: &Pubkey,
+    user_y_token_account: &Pubkey,
+    user_lp_token_account: &Pubkey,
+    lp_amount: u64,
+    minimum_x_amount: u64,
+    minimum_y_amount: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::RemoveLiquidity {
+        lp_amount,
+        minimum_x_amount,
+        minimum_y_amount,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: liquidity_accounts(
+            user_pubkey,
+            amm_data_account,
+            observations_account,
+            user_x_token_account,
+            user_y_token_account,
+            user_lp_token_account,
+            token_program_id,
+            amm_program_id,
+        ),
+        data,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn liquidity_accounts(
+    user_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    user_x_token_account: &Pubkey,
+    user_y_token_account: &Pubkey,
+    user_lp_token_account: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Vec<AccountMeta> {
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+    let (lp_mint_address, _) = pda::find_lp_mint_address(amm_program_id, amm_data_account);
+
+    let mut accounts = vec![
+        AccountMeta::new(*user_pubkey, true),
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(*user_x_token_account, false),
+        AccountMeta::new(*user_y_token_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new(lp_mint_address, false),
+        AccountMeta::new(*user_lp_token_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    push_observations_account(&mut accounts, observations_account);
+    accounts
+}
+
+/// Appends the optional observations account of a pool, left out for the
+/// default pubkey of a pool that records none.
+fn push_observations_account(accounts: &mut Vec<AccountMeta>, observations_account: &Pubkey) {
+    if *observations_account != Pubkey::default() {
+        accounts.push(AccountMeta::new(*observations_account, false));
+    }
+}
+
+/// The observations account of a pool, read-only when it is the default
+/// pubkey of a pool that records none.
+fn observations_account_meta(observations_account: &Pubkey) -> AccountMeta {
+    if *observations_account == Pubkey::default() {
+        AccountMeta::new_readonly(*observations_account, false)
+    } else {
+        AccountMeta::new(*observations_account, false)
+    }
+}
+
+/// Creates a `CollectProtocolFees` instruction.
+pub fn collect_protocol_fees(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    x_treasury_account: &Pubkey,
+    y_treasury_account: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::CollectProtocolFees.pack();
+
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*authority_pubkey, true),
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new(*x_treasury_account, false),
+        AccountMeta::new(*y_treasury_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
 
     Instruction {
         program_id: *amm_program_id,
@@ -130,3 +853,584 @@ pub fn exchange(
         data,
     }
 }
+
+/// Creates a `SetAuthority` instruction.
+pub fn set_authority(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    new_authority: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::SetAuthority {
+        new_authority: *new_authority,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: admin_accounts(authority_pubkey, amm_data_account),
+        data,
+    }
+}
+
+/// Creates an `AcceptAuthority` instruction.
+pub fn accept_authority(
+    pending_authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::AcceptAuthority.pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: admin_accounts(pending_authority_pubkey, amm_data_account),
+        data,
+    }
+}
+
+/// Creates a `SetFees` instruction.
+pub fn set_fees(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    (fee_numerator, fee_denominator): (u64, u64),
+    (protocol_fee_numerator, protocol_fee_denominator): (u64, u64),
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::SetFees {
+        fee_numerator,
+        fee_denominator,
+        protocol_fee_numerator,
+        protocol_fee_denominator,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: admin_accounts(authority_pubkey, amm_data_account),
+        data,
+    }
+}
+
+/// Creates a `Pause` instruction.
+pub fn pause(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: admin_accounts(authority_pubkey, amm_data_account),
+        data: AMMInstruction::Pause.pack(),
+    }
+}
+
+/// Creates an `Unpause` instruction.
+pub fn unpause(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: admin_accounts(authority_pubkey, amm_data_account),
+        data: AMMInstruction::Unpause.pack(),
+    }
+}
+
+/// Creates a `RampAmplification` instruction.
+pub fn ramp_amplification(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    target_amp: u64,
+    ramp_stop_ts: i64,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::RampAmplification {
+        target_amp,
+        ramp_stop_ts,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: admin_accounts(authority_pubkey, amm_data_account),
+        data,
+    }
+}
+
+/// Creates a `RouteExchange` instruction through the `route` of AMM data
+/// accounts, in trading order, each with its observations account or the
+/// default pubkey.
+#[allow(clippy::too_many_arguments)]
+pub fn route_exchange(
+    user_pubkey: &Pubkey,
+    user_source_token_account: &Pubkey,
+    user_destination_token_account: &Pubkey,
+    route: &[(Pubkey, Pubkey)],
+    amount_in: u64,
+    minimum_amount_out: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::RouteExchange {
+        amount_in,
+        minimum_amount_out,
+    }
+    .pack();
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*user_pubkey, true),
+        AccountMeta::new(*user_source_token_account, false),
+        AccountMeta::new(*user_destination_token_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    for (amm_data_account, observations_account) in route {
+        let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+        let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+
+        accounts.push(AccountMeta::new(*amm_data_account, false));
+        accounts.push(AccountMeta::new(x_vault_address, false));
+        accounts.push(AccountMeta::new(y_vault_address, false));
+        push_observations_account(&mut accounts, observations_account);
+    }
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates an `InitializeObservations` instruction.
+pub fn initialize_observations(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let mut accounts = admin_accounts(authority_pubkey, amm_data_account);
+    accounts.push(AccountMeta::new(*observations_account, false));
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data: AMMInstruction::InitializeObservations.pack(),
+    }
+}
+
+/// Creates a `GrowObservations` instruction.
+pub fn grow_observations(
+    authority_pubkey: &Pubkey,
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    new_observations_account: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let accounts = vec![
+        AccountMeta::new(*authority_pubkey, true),
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(*observations_account, false),
+        AccountMeta::new(*new_observations_account, false),
+    ];
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data: AMMInstruction::GrowObservations.pack(),
+    }
+}
+
+/// Creates a `FlashLoan` instruction lending `amount` of `token` to the
+/// `receiver_program`, which also gets the `receiver_accounts`.
+#[allow(clippy::too_many_arguments)]
+pub fn flash_loan(
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    borrower_token_account: &Pubkey,
+    receiver_program_id: &Pubkey,
+    receiver_accounts: &[AccountMeta],
+    amount: u64,
+    token: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::FlashLoan {
+        amount,
+        token: *token,
+    }
+    .pack();
+
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+
+    let mut accounts = vec![
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new(*borrower_token_account, false),
+        AccountMeta::new_readonly(*receiver_program_id, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        observations_account_meta(observations_account),
+    ];
+    accounts.extend_from_slice(receiver_accounts);
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates a `Sync` instruction.
+pub fn sync(
+    amm_data_account: &Pubkey,
+    observations_account: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+
+    let mut accounts = vec![
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+    ];
+    push_observations_account(&mut accounts, observations_account);
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data: AMMInstruction::Sync.pack(),
+    }
+}
+
+/// Creates a `Skim` instruction.
+pub fn skim(
+    amm_data_account: &Pubkey,
+    x_destination_account: &Pubkey,
+    y_destination_account: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, amm_data_account);
+
+    let accounts = vec![
+        AccountMeta::new(*amm_data_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new(*x_destination_account, false),
+        AccountMeta::new(*y_destination_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data: AMMInstruction::Skim.pack(),
+    }
+}
+
+/// Creates a `MigrateState` instruction.
+pub fn migrate_state(amm_data_account: &Pubkey, amm_program_id: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: vec![AccountMeta::new(*amm_data_account, false)],
+        data: AMMInstruction::MigrateState { pool_index: 0 }.pack(),
+    }
+}
+
+/// Creates a `MigrateState` instruction moving the version 1 pool into the
+/// `pool_index`-th pool of its mint pair, signed by the upgrade authority.
+pub fn migrate_v1_state(
+    upgrade_authority_pubkey: &Pubkey,
+    x_mint: &Pubkey,
+    y_mint: &Pubkey,
+    pool_index: u16,
+    lp_token_account: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let (legacy_amm_data_account, _) = pda::find_legacy_pool_address(amm_program_id);
+    let (legacy_x_vault_address, _) = pda::find_legacy_x_vault_address(amm_program_id);
+    let (legacy_y_vault_address, _) = pda::find_legacy_y_vault_address(amm_program_id);
+    let (amm_data_account, _) = pda::find_pool_address(amm_program_id, x_mint, y_mint, pool_index);
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, &amm_data_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, &amm_data_account);
+    let (lp_mint_address, _) = pda::find_lp_mint_address(amm_program_id, &amm_data_account);
+    let (program_data_address, _) =
+        Pubkey::find_program_address(&[amm_program_id.as_ref()], &bpf_loader_upgradeable::id());
+
+    let accounts = vec![
+        AccountMeta::new(legacy_amm_data_account, false),
+        AccountMeta::new(*upgrade_authority_pubkey, true),
+        AccountMeta::new(amm_data_account, false),
+        AccountMeta::new(legacy_x_vault_address, false),
+        AccountMeta::new(legacy_y_vault_address, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new_readonly(*x_mint, false),
+        AccountMeta::new_readonly(*y_mint, false),
+        AccountMeta::new(lp_mint_address, false),
+        AccountMeta::new(*lp_token_account, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(program_data_address, false),
+    ];
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data: AMMInstruction::MigrateState { pool_index }.pack(),
+    }
+}
+
+/// Creates an `InitializeConcentrated` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn initialize_concentrated(
+    owner_pubkey: &Pubkey,
+    x_mint: &Pubkey,
+    y_mint: &Pubkey,
+    pool_index: u16,
+    (fee_numerator, fee_denominator): (u64, u64),
+    tick_spacing: u16,
+    initial_sqrt_price: u128,
+    amm_program_id: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::InitializeConcentrated {
+        pool_index,
+        fee_numerator,
+        fee_denominator,
+        tick_spacing,
+        initial_sqrt_price,
+    }
+    .pack();
+
+    let (pool_account, _) =
+        pda::find_concentrated_pool_address(amm_program_id, x_mint, y_mint, pool_index);
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, &pool_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, &pool_account);
+
+    let accounts = vec![
+        AccountMeta::new(*owner_pubkey, true),
+        AccountMeta::new(pool_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new_readonly(*x_mint, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new_readonly(*y_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates an `OpenPosition` instruction for the position PDA of the owner.
+pub fn open_position(
+    owner_pubkey: &Pubkey,
+    pool_account: &Pubkey,
+    tick_lower: i32,
+    tick_upper: i32,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::OpenPosition {
+        tick_lower,
+        tick_upper,
+    }
+    .pack();
+
+    let (position_address, _) = pda::find_position_address(
+        amm_program_id,
+        pool_account,
+        owner_pubkey,
+        tick_lower,
+        tick_upper,
+    );
+
+    let accounts = vec![
+        AccountMeta::new(*owner_pubkey, true),
+        AccountMeta::new_readonly(*pool_account, false),
+        AccountMeta::new(position_address, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates an `IncreaseLiquidity` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn increase_liquidity(
+    owner_pubkey: &Pubkey,
+    pool_account: &Pubkey,
+    position_account: &Pubkey,
+    owner_x_token_account: &Pubkey,
+    owner_y_token_account: &Pubkey,
+    liquidity: u128,
+    maximum_x_amount: u64,
+    maximum_y_amount: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::IncreaseLiquidity {
+        liquidity,
+        maximum_x_amount,
+        maximum_y_amount,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: position_accounts(
+            owner_pubkey,
+            pool_account,
+            position_account,
+            owner_x_token_account,
+            owner_y_token_account,
+            token_program_id,
+            amm_program_id,
+        ),
+        data,
+    }
+}
+
+/// Creates a `DecreaseLiquidity` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn decrease_liquidity(
+    owner_pubkey: &Pubkey,
+    pool_account: &Pubkey,
+    position_account: &Pubkey,
+    owner_x_token_account: &Pubkey,
+    owner_y_token_account: &Pubkey,
+    liquidity: u128,
+    minimum_x_amount: u64,
+    minimum_y_amount: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::DecreaseLiquidity {
+        liquidity,
+        minimum_x_amount,
+        minimum_y_amount,
+    }
+    .pack();
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: position_accounts(
+            owner_pubkey,
+            pool_account,
+            position_account,
+            owner_x_token_account,
+            owner_y_token_account,
+            token_program_id,
+            amm_program_id,
+        ),
+        data,
+    }
+}
+
+/// Creates a `CollectPositionFees` instruction.
+pub fn collect_position_fees(
+    owner_pubkey: &Pubkey,
+    pool_account: &Pubkey,
+    position_account: &Pubkey,
+    owner_x_token_account: &Pubkey,
+    owner_y_token_account: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *amm_program_id,
+        accounts: position_accounts(
+            owner_pubkey,
+            pool_account,
+            position_account,
+            owner_x_token_account,
+            owner_y_token_account,
+            token_program_id,
+            amm_program_id,
+        ),
+        data: AMMInstruction::CollectPositionFees.pack(),
+    }
+}
+
+/// Creates an `ExchangeConcentrated` instruction against the pool at
+/// `pool_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_concentrated(
+    user_pubkey: &Pubkey,
+    pool_account: &Pubkey,
+    user_first_token_account: &Pubkey,
+    user_second_token_account: &Pubkey,
+    amount_in: u64,
+    minimum_amount_out: u64,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Instruction {
+    let data = AMMInstruction::ExchangeConcentrated {
+        amount_in,
+        minimum_amount_out,
+    }
+    .pack();
+
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, pool_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, pool_account);
+
+    let accounts = vec![
+        AccountMeta::new(*user_pubkey, true),
+        AccountMeta::new(*pool_account, false),
+        AccountMeta::new(*user_first_token_account, false),
+        AccountMeta::new(*user_second_token_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+
+    Instruction {
+        program_id: *amm_program_id,
+        accounts,
+        data,
+    }
+}
+
+fn position_accounts(
+    owner_pubkey: &Pubkey,
+    pool_account: &Pubkey,
+    position_account: &Pubkey,
+    owner_x_token_account: &Pubkey,
+    owner_y_token_account: &Pubkey,
+    token_program_id: &Pubkey,
+    amm_program_id: &Pubkey,
+) -> Vec<AccountMeta> {
+    let (x_vault_address, _) = pda::find_x_vault_address(amm_program_id, pool_account);
+    let (y_vault_address, _) = pda::find_y_vault_address(amm_program_id, pool_account);
+
+    vec![
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new(*pool_account, false),
+        AccountMeta::new(*position_account, false),
+        AccountMeta::new(*owner_x_token_account, false),
+        AccountMeta::new(*owner_y_token_account, false),
+        AccountMeta::new(x_vault_address, false),
+        AccountMeta::new(y_vault_address, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ]
+}
+
+fn admin_accounts(authority_pubkey: &Pubkey, amm_data_account: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new_readonly(*authority_pubkey, true),
+        AccountMeta::new(*amm_data_account, false),
+    ]
+}
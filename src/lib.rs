@@ -1,5 +1,10 @@
+pub mod concentrated;
+pub mod curve;
 pub mod error;
 pub mod instruction;
+pub mod math;
+pub mod oracle;
+pub mod pda;
 pub mod processor;
 pub mod state;
 
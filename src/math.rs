@@ -0,0 +1,215 @@
+use solana_program::program_error::ProgramError;
+use std::convert::TryFrom;
+
+use crate::error::AMMErrors;
+
+/// 1.0 in the 18 decimal fixed point used by `pow_ceil`
+pub const FIXED_ONE: u128 = 1_000_000_000_000_000_000;
+
+/// 1.0 in the Q64.64 fixed point used for prices
+pub const Q64: u128 = 1 << 64;
+
+/// Binomial series terms below 1e-10 are dropped by `pow_ceil`
+const POW_PRECISION: u128 = 100_000_000;
+const MAX_POW_TERMS: u128 = 128;
+
+// Pool math is done on u128 with checked operations, so a product of two u64
+// never overflows and anything that does not fit back into u64 is reported as
+// `AMMErrors::MathOverflow` instead of wrapping. The caller chooses the
+// rounding direction, always in favour of the pool.
+
+/// `a * b / c`, rounded down.
+pub fn mul_div_floor(a: u128, b: u128, c: u128) -> Result<u64, ProgramError> {
+    let product = a.checked_mul(b).ok_or(AMMErrors::MathOverflow)?;
+    to_u64(product.checked_div(c).ok_or(AMMErrors::MathOverflow)?)
+}
+
+/// `a * b / c`, rounded up.
+pub fn mul_div_ceil(a: u128, b: u128, c: u128) -> Result<u64, ProgramError> {
+    let product = a.checked_mul(b).ok_or(AMMErrors::MathOverflow)?;
+    let quotient = product.checked_div(c).ok_or(AMMErrors::MathOverflow)?;
+
+    if product % c == 0 {
+        to_u64(quotient)
+    } else {
+        to_u64(quotient + 1)
+    }
+}
+
+/// `a * b / c` on u128 values with a 256-bit intermediate product, rounded
+/// down. Used by curves whose invariants multiply three reserves together.
+pub fn mul_div_wide(a: u128, b: u128, c: u128) -> Result<u128, ProgramError> {
+    let (quotient, _) = div_wide(widening_mul(a, b), c)?;
+    Ok(quotient)
+}
+
+/// `a * b / c` on u128 values with a 256-bit intermediate product, rounded up.
+pub fn mul_div_wide_ceil(a: u128, b: u128, c: u128) -> Result<u128, ProgramError> {
+    match div_wide(widening_mul(a, b), c)? {
+        (quotient, 0) => Ok(quotient),
+        (quotient, _) => quotient
+            .checked_add(1)
+            .ok_or_else(|| AMMErrors::MathOverflow.into()),
+    }
+}
+
+/// `base ^ (numerator / denominator)` for a fixed point `base` within
+/// `[0.5, 1.5]`, rounded up.
+///
+/// The whole part of the exponent is done by squaring, the fractional part
+/// by the binomial series. The result is raised by a bound of the series
+/// tail and of the rounded multiplications, so it is never below the exact
+/// power.
+pub fn pow_ceil(base: u128, numerator: u64, denominator: u64) -> Result<u128, ProgramError> {
+    if denominator == 0 || !(FIXED_ONE / 2..=FIXED_ONE * 3 / 2).contains(&base) {
+        return Err(AMMErrors::MathOverflow.into());
+    }
+
+    let fraction = u128::from(numerator % denominator) * FIXED_ONE / u128::from(denominator);
+    let mut power = pow_whole(base, numerator / denominator)?;
+    if fraction != 0 {
+        power = mul_div_wide(power, pow_fraction(base, fraction)?, FIXED_ONE)?;
+    }
+
+    power
+        .checked_add(power / 1_000_000_000 + 10_000)
+        .ok_or_else(|| AMMErrors::MathOverflow.into())
+}
+
+pub fn checked_add(a: u64, b: u64) -> Result<u64, ProgramError> {
+    a.checked_add(b)
+        .ok_or_else(|| AMMErrors::MathOverflow.into())
+}
+
+pub fn checked_sub(a: u64, b: u64) -> Result<u64, ProgramError> {
+    a.checked_sub(b)
+        .ok_or_else(|| AMMErrors::MathOverflow.into())
+}
+
+pub fn to_u64(value: u128) -> Result<u64, ProgramError> {
+    u64::try_from(value).map_err(|_| AMMErrors::MathOverflow.into())
+}
+
+/// Integer square root, rounded down.
+pub fn sqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+
+    // Newton's method from the power of two just above the root
+    let bits = 128 - value.leading_zeros();
+    let mut root = 1u128 << (bits / 2 + 1);
+    let mut next = (root + value / root) / 2;
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
+
+/// `base ^ exponent` by squaring, rounded down.
+fn pow_whole(base: u128, mut exponent: u64) -> Result<u128, ProgramError> {
+    let mut power = FIXED_ONE;
+    let mut square = base;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            power = mul_div_wide(power, square, FIXED_ONE)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            square = mul_div_wide(square, square, FIXED_ONE)?;
+        }
+    }
+
+    Ok(power)
+}
+
+/// `base ^ fraction` for `fraction < 1` as the binomial series
+/// `(1 + x)^a = 1 + a x + a (a - 1) x^2 / 2! + ...`
+fn pow_fraction(base: u128, fraction: u128) -> Result<u128, ProgramError> {
+    let (x, x_negative) = if base >= FIXED_ONE {
+        (base - FIXED_ONE, false)
+    } else {
+        (FIXED_ONE - base, true)
+    };
+
+    let (mut positive, mut negative) = (FIXED_ONE, 0u128);
+    let mut term = FIXED_ONE;
+    let mut term_negative = false;
+    for k in 1..MAX_POW_TERMS {
+        // term_k = term_(k - 1) * (a - (k - 1)) * x / k
+        let previous = (k - 1) * FIXED_ONE;
+        let (coefficient, coefficient_negative) = if fraction >= previous {
+            (fraction - previous, false)
+        } else {
+            (previous - fraction, true)
+        };
+        term = mul_div_wide(
+            mul_div_wide(term, coefficient, FIXED_ONE)?,
+            x,
+            k * FIXED_ONE,
+        )?;
+        if term == 0 {
+            break;
+        }
+
+        term_negative ^= x_negative ^ coefficient_negative;
+        if term_negative {
+            negative += term;
+        } else {
+            positive += term;
+        }
+
+        if term < POW_PRECISION {
+            break;
+        }
+    }
+
+    positive
+        .checked_sub(negative)
+        .ok_or_else(|| AMMErrors::MathOverflow.into())
+}
+
+/// Quotient and remainder of a 256-bit `(high, low)` value divided by `c`.
+fn div_wide((high, low): (u128, u128), c: u128) -> Result<(u128, u128), ProgramError> {
+    if c == 0 || high >= c {
+        return Err(AMMErrors::MathOverflow.into());
+    }
+    if high == 0 {
+        return Ok((low / c, low % c));
+    }
+
+    // Long division bit by bit, the remainder always stays below `c`
+    let mut remainder = high;
+    let mut quotient = 0u128;
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+
+    Ok((quotient, remainder))
+}
+
+/// Full 256-bit product as (high, low) halves.
+fn widening_mul(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+
+    let (a_high, a_low) = (a >> 64, a & MASK);
+    let (b_high, b_low) = (b >> 64, b & MASK);
+
+    let low_low = a_low * b_low;
+    let low_high = a_low * b_high;
+    let high_low = a_high * b_low;
+    let high_high = a_high * b_high;
+
+    let middle = (low_low >> 64) + (low_high & MASK) + (high_low & MASK);
+    let low = (low_low & MASK) | (middle << 64);
+    let high = high_high + (low_high >> 64) + (high_low >> 64) + (middle >> 64);
+
+    (high, low)
+}
@@ -0,0 +1,156 @@
+use std::iter;
+
+use crate::{curve, math, state};
+
+// Time-weighted average prices as in Uniswap v2. Before the reserves of a
+// pool change, the spot prices they imply are added to the accumulators
+// multiplied by the seconds they were in effect. The difference of two
+// readings divided by the time between them is the average price over that
+// window, which a single transaction cannot move much.
+
+/// Accumulators of a pool as of `timestamp`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Observation {
+    pub timestamp: i64,
+    pub price_x_cumulative: u128,
+    pub price_y_cumulative: u128,
+}
+
+impl Observation {
+    /// Accumulators of the pool brought forward to `now`, as if it had been
+    /// updated then. Clients read them this way to observe a pool nobody
+    /// traded on lately.
+    pub fn of(amm_data: &state::AMM, now: i64) -> Self {
+        let last = Observation {
+            timestamp: amm_data.last_update_ts,
+            price_x_cumulative: amm_data.price_x_cumulative,
+            price_y_cumulative: amm_data.price_y_cumulative,
+        };
+        if now <= last.timestamp {
+            return last;
+        }
+        // An empty pool has no price, the time just passes
+        if amm_data.x_amount == 0 || amm_data.y_amount == 0 {
+            return Observation {
+                timestamp: now,
+                ..last
+            };
+        }
+
+        // Prices past the Q64.64 range only come from pools drained down to
+        // a few units and saturate rather than block trading
+        let price_x = curve::swap_curve(amm_data, now)
+            .and_then(|curve| curve.spot_price(amm_data.x_amount, amm_data.y_amount))
+            .unwrap_or(u128::MAX);
+        let price_y = math::mul_div_wide(math::Q64, math::Q64, price_x).unwrap_or(u128::MAX);
+
+        let elapsed = (now - last.timestamp) as u128;
+        Observation {
+            timestamp: now,
+            price_x_cumulative: last
+                .price_x_cumulative
+                .wrapping_add(price_x.wrapping_mul(elapsed)),
+            price_y_cumulative: last
+                .price_y_cumulative
+                .wrapping_add(price_y.wrapping_mul(elapsed)),
+        }
+    }
+}
+
+impl From<&state::PriceSample> for Observation {
+    fn from(sample: &state::PriceSample) -> Self {
+        Observation {
+            timestamp: sample.timestamp,
+            price_x_cumulative: sample.price_x_cumulative,
+            price_y_cumulative: sample.price_y_cumulative,
+        }
+    }
+}
+
+/// Accumulators of the pool at `timestamp`, from the samples of its
+/// `Observations` account up to the last update and from the pool itself
+/// after that. Between two samples the accumulators are interpolated, exact
+/// as long as the price did not move in between. `None` before the oldest
+/// sample.
+pub fn observe(
+    amm_data: &state::AMM,
+    observations: &state::Observations,
+    timestamp: i64,
+) -> Option<Observation> {
+    let latest = Observation::of(amm_data, amm_data.last_update_ts);
+    if timestamp >= latest.timestamp {
+        return Some(Observation::of(amm_data, timestamp));
+    }
+
+    let mut points = observations
+        .chronological()
+        .map(Observation::from)
+        .chain(iter::once(latest))
+        .peekable();
+    while let Some(before) = points.next() {
+        let after = points.peek()?;
+        if timestamp < before.timestamp {
+            return None;
+        }
+        if timestamp >= after.timestamp {
+            continue;
+        }
+
+        let elapsed = (timestamp - before.timestamp) as u128;
+        let window = (after.timestamp - before.timestamp) as u128;
+        let interpolate =
+            |start: u128, end: u128| start.wrapping_add(end.wrapping_sub(start) / window * elapsed);
+        return Some(Observation {
+            timestamp,
+            price_x_cumulative: interpolate(before.price_x_cumulative, after.price_x_cumulative),
+            price_y_cumulative: interpolate(before.price_y_cumulative, after.price_y_cumulative),
+        });
+    }
+
+    None
+}
+
+/// Sample of the pool accumulators and reserves for its `Observations`.
+pub fn sample(amm_data: &state::AMM) -> state::PriceSample {
+    let reserves_product = u128::from(amm_data.x_amount) * u128::from(amm_data.y_amount);
+
+    state::PriceSample {
+        timestamp: amm_data.last_update_ts,
+        price_x_cumulative: amm_data.price_x_cumulative,
+        price_y_cumulative: amm_data.price_y_cumulative,
+        // The root of a product of two u64 fits back into u64
+        liquidity: math::sqrt(reserves_product) as u64,
+    }
+}
+
+/// Accumulates the prices of the current reserves up to `now`. Called
+/// before every change of the reserves.
+pub fn update(amm_data: &mut state::AMM, now: i64) {
+    let observation = Observation::of(amm_data, now);
+
+    amm_data.price_x_cumulative = observation.price_x_cumulative;
+    amm_data.price_y_cumulative = observation.price_y_cumulative;
+    amm_data.last_update_ts = observation.timestamp;
+}
+
+/// Average prices of X in Y and of Y in X between two observations of the
+/// same pool, Q64.64. `None` unless `end` is later than `start`.
+///
+/// The accumulators wrap around, so the window must be short enough for the
+/// sum of prices over it to fit in u128.
+pub fn twap(start: &Observation, end: &Observation) -> Option<(u128, u128)> {
+    let elapsed = end.timestamp.checked_sub(start.timestamp)?;
+    if elapsed <= 0 {
+        return None;
+    }
+
+    let elapsed = elapsed as u128;
+    Some((
+        end.price_x_cumulative
+            .wrapping_sub(start.price_x_cumulative)
+            / elapsed,
+        end.price_y_cumulative
+            .wrapping_sub(start.price_y_cumulative)
+            / elapsed,
+    ))
+}
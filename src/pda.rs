@@ -0,0 +1,172 @@
+use solana_program::pubkey::{Pubkey, PubkeyError};
+
+pub const POOL_SEED: &[u8] = b"data";
+pub const X_VAULT_SEED: &[u8] = b"x_vault";
+pub const Y_VAULT_SEED: &[u8] = b"y_vault";
+pub const LP_MINT_SEED: &[u8] = b"lp_mint";
+pub const CONCENTRATED_POOL_SEED: &[u8] = b"concentrated";
+pub const POSITION_SEED: &[u8] = b"position";
+
+/// Orders a mint pair so that X/Y and Y/X resolve to the same pool.
+pub fn sort_mints<'a>(a: &'a Pubkey, b: &'a Pubkey) -> (&'a Pubkey, &'a Pubkey) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Finds the AMM data account of the `pool_index`-th pool for a mint pair.
+pub fn find_pool_address(
+    program_id: &Pubkey,
+    x_mint: &Pubkey,
+    y_mint: &Pubkey,
+    pool_index: u16,
+) -> (Pubkey, u8) {
+    let (first_mint, second_mint) = sort_mints(x_mint, y_mint);
+
+    Pubkey::find_program_address(
+        &[
+            POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
+
+/// Derives the AMM data account from its bump seed, without the search of
+/// `find_pool_address`.
+pub fn create_pool_address(
+    program_id: &Pubkey,
+    x_mint: &Pubkey,
+    y_mint: &Pubkey,
+    pool_index: u16,
+    bump_seed: u8,
+) -> Result<Pubkey, PubkeyError> {
+    let (first_mint, second_mint) = sort_mints(x_mint, y_mint);
+
+    Pubkey::create_program_address(
+        &[
+            POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+            &[bump_seed],
+        ],
+        program_id,
+    )
+}
+
+/// Finds the AMM data account of version 1, the only pool the program had
+/// before pools were derived from their mints.
+pub fn find_legacy_pool_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_SEED], program_id)
+}
+
+/// Finds the X token vault of the version 1 pool.
+pub fn find_legacy_x_vault_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[X_VAULT_SEED], program_id)
+}
+
+/// Finds the Y token vault of the version 1 pool.
+pub fn find_legacy_y_vault_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[Y_VAULT_SEED], program_id)
+}
+
+/// Finds the X token vault owned by a pool.
+pub fn find_x_vault_address(program_id: &Pubkey, pool: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[X_VAULT_SEED, pool.as_ref()], program_id)
+}
+
+/// Finds the Y token vault owned by a pool.
+pub fn find_y_vault_address(program_id: &Pubkey, pool: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[Y_VAULT_SEED, pool.as_ref()], program_id)
+}
+
+/// Derives the X token vault of a pool from its bump seed.
+pub fn create_x_vault_address(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    bump_seed: u8,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[X_VAULT_SEED, pool.as_ref(), &[bump_seed]], program_id)
+}
+
+/// Derives the Y token vault of a pool from its bump seed.
+pub fn create_y_vault_address(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    bump_seed: u8,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[Y_VAULT_SEED, pool.as_ref(), &[bump_seed]], program_id)
+}
+
+/// Finds the LP token mint of a pool.
+pub fn find_lp_mint_address(program_id: &Pubkey, pool: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], program_id)
+}
+
+/// Finds the `pool_index`-th concentrated liquidity pool of a mint pair.
+/// Its vaults are found with the same functions as the `AMM` ones.
+pub fn find_concentrated_pool_address(
+    program_id: &Pubkey,
+    x_mint: &Pubkey,
+    y_mint: &Pubkey,
+    pool_index: u16,
+) -> (Pubkey, u8) {
+    let (first_mint, second_mint) = sort_mints(x_mint, y_mint);
+
+    Pubkey::find_program_address(
+        &[
+            CONCENTRATED_POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
+
+/// Derives a concentrated liquidity pool from its bump seed.
+pub fn create_concentrated_pool_address(
+    program_id: &Pubkey,
+    x_mint: &Pubkey,
+    y_mint: &Pubkey,
+    pool_index: u16,
+    bump_seed: u8,
+) -> Result<Pubkey, PubkeyError> {
+    let (first_mint, second_mint) = sort_mints(x_mint, y_mint);
+
+    Pubkey::create_program_address(
+        &[
+            CONCENTRATED_POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+            &[bump_seed],
+        ],
+        program_id,
+    )
+}
+
+/// Finds the position of `owner` between two ticks of a concentrated pool.
+pub fn find_position_address(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    owner: &Pubkey,
+    tick_lower: i32,
+    tick_upper: i32,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            POSITION_SEED,
+            pool.as_ref(),
+            owner.as_ref(),
+            &tick_lower.to_le_bytes(),
+            &tick_upper.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
@@ -1,18 +1,28 @@
-use rust_decimal::prelude::*;
-
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable,
+    clock::Clock,
     entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
     program::{invoke, invoke_signed},
     program_error::ProgramError,
     program_pack::Pack,
     pubkey::Pubkey,
     rent::Rent,
-    system_instruction,
+    system_instruction, system_program,
     sysvar::Sysvar,
 };
 
-use crate::{error::AMMErrors, instruction::AMMInstruction, state};
+use bytemuck::Zeroable;
+use std::cell::RefMut;
+
+use crate::{
+    concentrated,
+    curve::{self, CurveParameters, CurveType, TradeDirection},
+    error::AMMErrors,
+    instruction::{AMMInstruction, FlashLoanCallback},
+    math, oracle, pda, state,
+};
 
 pub struct Processor;
 impl Processor {
@@ -24,12 +34,166 @@ impl Processor {
         let instruction = AMMInstruction::unpack(instruction_data)?;
 
         match instruction {
-            AMMInstruction::Initialize => Self::process_initialize(accounts, program_id),
-            AMMInstruction::Exchange => Self::process_exchange(accounts, program_id),
+            AMMInstruction::Initialize {
+                pool_index,
+                fee_numerator,
+                fee_denominator,
+                protocol_fee_numerator,
+                protocol_fee_denominator,
+                curve,
+            } => Self::process_initialize(
+                accounts,
+                pool_index,
+                (fee_numerator, fee_denominator),
+                (protocol_fee_numerator, protocol_fee_denominator),
+                curve,
+                program_id,
+            ),
+            AMMInstruction::Exchange {
+                amount_in,
+                minimum_amount_out,
+                expires_at,
+            } => Self::process_exchange(
+                accounts,
+                amount_in,
+                minimum_amount_out,
+                expires_at,
+                program_id,
+            ),
+            AMMInstruction::ExchangeExactOut {
+                amount_out,
+                maximum_amount_in,
+            } => Self::process_exchange_exact_out(
+                accounts,
+                amount_out,
+                maximum_amount_in,
+                program_id,
+            ),
+            AMMInstruction::AddLiquidity {
+                lp_amount,
+                maximum_x_amount,
+                maximum_y_amount,
+            } => Self::process_add_liquidity(
+                accounts,
+                lp_amount,
+                maximum_x_amount,
+                maximum_y_amount,
+                program_id,
+            ),
+            AMMInstruction::RemoveLiquidity {
+                lp_amount,
+                minimum_x_amount,
+                minimum_y_amount,
+            } => Self::process_remove_liquidity(
+                accounts,
+                lp_amount,
+                minimum_x_amount,
+                minimum_y_amount,
+                program_id,
+            ),
+            AMMInstruction::CollectProtocolFees => {
+                Self::process_collect_protocol_fees(accounts, program_id)
+            }
+            AMMInstruction::SetAuthority { new_authority } => {
+                Self::process_set_authority(accounts, new_authority, program_id)
+            }
+            AMMInstruction::AcceptAuthority => Self::process_accept_authority(accounts, program_id),
+            AMMInstruction::Pause => Self::process_set_paused(accounts, true, program_id),
+            AMMInstruction::Unpause => Self::process_set_paused(accounts, false, program_id),
+            AMMInstruction::RampAmplification {
+                target_amp,
+                ramp_stop_ts,
+            } => Self::process_ramp_amplification(accounts, target_amp, ramp_stop_ts, program_id),
+            AMMInstruction::RouteExchange {
+                amount_in,
+                minimum_amount_out,
+            } => Self::process_route_exchange(accounts, amount_in, minimum_amount_out, program_id),
+            AMMInstruction::InitializeConcentrated {
+                pool_index,
+                fee_numerator,
+                fee_denominator,
+                tick_spacing,
+                initial_sqrt_price,
+            } => Self::process_initialize_concentrated(
+                accounts,
+                pool_index,
+                (fee_numerator, fee_denominator),
+                tick_spacing,
+                initial_sqrt_price,
+                program_id,
+            ),
+            AMMInstruction::OpenPosition {
+                tick_lower,
+                tick_upper,
+            } => Self::process_open_position(accounts, tick_lower, tick_upper, program_id),
+            AMMInstruction::IncreaseLiquidity {
+                liquidity,
+                maximum_x_amount,
+                maximum_y_amount,
+            } => Self::process_increase_liquidity(
+                accounts,
+                liquidity,
+                (maximum_x_amount, maximum_y_amount),
+                program_id,
+            ),
+            AMMInstruction::DecreaseLiquidity {
+                liquidity,
+                minimum_x_amount,
+                minimum_y_amount,
+            } => Self::process_decrease_liquidity(
+                accounts,
+                liquidity,
+                (minimum_x_amount, minimum_y_amount),
+                program_id,
+            ),
+            AMMInstruction::CollectPositionFees => {
+                Self::process_collect_position_fees(accounts, program_id)
+            }
+            AMMInstruction::ExchangeConcentrated {
+                amount_in,
+                minimum_amount_out,
+            } => Self::process_exchange_concentrated(
+                accounts,
+                amount_in,
+                minimum_amount_out,
+                program_id,
+            ),
+            AMMInstruction::InitializeObservations => {
+                Self::process_initialize_observations(accounts, program_id)
+            }
+            AMMInstruction::GrowObservations => {
+                Self::process_grow_observations(accounts, program_id)
+            }
+            AMMInstruction::FlashLoan { amount, token } => {
+                Self::process_flash_loan(accounts, amount, &token, program_id)
+            }
+            AMMInstruction::Sync => Self::process_sync(accounts, program_id),
+            AMMInstruction::Skim => Self::process_skim(accounts, program_id),
+            AMMInstruction::MigrateState { pool_index } => {
+                Self::process_migrate_state(accounts, pool_index, program_id)
+            }
+            AMMInstruction::SetFees {
+                fee_numerator,
+                fee_denominator,
+                protocol_fee_numerator,
+                protocol_fee_denominator,
+            } => Self::process_set_fees(
+                accounts,
+                (fee_numerator, fee_denominator),
+                (protocol_fee_numerator, protocol_fee_denominator),
+                program_id,
+            ),
         }
     }
 
-    fn process_initialize(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+    fn process_initialize(
+        accounts: &[AccountInfo],
+        pool_index: u16,
+        (fee_numerator, fee_denominator): (u64, u64),
+        (protocol_fee_numerator, protocol_fee_denominator): (u64, u64),
+        curve: CurveParameters,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
 
         let owner_account = next_account_info(accounts_iter)?;
@@ -40,21 +204,92 @@ impl Processor {
         let y_temp_account = next_account_info(accounts_iter)?;
         let y_vault_account = next_account_info(accounts_iter)?;
         let y_mint_account = next_account_info(accounts_iter)?;
+        let lp_mint_account = next_account_info(accounts_iter)?;
+        let owner_lp_account = next_account_info(accounts_iter)?;
         let system_account = next_account_info(accounts_iter)?;
         let rent_sysvar = next_account_info(accounts_iter)?;
         let token_program = next_account_info(accounts_iter)?;
 
-        let (amm_data_address, amm_data_bump_seed) =
-            Pubkey::find_program_address(&[b"data"], program_id);
+        let (amm_data_address, amm_data_bump_seed) = pda::find_pool_address(
+            program_id,
+            x_mint_account.key,
+            y_mint_account.key,
+            pool_index,
+        );
+
+        let (x_vault_address, x_vault_bump_seed) =
+            pda::find_x_vault_address(program_id, &amm_data_address);
+        let (y_vault_address, y_vault_bump_seed) =
+            pda::find_y_vault_address(program_id, &amm_data_address);
+        let (lp_mint_address, lp_mint_bump_seed) =
+            pda::find_lp_mint_address(program_id, &amm_data_address);
+
+        Self::check_signer(owner_account)?;
+        Self::check_signer(owner_lp_account)?;
+        Self::check_program(system_account, &system_program::id())?;
+        Self::check_program(token_program, &spl_token::id())?;
 
         if *amm_data_account.key != amm_data_address {
             return Err(AMMErrors::DataAccountMismatch.into());
         }
 
+        if *x_vault_account.key != x_vault_address
+            || *y_vault_account.key != y_vault_address
+            || *lp_mint_account.key != lp_mint_address
+        {
+            return Err(AMMErrors::VaultAccountMismatch.into());
+        }
+
+        for account in [
+            owner_account,
+            amm_data_account,
+            x_temp_account,
+            x_vault_account,
+            y_temp_account,
+            y_vault_account,
+            lp_mint_account,
+            owner_lp_account,
+        ] {
+            Self::check_writable(account)?;
+        }
+
+        for account in [
+            x_temp_account,
+            x_mint_account,
+            y_temp_account,
+            y_mint_account,
+        ] {
+            Self::check_owner(account, &spl_token::id())?;
+        }
+
         if !amm_data_account.data_is_empty() {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
+        Self::check_fees(
+            (fee_numerator, fee_denominator),
+            (protocol_fee_numerator, protocol_fee_denominator),
+        )?;
+
+        let (amp, (x_weight, y_weight)) = match curve {
+            CurveParameters::ConstantProduct => (0, (0, 0)),
+            CurveParameters::StableSwap { amp } => {
+                if !(curve::MIN_AMP..=curve::MAX_AMP).contains(&amp) {
+                    return Err(AMMErrors::InvalidAmplification.into());
+                }
+                (amp, (0, 0))
+            }
+            CurveParameters::Weighted { x_weight, y_weight } => {
+                if x_weight < curve::MIN_WEIGHT
+                    || y_weight < curve::MIN_WEIGHT
+                    || x_weight.checked_add(y_weight) != Some(curve::WEIGHT_TOTAL)
+                {
+                    return Err(AMMErrors::InvalidWeights.into());
+                }
+                (0, (x_weight, y_weight))
+            }
+        };
+
         let x_temp_data = spl_token::state::Account::unpack(&x_temp_account.data.borrow())?;
         let y_temp_data = spl_token::state::Account::unpack(&y_temp_account.data.borrow())?;
 
@@ -65,8 +300,19 @@ impl Processor {
             return Err(AMMErrors::TokenMintMismatch.into());
         }
 
+        if x_temp_data.amount == 0 || y_temp_data.amount == 0 {
+            return Err(AMMErrors::ZeroDeposit.into());
+        }
+
         let rent = &Rent::from_account_info(rent_sysvar)?;
-        let amm_data_signer_seeds: &[&[_]] = &[b"data", &[amm_data_bump_seed]];
+        let (first_mint, second_mint) = pda::sort_mints(x_mint_account.key, y_mint_account.key);
+        let amm_data_signer_seeds: &[&[_]] = &[
+            pda::POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+            &[amm_data_bump_seed],
+        ];
 
         invoke_signed(
             &system_instruction::create_account(
@@ -81,25 +327,28 @@ impl Processor {
                 amm_data_account.clone(),
                 system_account.clone(),
             ],
-            &[&amm_data_signer_seeds],
+            &[amm_data_signer_seeds],
         )?;
 
         for (
             vault_seed,
+            vault_bump_seed,
             vault_account,
             mint_account,
             temp_token_account,
             temp_token_account_data,
         ) in [
             (
-                b"x_vault".clone(),
+                pda::X_VAULT_SEED,
+                x_vault_bump_seed,
                 x_vault_account.clone(),
                 x_mint_account.clone(),
                 x_temp_account.clone(),
                 x_temp_data,
             ),
             (
-                b"y_vault".clone(),
+                pda::Y_VAULT_SEED,
+                y_vault_bump_seed,
                 y_vault_account.clone(),
                 y_mint_account.clone(),
                 y_temp_account.clone(),
@@ -108,43 +357,23 @@ impl Processor {
         ]
         .iter()
         {
-            let (vault_address, vault_bump_seed) =
-                Pubkey::find_program_address(&[vault_seed], program_id);
-            let vault_signer_seeds: &[&[_]] = &[vault_seed, &[vault_bump_seed]];
-
-            invoke_signed(
-                &system_instruction::create_account(
-                    owner_account.key,
-                    &vault_address,
-                    1.max(rent.minimum_balance(spl_token::state::Account::LEN)),
-                    spl_token::state::Account::LEN as u64,
-                    token_program.key,
-                ),
-                &[vault_account.clone(), owner_account.clone()],
-                &[&vault_signer_seeds],
-            )?;
-
-            invoke(
-                &spl_token::instruction::initialize_account(
-                    token_program.key,
-                    vault_account.key,
-                    mint_account.key,
-                    vault_account.key,
-                )?,
-                &[
-                    vault_account.clone(),
-                    mint_account.clone(),
-                    rent_sysvar.clone(),
-                ],
+            Self::create_vault(
+                owner_account,
+                amm_data_account,
+                (vault_account, vault_seed, *vault_bump_seed),
+                mint_account,
+                rent,
+                rent_sysvar,
+                token_program,
             )?;
 
             invoke(
                 &spl_token::instruction::transfer(
                     token_program.key,
-                    &temp_token_account.key,
-                    &vault_account.key,
-                    &owner_account.key,
-                    &[&owner_account.key],
+                    temp_token_account.key,
+                    vault_account.key,
+                    owner_account.key,
+                    &[owner_account.key],
                     temp_token_account_data.amount,
                 )?,
                 &[
@@ -155,122 +384,2410 @@ impl Processor {
             )?;
         }
 
+        Self::create_lp_mint(
+            owner_account,
+            amm_data_account,
+            (lp_mint_account, lp_mint_bump_seed),
+            (owner_lp_account, owner_account),
+            rent,
+            rent_sysvar,
+            token_program,
+        )?;
+
         let amm_data = state::AMM {
-            is_initialized: true,
+            version: state::AMM::VERSION,
+            is_initialized: 1,
+            pool_index,
             x_mint: x_temp_data.mint,
             x_amount: x_temp_data.amount,
             y_mint: y_temp_data.mint,
             y_amount: y_temp_data.amount,
+            lp_mint: lp_mint_address,
+            fee_numerator,
+            fee_denominator,
+            protocol_fee_numerator,
+            protocol_fee_denominator,
+            authority: *owner_account.key,
+            pending_authority: Pubkey::default(),
+            protocol_fees_x: 0,
+            protocol_fees_y: 0,
+            paused: 0,
+            curve_type: curve.curve_type() as u8,
+            initial_amp: amp,
+            target_amp: amp,
+            ramp_start_ts: 0,
+            ramp_stop_ts: 0,
+            x_decimals: spl_token::state::Mint::unpack(&x_mint_account.data.borrow())?.decimals,
+            y_decimals: spl_token::state::Mint::unpack(&y_mint_account.data.borrow())?.decimals,
+            x_weight,
+            y_weight,
+            price_x_cumulative: 0,
+            price_y_cumulative: 0,
+            last_update_ts: Clock::get()?.unix_timestamp,
+            observations: Pubkey::default(),
+            bump_seed: amm_data_bump_seed,
+            x_vault_bump_seed,
+            y_vault_bump_seed,
+            lp_mint_bump_seed,
+            ..Zeroable::zeroed()
         };
 
-        state::AMM::pack(amm_data, &mut amm_data_account.data.borrow_mut())?;
+        *state::AMM::load_mut(&mut amm_data_account.data.borrow_mut())? = amm_data;
 
         Ok(())
     }
 
-    fn process_exchange(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+    fn process_exchange(
+        accounts: &[AccountInfo],
+        amount_in: u64,
+        minimum_amount_out: u64,
+        expires_at: Option<i64>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
         let accounts_iter = &mut accounts.iter();
 
         let user_account = next_account_info(accounts_iter)?;
         let amm_data_account = next_account_info(accounts_iter)?;
-        let temp_first_token_account = next_account_info(accounts_iter)?;
+        let user_first_token_account = next_account_info(accounts_iter)?;
         let user_second_token_account = next_account_info(accounts_iter)?;
         let x_token_vault = next_account_info(accounts_iter)?;
         let y_token_vault = next_account_info(accounts_iter)?;
         let token_program = next_account_info(accounts_iter)?;
+        let observations_account = accounts_iter.next();
 
-        let mut amm_data = state::AMM::unpack(&amm_data_account.data.borrow())?;
-        let temp_first_token_account_data =
-            spl_token::state::Account::unpack(&temp_first_token_account.data.borrow())?;
+        Self::check_signer(user_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[user_first_token_account, user_second_token_account],
+        )?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        if amm_data.paused != 0 {
+            return Err(AMMErrors::PoolPaused.into());
+        }
+
+        if let Some(expires_at) = expires_at {
+            if Clock::get()?.unix_timestamp > expires_at {
+                return Err(AMMErrors::Expired.into());
+            }
+        }
+
+        let user_first_token_account_data =
+            spl_token::state::Account::unpack(&user_first_token_account.data.borrow())?;
         let user_second_token_account_data =
             spl_token::state::Account::unpack(&user_second_token_account.data.borrow())?;
 
-        let (first_token_vault, second_token_vault, second_token_vault_seed, second_token_amount) =
-            if temp_first_token_account_data.mint == amm_data.x_mint
-                && user_second_token_account_data.mint == amm_data.y_mint
-            {
-                let x_amount: Decimal = temp_first_token_account_data.amount.into();
-                let current_x: Decimal = amm_data.x_amount.into();
-                let current_y: Decimal = amm_data.y_amount.into();
+        let is_x_to_y = if user_first_token_account_data.mint == amm_data.x_mint
+            && user_second_token_account_data.mint == amm_data.y_mint
+        {
+            true
+        } else if user_first_token_account_data.mint == amm_data.y_mint
+            && user_second_token_account_data.mint == amm_data.x_mint
+        {
+            false
+        } else {
+            return Err(AMMErrors::TokenMintMismatch.into());
+        };
+
+        let (
+            direction,
+            first_token_vault,
+            second_token_vault,
+            second_token_vault_seed,
+            second_token_vault_bump_seed,
+        ) = if is_x_to_y {
+            (
+                TradeDirection::XtoY,
+                x_token_vault,
+                y_token_vault,
+                pda::Y_VAULT_SEED,
+                amm_data.y_vault_bump_seed,
+            )
+        } else {
+            (
+                TradeDirection::YtoX,
+                y_token_vault,
+                x_token_vault,
+                pda::X_VAULT_SEED,
+                amm_data.x_vault_bump_seed,
+            )
+        };
+
+        let second_token_amount = Self::swap_reserves(&mut amm_data, amount_in, direction)?;
 
-                let new_x = current_x + x_amount;
-                let new_y = current_y * current_x / new_x;
+        if second_token_amount < minimum_amount_out {
+            return Err(AMMErrors::InsufficientOutputAmount.into());
+        }
 
-                let y_amount = current_y - new_y;
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                user_first_token_account.key,
+                first_token_vault.key,
+                user_account.key,
+                &[user_account.key],
+                amount_in,
+            )?,
+            &[
+                user_first_token_account.clone(),
+                user_account.clone(),
+                first_token_vault.clone(),
+            ],
+        )?;
 
-                amm_data.x_amount = new_x.round().trunc().mantissa() as u64;
-                amm_data.y_amount = new_y.round().trunc().mantissa() as u64;
+        let second_token_vault_signer_seeds: &[&[_]] = &[
+            second_token_vault_seed,
+            amm_data_account.key.as_ref(),
+            &[second_token_vault_bump_seed],
+        ];
 
-                (
-                    x_token_vault.clone(),
-                    y_token_vault.clone(),
-                    b"y_vault".clone(),
-                    y_amount.round().trunc().mantissa() as u64,
-                )
-            } else if temp_first_token_account_data.mint == amm_data.y_mint
-                && user_second_token_account_data.mint == amm_data.x_mint
-            {
-                let y_amount: Decimal = temp_first_token_account_data.amount.into();
-                let current_x: Decimal = amm_data.x_amount.into();
-                let current_y: Decimal = amm_data.y_amount.into();
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                second_token_vault.key,
+                user_second_token_account.key,
+                second_token_vault.key,
+                &[second_token_vault.key],
+                second_token_amount,
+            )?,
+            &[
+                user_second_token_account.clone(),
+                second_token_vault.clone(),
+            ],
+            &[second_token_vault_signer_seeds],
+        )?;
+
+        Self::record_observation(&amm_data, observations_account)?;
+
+        Ok(())
+    }
+
+    fn process_route_exchange(
+        accounts: &[AccountInfo],
+        amount_in: u64,
+        minimum_amount_out: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account = next_account_info(accounts_iter)?;
+        let user_source_token_account = next_account_info(accounts_iter)?;
+        let user_destination_token_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let hops = &mut accounts_iter.peekable();
+
+        Self::check_signer(user_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[user_source_token_account, user_destination_token_account],
+        )?;
+
+        if hops.peek().is_none() {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+
+        let mut mint =
+            spl_token::state::Account::unpack(&user_source_token_account.data.borrow())?.mint;
+        let mut amount = amount_in;
+        // Pool and vault holding the output of the previous hop, with the
+        // bump seeds of the pool vaults
+        let mut previous_output: Option<(&AccountInfo, &AccountInfo, (u8, u8))> = None;
 
-                let new_y = current_y + y_amount;
-                let new_x = current_x * current_y / new_y;
+        while hops.peek().is_some() {
+            let amm_data_account = next_account_info(hops)?;
+            let x_token_vault = next_account_info(hops)?;
+            let y_token_vault = next_account_info(hops)?;
 
-                let x_amount = current_x - new_x;
+            let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+            // The optional observations account of the hop is told apart
+            // from the AMM data account of the next one by its key
+            let observations_account =
+                hops.next_if(|account| *account.key == amm_data.observations);
+            Self::check_vault_accounts(
+                amm_data_account,
+                x_token_vault,
+                y_token_vault,
+                amm_data.vault_bump_seeds(),
+                program_id,
+            )?;
 
-                amm_data.x_amount = new_x.round().trunc().mantissa() as u64;
-                amm_data.y_amount = new_y.round().trunc().mantissa() as u64;
+            if amm_data.paused != 0 {
+                return Err(AMMErrors::PoolPaused.into());
+            }
 
+            let (direction, input_vault, output_vault, output_mint) = if mint == amm_data.x_mint {
                 (
-                    y_token_vault.clone(),
-                    x_token_vault.clone(),
-                    b"x_vault".clone(),
-                    x_amount.round().trunc().mantissa() as u64,
+                    TradeDirection::XtoY,
+                    x_token_vault,
+                    y_token_vault,
+                    amm_data.y_mint,
+                )
+            } else if mint == amm_data.y_mint {
+                (
+                    TradeDirection::YtoX,
+                    y_token_vault,
+                    x_token_vault,
+                    amm_data.x_mint,
                 )
             } else {
                 return Err(AMMErrors::TokenMintMismatch.into());
             };
 
+            let amount_out = Self::swap_reserves(&mut amm_data, amount, direction)?;
+
+            // Intermediate amounts go straight from one pool's vault to the next
+            match previous_output {
+                None => invoke(
+                    &spl_token::instruction::transfer(
+                        token_program.key,
+                        user_source_token_account.key,
+                        input_vault.key,
+                        user_account.key,
+                        &[user_account.key],
+                        amount,
+                    )?,
+                    &[
+                        user_source_token_account.clone(),
+                        user_account.clone(),
+                        input_vault.clone(),
+                    ],
+                )?,
+                Some((pool_account, vault, vault_bump_seeds)) => Self::transfer_from_vaults(
+                    pool_account,
+                    [(vault, input_vault, amount)],
+                    vault_bump_seeds,
+                    token_program,
+                    program_id,
+                )?,
+            }
+
+            Self::record_observation(&amm_data, observations_account)?;
+            let vault_bump_seeds = amm_data.vault_bump_seeds();
+
+            previous_output = Some((amm_data_account, output_vault, vault_bump_seeds));
+            mint = output_mint;
+            amount = amount_out;
+        }
+
+        if mint
+            != spl_token::state::Account::unpack(&user_destination_token_account.data.borrow())?
+                .mint
+        {
+            return Err(AMMErrors::TokenMintMismatch.into());
+        }
+
+        if amount < minimum_amount_out {
+            return Err(AMMErrors::InsufficientOutputAmount.into());
+        }
+
+        if let Some((pool_account, vault, vault_bump_seeds)) = previous_output {
+            Self::transfer_from_vaults(
+                pool_account,
+                [(vault, user_destination_token_account, amount)],
+                vault_bump_seeds,
+                token_program,
+                program_id,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn process_exchange_exact_out(
+        accounts: &[AccountInfo],
+        amount_out: u64,
+        maximum_amount_in: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let user_first_token_account = next_account_info(accounts_iter)?;
+        let user_second_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let observations_account = accounts_iter.next();
+
+        Self::check_signer(user_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[user_first_token_account, user_second_token_account],
+        )?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        if amm_data.paused != 0 {
+            return Err(AMMErrors::PoolPaused.into());
+        }
+
+        let user_first_token_account_data =
+            spl_token::state::Account::unpack(&user_first_token_account.data.borrow())?;
+        let user_second_token_account_data =
+            spl_token::state::Account::unpack(&user_second_token_account.data.borrow())?;
+
+        let is_x_to_y = if user_first_token_account_data.mint == amm_data.x_mint
+            && user_second_token_account_data.mint == amm_data.y_mint
+        {
+            true
+        } else if user_first_token_account_data.mint == amm_data.y_mint
+            && user_second_token_account_data.mint == amm_data.x_mint
+        {
+            false
+        } else {
+            return Err(AMMErrors::TokenMintMismatch.into());
+        };
+
+        let (current_in, current_out, direction) = if is_x_to_y {
+            (amm_data.x_amount, amm_data.y_amount, TradeDirection::XtoY)
+        } else {
+            (amm_data.y_amount, amm_data.x_amount, TradeDirection::YtoX)
+        };
+
+        if amount_out >= current_out {
+            return Err(AMMErrors::InsufficientLiquidity.into());
+        }
+
+        // Input the curve requires for amount_out, grossed up by the fee,
+        // both rounded up so the pool never loses
+        let now = Clock::get()?.unix_timestamp;
+        oracle::update(&mut amm_data, now);
+
+        let amount_in_after_fee = curve::swap_curve(&amm_data, now)?.swap_in(
+            amount_out,
+            current_in,
+            current_out,
+            direction,
+        )?;
+        let amount_in = math::mul_div_ceil(
+            amount_in_after_fee.into(),
+            amm_data.fee_denominator.into(),
+            math::checked_sub(amm_data.fee_denominator, amm_data.fee_numerator)?.into(),
+        )?;
+
+        if amount_in > maximum_amount_in {
+            return Err(AMMErrors::ExcessiveInputAmount.into());
+        }
+
+        let protocol_fee = Self::protocol_fee(&amm_data, amount_in - amount_in_after_fee)?;
+        let reserve_in_increase = amount_in - protocol_fee;
+
+        let (
+            first_token_vault,
+            second_token_vault,
+            second_token_vault_seed,
+            second_token_vault_bump_seed,
+        ) = if is_x_to_y {
+            amm_data.x_amount = math::checked_add(amm_data.x_amount, reserve_in_increase)?;
+            amm_data.y_amount = math::checked_sub(amm_data.y_amount, amount_out)?;
+            amm_data.protocol_fees_x = math::checked_add(amm_data.protocol_fees_x, protocol_fee)?;
+            (
+                x_token_vault,
+                y_token_vault,
+                pda::Y_VAULT_SEED,
+                amm_data.y_vault_bump_seed,
+            )
+        } else {
+            amm_data.y_amount = math::checked_add(amm_data.y_amount, reserve_in_increase)?;
+            amm_data.x_amount = math::checked_sub(amm_data.x_amount, amount_out)?;
+            amm_data.protocol_fees_y = math::checked_add(amm_data.protocol_fees_y, protocol_fee)?;
+            (
+                y_token_vault,
+                x_token_vault,
+                pda::X_VAULT_SEED,
+                amm_data.x_vault_bump_seed,
+            )
+        };
+
         invoke(
             &spl_token::instruction::transfer(
                 token_program.key,
-                &temp_first_token_account.key,
-                &first_token_vault.key,
-                &user_account.key,
-                &[&user_account.key],
-                temp_first_token_account_data.amount,
+                user_first_token_account.key,
+                first_token_vault.key,
+                user_account.key,
+                &[user_account.key],
+                amount_in,
             )?,
             &[
-                temp_first_token_account.clone(),
+                user_first_token_account.clone(),
                 user_account.clone(),
                 first_token_vault.clone(),
             ],
         )?;
 
-        let (_, second_token_vault_bump_seed) =
-            Pubkey::find_program_address(&[&second_token_vault_seed], program_id);
-        let second_token_vault_signer_seeds: &[&[_]] =
-            &[&second_token_vault_seed, &[second_token_vault_bump_seed]];
+        let second_token_vault_signer_seeds: &[&[_]] = &[
+            second_token_vault_seed,
+            amm_data_account.key.as_ref(),
+            &[second_token_vault_bump_seed],
+        ];
 
         invoke_signed(
             &spl_token::instruction::transfer(
                 token_program.key,
-                &second_token_vault.key,
-                &user_second_token_account.key,
-                &second_token_vault.key,
-                &[&second_token_vault.key],
-                second_token_amount,
+                second_token_vault.key,
+                user_second_token_account.key,
+                second_token_vault.key,
+                &[second_token_vault.key],
+                amount_out,
             )?,
             &[
                 user_second_token_account.clone(),
                 second_token_vault.clone(),
             ],
-            &[&second_token_vault_signer_seeds],
+            &[second_token_vault_signer_seeds],
+        )?;
+
+        Self::record_observation(&amm_data, observations_account)?;
+
+        Ok(())
+    }
+
+    fn process_add_liquidity(
+        accounts: &[AccountInfo],
+        lp_amount: u64,
+        maximum_x_amount: u64,
+        maximum_y_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let user_x_token_account = next_account_info(accounts_iter)?;
+        let user_y_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let lp_mint_account = next_account_info(accounts_iter)?;
+        let user_lp_token_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let observations_account = accounts_iter.next();
+
+        Self::check_signer(user_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[
+                user_x_token_account,
+                user_y_token_account,
+                user_lp_token_account,
+            ],
+        )?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+
+        if amm_data.paused != 0 {
+            return Err(AMMErrors::PoolPaused.into());
+        }
+
+        Self::check_pool_accounts(
+            &amm_data,
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            lp_mint_account,
+            program_id,
+        )?;
+
+        let lp_supply = spl_token::state::Mint::unpack(&lp_mint_account.data.borrow())?.supply;
+        let now = Clock::get()?.unix_timestamp;
+        oracle::update(&mut amm_data, now);
+
+        // A drained pool is funded again like a new one, at the ratio of
+        // the maximum amounts
+        let (x_amount, y_amount) = if lp_supply == 0 {
+            (maximum_x_amount, maximum_y_amount)
+        } else {
+            curve::swap_curve(&amm_data, now)?.deposit_amounts(
+                lp_amount,
+                lp_supply,
+                amm_data.x_amount,
+                amm_data.y_amount,
+            )?
+        };
+
+        if lp_amount == 0 || x_amount == 0 || y_amount == 0 {
+            return Err(AMMErrors::ZeroDeposit.into());
+        }
+
+        if x_amount > maximum_x_amount || y_amount > maximum_y_amount {
+            return Err(AMMErrors::ExceededSlippage.into());
+        }
+
+        for (user_token_account, token_vault, amount) in [
+            (user_x_token_account, x_token_vault, x_amount),
+            (user_y_token_account, y_token_vault, y_amount),
+        ] {
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    user_token_account.key,
+                    token_vault.key,
+                    user_account.key,
+                    &[user_account.key],
+                    amount,
+                )?,
+                &[
+                    user_token_account.clone(),
+                    user_account.clone(),
+                    token_vault.clone(),
+                ],
+            )?;
+        }
+
+        let lp_mint_signer_seeds: &[&[_]] = &[
+            pda::LP_MINT_SEED,
+            amm_data_account.key.as_ref(),
+            &[amm_data.lp_mint_bump_seed],
+        ];
+
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                lp_mint_account.key,
+                user_lp_token_account.key,
+                lp_mint_account.key,
+                &[lp_mint_account.key],
+                lp_amount,
+            )?,
+            &[lp_mint_account.clone(), user_lp_token_account.clone()],
+            &[lp_mint_signer_seeds],
         )?;
 
-        state::AMM::pack(amm_data, &mut amm_data_account.data.borrow_mut())?;
+        amm_data.x_amount = math::checked_add(amm_data.x_amount, x_amount)?;
+        amm_data.y_amount = math::checked_add(amm_data.y_amount, y_amount)?;
+
+        Self::record_observation(&amm_data, observations_account)?;
 
         Ok(())
     }
+
+    fn process_remove_liquidity(
+        accounts: &[AccountInfo],
+        lp_amount: u64,
+        minimum_x_amount: u64,
+        minimum_y_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let user_x_token_account = next_account_info(accounts_iter)?;
+        let user_y_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let lp_mint_account = next_account_info(accounts_iter)?;
+        let user_lp_token_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let observations_account = accounts_iter.next();
+
+        Self::check_signer(user_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[
+                user_x_token_account,
+                user_y_token_account,
+                user_lp_token_account,
+            ],
+        )?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_pool_accounts(
+            &amm_data,
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            lp_mint_account,
+            program_id,
+        )?;
+
+        let lp_supply = spl_token::state::Mint::unpack(&lp_mint_account.data.borrow())?.supply;
+        let now = Clock::get()?.unix_timestamp;
+        oracle::update(&mut amm_data, now);
+
+        let (x_amount, y_amount) = curve::swap_curve(&amm_data, now)?.withdraw_amounts(
+            lp_amount,
+            lp_supply,
+            amm_data.x_amount,
+            amm_data.y_amount,
+        )?;
+
+        if x_amount < minimum_x_amount || y_amount < minimum_y_amount {
+            return Err(AMMErrors::ExceededSlippage.into());
+        }
+
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                user_lp_token_account.key,
+                lp_mint_account.key,
+                user_account.key,
+                &[user_account.key],
+                lp_amount,
+            )?,
+            &[
+                user_lp_token_account.clone(),
+                lp_mint_account.clone(),
+                user_account.clone(),
+            ],
+        )?;
+
+        for (vault_seed, vault_bump_seed, token_vault, user_token_account, amount) in [
+            (
+                pda::X_VAULT_SEED,
+                amm_data.x_vault_bump_seed,
+                x_token_vault,
+                user_x_token_account,
+                x_amount,
+            ),
+            (
+                pda::Y_VAULT_SEED,
+                amm_data.y_vault_bump_seed,
+                y_token_vault,
+                user_y_token_account,
+                y_amount,
+            ),
+        ] {
+            let vault_signer_seeds: &[&[_]] = &[
+                vault_seed,
+                amm_data_account.key.as_ref(),
+                &[vault_bump_seed],
+            ];
+
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    token_vault.key,
+                    user_token_account.key,
+                    token_vault.key,
+                    &[token_vault.key],
+                    amount,
+                )?,
+                &[user_token_account.clone(), token_vault.clone()],
+                &[vault_signer_seeds],
+            )?;
+        }
+
+        amm_data.x_amount = math::checked_sub(amm_data.x_amount, x_amount)?;
+        amm_data.y_amount = math::checked_sub(amm_data.y_amount, y_amount)?;
+
+        Self::record_observation(&amm_data, observations_account)?;
+
+        Ok(())
+    }
+
+    fn process_collect_protocol_fees(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let x_treasury_account = next_account_info(accounts_iter)?;
+        let y_treasury_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        Self::check_token_accounts(token_program, &[x_treasury_account, y_treasury_account])?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        Self::check_authority(&amm_data, authority_account)?;
+
+        for (vault_seed, vault_bump_seed, token_vault, treasury_account, amount) in [
+            (
+                pda::X_VAULT_SEED,
+                amm_data.x_vault_bump_seed,
+                x_token_vault,
+                x_treasury_account,
+                amm_data.protocol_fees_x,
+            ),
+            (
+                pda::Y_VAULT_SEED,
+                amm_data.y_vault_bump_seed,
+                y_token_vault,
+                y_treasury_account,
+                amm_data.protocol_fees_y,
+            ),
+        ] {
+            let vault_signer_seeds: &[&[_]] = &[
+                vault_seed,
+                amm_data_account.key.as_ref(),
+                &[vault_bump_seed],
+            ];
+
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    token_vault.key,
+                    treasury_account.key,
+                    token_vault.key,
+                    &[token_vault.key],
+                    amount,
+                )?,
+                &[treasury_account.clone(), token_vault.clone()],
+                &[vault_signer_seeds],
+            )?;
+        }
+
+        amm_data.protocol_fees_x = 0;
+        amm_data.protocol_fees_y = 0;
+
+        Ok(())
+    }
+
+    fn process_set_authority(
+        accounts: &[AccountInfo],
+        new_authority: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_authority(&amm_data, authority_account)?;
+
+        amm_data.pending_authority = new_authority;
+
+        Ok(())
+    }
+
+    fn process_accept_authority(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let pending_authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+
+        if !pending_authority_account.is_signer
+            || amm_data.pending_authority == Pubkey::default()
+            || *pending_authority_account.key != amm_data.pending_authority
+        {
+            return Err(AMMErrors::Unauthorized.into());
+        }
+
+        amm_data.authority = amm_data.pending_authority;
+        amm_data.pending_authority = Pubkey::default();
+
+        Ok(())
+    }
+
+    fn process_set_fees(
+        accounts: &[AccountInfo],
+        (fee_numerator, fee_denominator): (u64, u64),
+        (protocol_fee_numerator, protocol_fee_denominator): (u64, u64),
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_authority(&amm_data, authority_account)?;
+        Self::check_fees(
+            (fee_numerator, fee_denominator),
+            (protocol_fee_numerator, protocol_fee_denominator),
+        )?;
+
+        amm_data.fee_numerator = fee_numerator;
+        amm_data.fee_denominator = fee_denominator;
+        amm_data.protocol_fee_numerator = protocol_fee_numerator;
+        amm_data.protocol_fee_denominator = protocol_fee_denominator;
+
+        Ok(())
+    }
+
+    fn process_set_paused(
+        accounts: &[AccountInfo],
+        paused: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_authority(&amm_data, authority_account)?;
+
+        amm_data.paused = paused as u8;
+
+        Ok(())
+    }
+
+    fn process_ramp_amplification(
+        accounts: &[AccountInfo],
+        target_amp: u64,
+        ramp_stop_ts: i64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_authority(&amm_data, authority_account)?;
+
+        if amm_data.curve_type != CurveType::StableSwap as u8 {
+            return Err(AMMErrors::InvalidCurve.into());
+        }
+
+        if !(curve::MIN_AMP..=curve::MAX_AMP).contains(&target_amp) {
+            return Err(AMMErrors::InvalidAmplification.into());
+        }
+
+        // The new ramp starts from wherever the current one got to
+        let now = Clock::get()?.unix_timestamp;
+        amm_data.initial_amp = curve::amplification(&amm_data, now);
+        amm_data.target_amp = target_amp;
+        amm_data.ramp_start_ts = now;
+        amm_data.ramp_stop_ts = ramp_stop_ts.max(now);
+
+        Ok(())
+    }
+
+    fn process_flash_loan(
+        accounts: &[AccountInfo],
+        amount: u64,
+        token: &Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let borrower_token_account = next_account_info(accounts_iter)?;
+        let receiver_program = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let observations_account = next_account_info(accounts_iter)?;
+
+        Self::check_token_accounts(token_program, &[borrower_token_account])?;
+        // Calling back into the pool mid-loan would trade on reserves that
+        // are out on loan
+        if receiver_program.key == program_id || !receiver_program.executable {
+            return Err(AMMErrors::InvalidProgramAccount.into());
+        }
+
+        let amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        if amm_data.paused != 0 {
+            return Err(AMMErrors::PoolPaused.into());
+        }
+
+        let (token_vault, reserve) = if *token == amm_data.x_mint {
+            (x_token_vault, amm_data.x_amount)
+        } else if *token == amm_data.y_mint {
+            (y_token_vault, amm_data.y_amount)
+        } else {
+            return Err(AMMErrors::TokenMintMismatch.into());
+        };
+
+        if amount > reserve {
+            return Err(AMMErrors::InsufficientLiquidity.into());
+        }
+
+        let fee = math::mul_div_ceil(
+            amount.into(),
+            amm_data.fee_numerator.into(),
+            amm_data.fee_denominator.into(),
+        )?;
+        let balance_before = spl_token::state::Account::unpack(&token_vault.data.borrow())?.amount;
+
+        Self::transfer_from_vaults(
+            amm_data_account,
+            [(token_vault, borrower_token_account, amount)],
+            amm_data.vault_bump_seeds(),
+            token_program,
+            program_id,
+        )?;
+
+        let mut receiver_accounts = vec![
+            borrower_token_account.clone(),
+            token_vault.clone(),
+            token_program.clone(),
+        ];
+        receiver_accounts.extend(accounts_iter.cloned());
+        let callback = Instruction {
+            program_id: *receiver_program.key,
+            accounts: receiver_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: FlashLoanCallback { amount, fee }.pack(),
+        };
+        receiver_accounts.push(receiver_program.clone());
+        // The receiver accounts may include the pool
+        drop(amm_data);
+        invoke(&callback, &receiver_accounts)?;
+        let mut data = amm_data_account.data.borrow_mut();
+        let amm_data = state::AMM::load_mut(&mut data)?;
+
+        let balance_after = spl_token::state::Account::unpack(&token_vault.data.borrow())?.amount;
+        if balance_after < math::checked_add(balance_before, fee)? {
+            return Err(AMMErrors::FlashLoanNotRepaid.into());
+        }
+
+        // The fee goes to the reserves like a trading fee, anything paid
+        // above it is left for `Skim`
+        oracle::update(amm_data, Clock::get()?.unix_timestamp);
+        let protocol_fee = Self::protocol_fee(amm_data, fee)?;
+        if *token == amm_data.x_mint {
+            amm_data.x_amount = math::checked_add(amm_data.x_amount, fee - protocol_fee)?;
+            amm_data.protocol_fees_x = math::checked_add(amm_data.protocol_fees_x, protocol_fee)?;
+        } else {
+            amm_data.y_amount = math::checked_add(amm_data.y_amount, fee - protocol_fee)?;
+            amm_data.protocol_fees_y = math::checked_add(amm_data.protocol_fees_y, protocol_fee)?;
+        }
+
+        Self::record_observation(amm_data, Some(observations_account))?;
+
+        Ok(())
+    }
+
+    fn process_sync(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let observations_account = accounts_iter.next();
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        let x_balance = spl_token::state::Account::unpack(&x_token_vault.data.borrow())?.amount;
+        let y_balance = spl_token::state::Account::unpack(&y_token_vault.data.borrow())?.amount;
+
+        oracle::update(&mut amm_data, Clock::get()?.unix_timestamp);
+        amm_data.x_amount = math::checked_sub(x_balance, amm_data.protocol_fees_x)?;
+        amm_data.y_amount = math::checked_sub(y_balance, amm_data.protocol_fees_y)?;
+
+        Self::record_observation(&amm_data, observations_account)?;
+
+        Ok(())
+    }
+
+    fn process_migrate_state(
+        accounts: &[AccountInfo],
+        pool_index: u16,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let amm_data_account = next_account_info(accounts_iter)?;
+
+        Self::check_owner(amm_data_account, program_id)?;
+        Self::check_writable(amm_data_account)?;
+
+        let version = *amm_data_account
+            .data
+            .borrow()
+            .first()
+            .ok_or(ProgramError::InvalidAccountData)?;
+        match version {
+            state::AMM::VERSION => Ok(()),
+            // The data of version 1 accounts cannot be resized by this
+            // runtime, so the pool moves to a new account of the current size
+            1 => Self::process_migrate_v1_state(accounts, pool_index, program_id),
+            // Not a layout this program ever wrote
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    fn process_migrate_v1_state(
+        accounts: &[AccountInfo],
+        pool_index: u16,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let legacy_amm_data_account = next_account_info(accounts_iter)?;
+        let payer_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let legacy_x_vault_account = next_account_info(accounts_iter)?;
+        let legacy_y_vault_account = next_account_info(accounts_iter)?;
+        let x_vault_account = next_account_info(accounts_iter)?;
+        let y_vault_account = next_account_info(accounts_iter)?;
+        let x_mint_account = next_account_info(accounts_iter)?;
+        let y_mint_account = next_account_info(accounts_iter)?;
+        let lp_mint_account = next_account_info(accounts_iter)?;
+        let lp_account = next_account_info(accounts_iter)?;
+        let system_account = next_account_info(accounts_iter)?;
+        let rent_sysvar = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let program_data_account = next_account_info(accounts_iter)?;
+
+        // The caller picks the pool index and becomes the pool authority
+        Self::check_upgrade_authority(payer_account, program_data_account, program_id)?;
+
+        let legacy_data = state::PoolV1::unpack(&legacy_amm_data_account.data.borrow())?;
+
+        let (legacy_amm_data_address, _) = pda::find_legacy_pool_address(program_id);
+        let (legacy_x_vault_address, legacy_x_vault_bump_seed) =
+            pda::find_legacy_x_vault_address(program_id);
+        let (legacy_y_vault_address, legacy_y_vault_bump_seed) =
+            pda::find_legacy_y_vault_address(program_id);
+
+        let (amm_data_address, amm_data_bump_seed) = pda::find_pool_address(
+            program_id,
+            &legacy_data.x_mint,
+            &legacy_data.y_mint,
+            pool_index,
+        );
+        let (x_vault_address, x_vault_bump_seed) =
+            pda::find_x_vault_address(program_id, &amm_data_address);
+        let (y_vault_address, y_vault_bump_seed) =
+            pda::find_y_vault_address(program_id, &amm_data_address);
+        let (lp_mint_address, lp_mint_bump_seed) =
+            pda::find_lp_mint_address(program_id, &amm_data_address);
+
+        Self::check_signer(payer_account)?;
+        Self::check_signer(lp_account)?;
+        Self::check_program(system_account, &system_program::id())?;
+        Self::check_program(token_program, &spl_token::id())?;
+
+        if *legacy_amm_data_account.key != legacy_amm_data_address
+            || *amm_data_account.key != amm_data_address
+        {
+            return Err(AMMErrors::DataAccountMismatch.into());
+        }
+
+        if *legacy_x_vault_account.key != legacy_x_vault_address
+            || *legacy_y_vault_account.key != legacy_y_vault_address
+            || *x_vault_account.key != x_vault_address
+            || *y_vault_account.key != y_vault_address
+            || *lp_mint_account.key != lp_mint_address
+        {
+            return Err(AMMErrors::VaultAccountMismatch.into());
+        }
+
+        if *x_mint_account.key != legacy_data.x_mint || *y_mint_account.key != legacy_data.y_mint {
+            return Err(AMMErrors::TokenMintMismatch.into());
+        }
+
+        for account in [
+            payer_account,
+            amm_data_account,
+            legacy_x_vault_account,
+            legacy_y_vault_account,
+            x_vault_account,
+            y_vault_account,
+            lp_mint_account,
+            lp_account,
+        ] {
+            Self::check_writable(account)?;
+        }
+
+        for account in [x_mint_account, y_mint_account] {
+            Self::check_owner(account, &spl_token::id())?;
+        }
+
+        if !amm_data_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let rent = &Rent::from_account_info(rent_sysvar)?;
+        let (first_mint, second_mint) = pda::sort_mints(x_mint_account.key, y_mint_account.key);
+        let amm_data_signer_seeds: &[&[_]] = &[
+            pda::POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+            &[amm_data_bump_seed],
+        ];
+
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_account.key,
+                amm_data_account.key,
+                1.max(rent.minimum_balance(state::AMM::LEN)),
+                state::AMM::LEN as u64,
+                program_id,
+            ),
+            &[
+                payer_account.clone(),
+                amm_data_account.clone(),
+                system_account.clone(),
+            ],
+            &[amm_data_signer_seeds],
+        )?;
+
+        // Version 1 vaults were their own authority, derived from the seed
+        // alone. They are emptied into the new vaults and closed.
+        for (
+            (legacy_vault_seed, legacy_vault_bump_seed, legacy_vault_account),
+            (vault_seed, vault_bump_seed, vault_account),
+            mint_account,
+        ) in [
+            (
+                (
+                    pda::X_VAULT_SEED,
+                    legacy_x_vault_bump_seed,
+                    legacy_x_vault_account,
+                ),
+                (pda::X_VAULT_SEED, x_vault_bump_seed, x_vault_account),
+                x_mint_account,
+            ),
+            (
+                (
+                    pda::Y_VAULT_SEED,
+                    legacy_y_vault_bump_seed,
+                    legacy_y_vault_account,
+                ),
+                (pda::Y_VAULT_SEED, y_vault_bump_seed, y_vault_account),
+                y_mint_account,
+            ),
+        ] {
+            Self::create_vault(
+                payer_account,
+                amm_data_account,
+                (vault_account, vault_seed, vault_bump_seed),
+                mint_account,
+                rent,
+                rent_sysvar,
+                token_program,
+            )?;
+
+            let legacy_vault_signer_seeds: &[&[_]] =
+                &[legacy_vault_seed, &[legacy_vault_bump_seed]];
+            let balance =
+                spl_token::state::Account::unpack(&legacy_vault_account.data.borrow())?.amount;
+
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    legacy_vault_account.key,
+                    vault_account.key,
+                    legacy_vault_account.key,
+                    &[legacy_vault_account.key],
+                    balance,
+                )?,
+                &[legacy_vault_account.clone(), vault_account.clone()],
+                &[legacy_vault_signer_seeds],
+            )?;
+
+            invoke_signed(
+                &spl_token::instruction::close_account(
+                    token_program.key,
+                    legacy_vault_account.key,
+                    payer_account.key,
+                    legacy_vault_account.key,
+                    &[legacy_vault_account.key],
+                )?,
+                &[legacy_vault_account.clone(), payer_account.clone()],
+                &[legacy_vault_signer_seeds],
+            )?;
+        }
+
+        // Nobody could withdraw from a version 1 pool, so the LP tokens for
+        // its reserves stay locked with the pool, which never signs for them
+        Self::create_lp_mint(
+            payer_account,
+            amm_data_account,
+            (lp_mint_account, lp_mint_bump_seed),
+            (lp_account, amm_data_account),
+            rent,
+            rent_sysvar,
+            token_program,
+        )?;
+
+        let amm_data = state::AMM {
+            version: state::AMM::VERSION,
+            is_initialized: 1,
+            pool_index,
+            x_mint: legacy_data.x_mint,
+            x_amount: legacy_data.x_amount,
+            y_mint: legacy_data.y_mint,
+            y_amount: legacy_data.y_amount,
+            lp_mint: lp_mint_address,
+            fee_numerator: 0,
+            fee_denominator: 1,
+            protocol_fee_numerator: 0,
+            protocol_fee_denominator: 1,
+            authority: *payer_account.key,
+            curve_type: CurveType::ConstantProduct as u8,
+            x_decimals: spl_token::state::Mint::unpack(&x_mint_account.data.borrow())?.decimals,
+            y_decimals: spl_token::state::Mint::unpack(&y_mint_account.data.borrow())?.decimals,
+            last_update_ts: Clock::get()?.unix_timestamp,
+            bump_seed: amm_data_bump_seed,
+            x_vault_bump_seed,
+            y_vault_bump_seed,
+            lp_mint_bump_seed,
+            ..Zeroable::zeroed()
+        };
+
+        *state::AMM::load_mut(&mut amm_data_account.data.borrow_mut())? = amm_data;
+
+        // Close the version 1 account, the runtime drops it without lamports
+        legacy_amm_data_account.data.borrow_mut().fill(0);
+        let lamports = legacy_amm_data_account.lamports();
+        **legacy_amm_data_account.lamports.borrow_mut() = 0;
+        **payer_account.lamports.borrow_mut() = payer_account
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(AMMErrors::MathOverflow)?;
+
+        Ok(())
+    }
+
+    fn process_skim(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let x_destination_account = next_account_info(accounts_iter)?;
+        let y_destination_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        Self::check_token_accounts(
+            token_program,
+            &[x_destination_account, y_destination_account],
+        )?;
+
+        let amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        let excess = |vault: &AccountInfo, reserve: u64, protocol_fees: u64| {
+            let balance = spl_token::state::Account::unpack(&vault.data.borrow())?.amount;
+            Ok::<_, ProgramError>(
+                balance.saturating_sub(math::checked_add(reserve, protocol_fees)?),
+            )
+        };
+        let x_excess = excess(x_token_vault, amm_data.x_amount, amm_data.protocol_fees_x)?;
+        let y_excess = excess(y_token_vault, amm_data.y_amount, amm_data.protocol_fees_y)?;
+
+        Self::transfer_from_vaults(
+            amm_data_account,
+            [
+                (x_token_vault, x_destination_account, x_excess),
+                (y_token_vault, y_destination_account, y_excess),
+            ],
+            amm_data.vault_bump_seeds(),
+            token_program,
+            program_id,
+        )
+    }
+
+    fn process_initialize_observations(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let observations_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_authority(&amm_data, authority_account)?;
+
+        if amm_data.observations != Pubkey::default() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let mut observations =
+            Self::load_new_observations(observations_account, amm_data_account, program_id)?;
+        if observations.capacity == 0 {
+            return Err(AMMErrors::InvalidObservationsCapacity.into());
+        }
+
+        oracle::update(&mut amm_data, Clock::get()?.unix_timestamp);
+        observations.push(oracle::sample(&amm_data));
+        observations.pack(&mut observations_account.data.borrow_mut())?;
+
+        amm_data.observations = *observations_account.key;
+
+        Ok(())
+    }
+
+    fn process_grow_observations(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(accounts_iter)?;
+        let amm_data_account = next_account_info(accounts_iter)?;
+        let observations_account = next_account_info(accounts_iter)?;
+        let new_observations_account = next_account_info(accounts_iter)?;
+
+        let mut amm_data = Self::load_amm_data(amm_data_account, program_id)?;
+        Self::check_authority(&amm_data, authority_account)?;
+        Self::check_writable(authority_account)?;
+
+        if amm_data.observations == Pubkey::default()
+            || *observations_account.key != amm_data.observations
+        {
+            return Err(AMMErrors::ObservationsMismatch.into());
+        }
+        Self::check_writable(observations_account)?;
+        let observations = state::Observations::unpack(&observations_account.data.borrow())?;
+
+        let mut new_observations =
+            Self::load_new_observations(new_observations_account, amm_data_account, program_id)?;
+        if new_observations.capacity <= observations.capacity {
+            return Err(AMMErrors::InvalidObservationsCapacity.into());
+        }
+
+        // Copied oldest first, so the new buffer fills up from index 0
+        new_observations.samples = observations.chronological().copied().collect();
+        new_observations.newest = new_observations.samples.len().saturating_sub(1) as u32;
+        new_observations.pack(&mut new_observations_account.data.borrow_mut())?;
+
+        // Close the current account, the runtime drops it without lamports
+        observations_account.data.borrow_mut().fill(0);
+        let lamports = observations_account.lamports();
+        **observations_account.lamports.borrow_mut() = 0;
+        **authority_account.lamports.borrow_mut() = authority_account
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(AMMErrors::MathOverflow)?;
+
+        amm_data.observations = *new_observations_account.key;
+
+        Ok(())
+    }
+
+    fn process_initialize_concentrated(
+        accounts: &[AccountInfo],
+        pool_index: u16,
+        (fee_numerator, fee_denominator): (u64, u64),
+        tick_spacing: u16,
+        initial_sqrt_price: u128,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(accounts_iter)?;
+        let pool_account = next_account_info(accounts_iter)?;
+        let x_vault_account = next_account_info(accounts_iter)?;
+        let x_mint_account = next_account_info(accounts_iter)?;
+        let y_vault_account = next_account_info(accounts_iter)?;
+        let y_mint_account = next_account_info(accounts_iter)?;
+        let system_account = next_account_info(accounts_iter)?;
+        let rent_sysvar = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        let (pool_address, pool_bump_seed) = pda::find_concentrated_pool_address(
+            program_id,
+            x_mint_account.key,
+            y_mint_account.key,
+            pool_index,
+        );
+        let (x_vault_address, x_vault_bump_seed) =
+            pda::find_x_vault_address(program_id, &pool_address);
+        let (y_vault_address, y_vault_bump_seed) =
+            pda::find_y_vault_address(program_id, &pool_address);
+
+        Self::check_signer(owner_account)?;
+        Self::check_program(system_account, &system_program::id())?;
+        Self::check_program(token_program, &spl_token::id())?;
+
+        if *pool_account.key != pool_address {
+            return Err(AMMErrors::DataAccountMismatch.into());
+        }
+
+        if *x_vault_account.key != x_vault_address || *y_vault_account.key != y_vault_address {
+            return Err(AMMErrors::VaultAccountMismatch.into());
+        }
+
+        for account in [
+            owner_account,
+            pool_account,
+            x_vault_account,
+            y_vault_account,
+        ] {
+            Self::check_writable(account)?;
+        }
+
+        for account in [x_mint_account, y_mint_account] {
+            Self::check_owner(account, &spl_token::id())?;
+        }
+
+        if !pool_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        if x_mint_account.key == y_mint_account.key {
+            return Err(AMMErrors::TokenMintMismatch.into());
+        }
+
+        Self::check_fees((fee_numerator, fee_denominator), (0, 1))?;
+
+        if tick_spacing == 0 || tick_spacing > concentrated::MAX_TICK_SPACING {
+            return Err(AMMErrors::InvalidTickRange.into());
+        }
+
+        if !(concentrated::MIN_SQRT_PRICE..concentrated::MAX_SQRT_PRICE)
+            .contains(&initial_sqrt_price)
+        {
+            return Err(AMMErrors::InvalidSqrtPrice.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_sysvar)?;
+        let (first_mint, second_mint) = pda::sort_mints(x_mint_account.key, y_mint_account.key);
+        let pool_signer_seeds: &[&[_]] = &[
+            pda::CONCENTRATED_POOL_SEED,
+            first_mint.as_ref(),
+            second_mint.as_ref(),
+            &pool_index.to_le_bytes(),
+            &[pool_bump_seed],
+        ];
+
+        invoke_signed(
+            &system_instruction::create_account(
+                owner_account.key,
+                pool_account.key,
+                1.max(rent.minimum_balance(state::ConcentratedPool::LEN)),
+                state::ConcentratedPool::LEN as u64,
+                program_id,
+            ),
+            &[
+                owner_account.clone(),
+                pool_account.clone(),
+                system_account.clone(),
+            ],
+            &[pool_signer_seeds],
+        )?;
+
+        for (vault, mint_account) in [
+            (
+                (x_vault_account, pda::X_VAULT_SEED, x_vault_bump_seed),
+                x_mint_account,
+            ),
+            (
+                (y_vault_account, pda::Y_VAULT_SEED, y_vault_bump_seed),
+                y_mint_account,
+            ),
+        ] {
+            Self::create_vault(
+                owner_account,
+                pool_account,
+                vault,
+                mint_account,
+                rent,
+                rent_sysvar,
+                token_program,
+            )?;
+        }
+
+        let pool = state::ConcentratedPool {
+            is_initialized: true,
+            pool_index,
+            x_mint: *x_mint_account.key,
+            y_mint: *y_mint_account.key,
+            fee_numerator,
+            fee_denominator,
+            tick_spacing,
+            sqrt_price: initial_sqrt_price,
+            tick_current: concentrated::tick_at_sqrt_price(initial_sqrt_price)?,
+            liquidity: 0,
+            fee_growth_global_x: 0,
+            fee_growth_global_y: 0,
+            bump_seed: pool_bump_seed,
+            x_vault_bump_seed,
+            y_vault_bump_seed,
+            ticks: Vec::new(),
+        };
+
+        state::ConcentratedPool::pack(pool, &mut pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_open_position(
+        accounts: &[AccountInfo],
+        tick_lower: i32,
+        tick_upper: i32,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(accounts_iter)?;
+        let pool_account = next_account_info(accounts_iter)?;
+        let position_account = next_account_info(accounts_iter)?;
+        let system_account = next_account_info(accounts_iter)?;
+
+        Self::check_signer(owner_account)?;
+        Self::check_writable(owner_account)?;
+        Self::check_writable(position_account)?;
+        Self::check_program(system_account, &system_program::id())?;
+
+        let pool = Self::load_concentrated_pool(pool_account, program_id)?;
+        concentrated::check_tick_range(tick_lower, tick_upper, pool.tick_spacing)?;
+
+        let (position_address, position_bump_seed) = pda::find_position_address(
+            program_id,
+            pool_account.key,
+            owner_account.key,
+            tick_lower,
+            tick_upper,
+        );
+        if *position_account.key != position_address {
+            return Err(AMMErrors::PositionMismatch.into());
+        }
+
+        if !position_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let position_signer_seeds: &[&[_]] = &[
+            pda::POSITION_SEED,
+            pool_account.key.as_ref(),
+            owner_account.key.as_ref(),
+            &tick_lower.to_le_bytes(),
+            &tick_upper.to_le_bytes(),
+            &[position_bump_seed],
+        ];
+
+        invoke_signed(
+            &system_instruction::create_account(
+                owner_account.key,
+                position_account.key,
+                1.max(Rent::get()?.minimum_balance(state::Position::LEN)),
+                state::Position::LEN as u64,
+                program_id,
+            ),
+            &[
+                owner_account.clone(),
+                position_account.clone(),
+                system_account.clone(),
+            ],
+            &[position_signer_seeds],
+        )?;
+
+        let position = state::Position {
+            is_initialized: true,
+            pool: *pool_account.key,
+            owner: *owner_account.key,
+            tick_lower,
+            tick_upper,
+            liquidity: 0,
+            fee_growth_inside_x_last: 0,
+            fee_growth_inside_y_last: 0,
+            tokens_owed_x: 0,
+            tokens_owed_y: 0,
+        };
+
+        state::Position::pack(position, &mut position_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_increase_liquidity(
+        accounts: &[AccountInfo],
+        liquidity: u128,
+        (maximum_x_amount, maximum_y_amount): (u64, u64),
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(accounts_iter)?;
+        let pool_account = next_account_info(accounts_iter)?;
+        let position_account = next_account_info(accounts_iter)?;
+        let owner_x_token_account = next_account_info(accounts_iter)?;
+        let owner_y_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        let (mut pool, mut position) = Self::load_position(
+            owner_account,
+            pool_account,
+            position_account,
+            (owner_x_token_account, owner_y_token_account),
+            (x_token_vault, y_token_vault),
+            token_program,
+            program_id,
+        )?;
+
+        let (x_amount, y_amount) = concentrated::modify_position(
+            &mut pool,
+            &mut position,
+            concentrated::liquidity_delta(liquidity, false)?,
+        )?;
+
+        if x_amount > maximum_x_amount || y_amount > maximum_y_amount {
+            return Err(AMMErrors::ExcessiveInputAmount.into());
+        }
+
+        for (owner_token_account, token_vault, amount) in [
+            (owner_x_token_account, x_token_vault, x_amount),
+            (owner_y_token_account, y_token_vault, y_amount),
+        ] {
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    owner_token_account.key,
+                    token_vault.key,
+                    owner_account.key,
+                    &[owner_account.key],
+                    amount,
+                )?,
+                &[
+                    owner_token_account.clone(),
+                    owner_account.clone(),
+                    token_vault.clone(),
+                ],
+            )?;
+        }
+
+        state::ConcentratedPool::pack(pool, &mut pool_account.data.borrow_mut())?;
+        state::Position::pack(position, &mut position_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_decrease_liquidity(
+        accounts: &[AccountInfo],
+        liquidity: u128,
+        (minimum_x_amount, minimum_y_amount): (u64, u64),
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(accounts_iter)?;
+        let pool_account = next_account_info(accounts_iter)?;
+        let position_account = next_account_info(accounts_iter)?;
+        let owner_x_token_account = next_account_info(accounts_iter)?;
+        let owner_y_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        let (mut pool, mut position) = Self::load_position(
+            owner_account,
+            pool_account,
+            position_account,
+            (owner_x_token_account, owner_y_token_account),
+            (x_token_vault, y_token_vault),
+            token_program,
+            program_id,
+        )?;
+
+        if liquidity > position.liquidity {
+            return Err(AMMErrors::InsufficientLiquidity.into());
+        }
+
+        let (x_amount, y_amount) = concentrated::modify_position(
+            &mut pool,
+            &mut position,
+            concentrated::liquidity_delta(liquidity, true)?,
+        )?;
+
+        if x_amount < minimum_x_amount || y_amount < minimum_y_amount {
+            return Err(AMMErrors::InsufficientOutputAmount.into());
+        }
+
+        Self::transfer_from_vaults(
+            pool_account,
+            [
+                (x_token_vault, owner_x_token_account, x_amount),
+                (y_token_vault, owner_y_token_account, y_amount),
+            ],
+            pool.vault_bump_seeds(),
+            token_program,
+            program_id,
+        )?;
+
+        state::ConcentratedPool::pack(pool, &mut pool_account.data.borrow_mut())?;
+        state::Position::pack(position, &mut position_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_collect_position_fees(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(accounts_iter)?;
+        let pool_account = next_account_info(accounts_iter)?;
+        let position_account = next_account_info(accounts_iter)?;
+        let owner_x_token_account = next_account_info(accounts_iter)?;
+        let owner_y_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        let (mut pool, mut position) = Self::load_position(
+            owner_account,
+            pool_account,
+            position_account,
+            (owner_x_token_account, owner_y_token_account),
+            (x_token_vault, y_token_vault),
+            token_program,
+            program_id,
+        )?;
+
+        // Settles the fees earned since the last update of the position
+        concentrated::modify_position(&mut pool, &mut position, 0)?;
+
+        Self::transfer_from_vaults(
+            pool_account,
+            [
+                (x_token_vault, owner_x_token_account, position.tokens_owed_x),
+                (y_token_vault, owner_y_token_account, position.tokens_owed_y),
+            ],
+            pool.vault_bump_seeds(),
+            token_program,
+            program_id,
+        )?;
+
+        position.tokens_owed_x = 0;
+        position.tokens_owed_y = 0;
+
+        state::ConcentratedPool::pack(pool, &mut pool_account.data.borrow_mut())?;
+        state::Position::pack(position, &mut position_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_exchange_concentrated(
+        accounts: &[AccountInfo],
+        amount_in: u64,
+        minimum_amount_out: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account = next_account_info(accounts_iter)?;
+        let pool_account = next_account_info(accounts_iter)?;
+        let user_first_token_account = next_account_info(accounts_iter)?;
+        let user_second_token_account = next_account_info(accounts_iter)?;
+        let x_token_vault = next_account_info(accounts_iter)?;
+        let y_token_vault = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        Self::check_signer(user_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[user_first_token_account, user_second_token_account],
+        )?;
+
+        let mut pool = Self::load_concentrated_pool(pool_account, program_id)?;
+        Self::check_writable(pool_account)?;
+        Self::check_vault_accounts(
+            pool_account,
+            x_token_vault,
+            y_token_vault,
+            pool.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        let user_first_token_account_data =
+            spl_token::state::Account::unpack(&user_first_token_account.data.borrow())?;
+        let user_second_token_account_data =
+            spl_token::state::Account::unpack(&user_second_token_account.data.borrow())?;
+
+        let (direction, first_token_vault, second_token_vault) =
+            if user_first_token_account_data.mint == pool.x_mint
+                && user_second_token_account_data.mint == pool.y_mint
+            {
+                (TradeDirection::XtoY, x_token_vault, y_token_vault)
+            } else if user_first_token_account_data.mint == pool.y_mint
+                && user_second_token_account_data.mint == pool.x_mint
+            {
+                (TradeDirection::YtoX, y_token_vault, x_token_vault)
+            } else {
+                return Err(AMMErrors::TokenMintMismatch.into());
+            };
+
+        let second_token_amount = concentrated::swap(&mut pool, amount_in, direction)?;
+
+        if second_token_amount < minimum_amount_out {
+            return Err(AMMErrors::InsufficientOutputAmount.into());
+        }
+
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                user_first_token_account.key,
+                first_token_vault.key,
+                user_account.key,
+                &[user_account.key],
+                amount_in,
+            )?,
+            &[
+                user_first_token_account.clone(),
+                user_account.clone(),
+                first_token_vault.clone(),
+            ],
+        )?;
+
+        Self::transfer_from_vaults(
+            pool_account,
+            [(
+                second_token_vault,
+                user_second_token_account,
+                second_token_amount,
+            )],
+            pool.vault_bump_seeds(),
+            token_program,
+            program_id,
+        )?;
+
+        state::ConcentratedPool::pack(pool, &mut pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Creates a token vault PDA of `pool_account`, owned by itself.
+    fn create_vault<'a>(
+        payer_account: &AccountInfo<'a>,
+        pool_account: &AccountInfo<'a>,
+        (vault_account, vault_seed, vault_bump_seed): (&AccountInfo<'a>, &[u8], u8),
+        mint_account: &AccountInfo<'a>,
+        rent: &Rent,
+        rent_sysvar: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let vault_signer_seeds: &[&[_]] =
+            &[vault_seed, pool_account.key.as_ref(), &[vault_bump_seed]];
+
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_account.key,
+                vault_account.key,
+                1.max(rent.minimum_balance(spl_token::state::Account::LEN)),
+                spl_token::state::Account::LEN as u64,
+                token_program.key,
+            ),
+            &[vault_account.clone(), payer_account.clone()],
+            &[vault_signer_seeds],
+        )?;
+
+        invoke(
+            &spl_token::instruction::initialize_account(
+                token_program.key,
+                vault_account.key,
+                mint_account.key,
+                vault_account.key,
+            )?,
+            &[
+                vault_account.clone(),
+                mint_account.clone(),
+                rent_sysvar.clone(),
+            ],
+        )
+    }
+
+    /// Creates the LP mint of `pool_account` and mints `INITIAL_LP_SUPPLY` to a
+    /// new LP token account of `lp_owner`.
+    fn create_lp_mint<'a>(
+        payer_account: &AccountInfo<'a>,
+        pool_account: &AccountInfo<'a>,
+        (lp_mint_account, lp_mint_bump_seed): (&AccountInfo<'a>, u8),
+        (lp_account, lp_owner): (&AccountInfo<'a>, &AccountInfo<'a>),
+        rent: &Rent,
+        rent_sysvar: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let lp_mint_signer_seeds: &[&[_]] = &[
+            pda::LP_MINT_SEED,
+            pool_account.key.as_ref(),
+            &[lp_mint_bump_seed],
+        ];
+
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_account.key,
+                lp_mint_account.key,
+                1.max(rent.minimum_balance(spl_token::state::Mint::LEN)),
+                spl_token::state::Mint::LEN as u64,
+                token_program.key,
+            ),
+            &[lp_mint_account.clone(), payer_account.clone()],
+            &[lp_mint_signer_seeds],
+        )?;
+
+        invoke(
+            &spl_token::instruction::initialize_mint(
+                token_program.key,
+                lp_mint_account.key,
+                lp_mint_account.key,
+                None,
+                state::LP_DECIMALS,
+            )?,
+            &[lp_mint_account.clone(), rent_sysvar.clone()],
+        )?;
+
+        invoke(
+            &system_instruction::create_account(
+                payer_account.key,
+                lp_account.key,
+                1.max(rent.minimum_balance(spl_token::state::Account::LEN)),
+                spl_token::state::Account::LEN as u64,
+                token_program.key,
+            ),
+            &[lp_account.clone(), payer_account.clone()],
+        )?;
+
+        invoke(
+            &spl_token::instruction::initialize_account(
+                token_program.key,
+                lp_account.key,
+                lp_mint_account.key,
+                lp_owner.key,
+            )?,
+            &[
+                lp_account.clone(),
+                lp_mint_account.clone(),
+                lp_owner.clone(),
+                rent_sysvar.clone(),
+            ],
+        )?;
+
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                lp_mint_account.key,
+                lp_account.key,
+                lp_mint_account.key,
+                &[lp_mint_account.key],
+                state::INITIAL_LP_SUPPLY,
+            )?,
+            &[lp_mint_account.clone(), lp_account.clone()],
+            &[lp_mint_signer_seeds],
+        )
+    }
+
+    /// Pays out of the vaults of `pool_account`, which sign for themselves.
+    fn transfer_from_vaults<'a, const N: usize>(
+        pool_account: &AccountInfo<'a>,
+        transfers: [(&AccountInfo<'a>, &AccountInfo<'a>, u64); N],
+        (x_vault_bump_seed, y_vault_bump_seed): (u8, u8),
+        token_program: &AccountInfo<'a>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let x_vault_address =
+            pda::create_x_vault_address(program_id, pool_account.key, x_vault_bump_seed)?;
+
+        for (vault, destination, amount) in transfers {
+            let (vault_seed, vault_bump_seed) = if *vault.key == x_vault_address {
+                (pda::X_VAULT_SEED, x_vault_bump_seed)
+            } else {
+                (pda::Y_VAULT_SEED, y_vault_bump_seed)
+            };
+            let vault_signer_seeds: &[&[_]] =
+                &[vault_seed, pool_account.key.as_ref(), &[vault_bump_seed]];
+
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    vault.key,
+                    destination.key,
+                    vault.key,
+                    &[vault.key],
+                    amount,
+                )?,
+                &[destination.clone(), vault.clone()],
+                &[vault_signer_seeds],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Unpacks a concentrated pool once its account is known to be the
+    /// program-owned PDA of its mint pair.
+    fn load_concentrated_pool(
+        pool_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<state::ConcentratedPool, ProgramError> {
+        Self::check_owner(pool_account, program_id)?;
+
+        let pool = state::ConcentratedPool::unpack(&pool_account.data.borrow())?;
+        let pool_address = pda::create_concentrated_pool_address(
+            program_id,
+            &pool.x_mint,
+            &pool.y_mint,
+            pool.pool_index,
+            pool.bump_seed,
+        )?;
+
+        if *pool_account.key != pool_address {
+            return Err(AMMErrors::DataAccountMismatch.into());
+        }
+
+        Ok(pool)
+    }
+
+    /// Validates the accounts shared by the position instructions and
+    /// unpacks the pool and the position of the signing owner.
+    fn load_position<'a>(
+        owner_account: &AccountInfo,
+        pool_account: &AccountInfo,
+        position_account: &AccountInfo,
+        (owner_x_token_account, owner_y_token_account): (&AccountInfo<'a>, &AccountInfo<'a>),
+        (x_token_vault, y_token_vault): (&AccountInfo, &AccountInfo),
+        token_program: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<(state::ConcentratedPool, state::Position), ProgramError> {
+        Self::check_signer(owner_account)?;
+        Self::check_token_accounts(
+            token_program,
+            &[owner_x_token_account, owner_y_token_account],
+        )?;
+
+        let pool = Self::load_concentrated_pool(pool_account, program_id)?;
+        Self::check_writable(pool_account)?;
+        Self::check_vault_accounts(
+            pool_account,
+            x_token_vault,
+            y_token_vault,
+            pool.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        Self::check_owner(position_account, program_id)?;
+        Self::check_writable(position_account)?;
+        let position = state::Position::unpack(&position_account.data.borrow())?;
+        if position.pool != *pool_account.key || position.owner != *owner_account.key {
+            return Err(AMMErrors::PositionMismatch.into());
+        }
+
+        Ok((pool, position))
+    }
+
+    /// Unpacks an observations account created for `amm_data_account` by the
+    /// client, still uninitialized.
+    fn load_new_observations(
+        observations_account: &AccountInfo,
+        amm_data_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<state::Observations, ProgramError> {
+        Self::check_writable(observations_account)?;
+        Self::check_owner(observations_account, program_id)?;
+
+        if !Rent::get()?.is_exempt(
+            observations_account.lamports(),
+            observations_account.data_len(),
+        ) {
+            return Err(ProgramError::AccountNotRentExempt);
+        }
+
+        let mut observations = state::Observations::unpack(&observations_account.data.borrow())?;
+        if observations.is_initialized {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        observations.is_initialized = true;
+        observations.pool = *amm_data_account.key;
+
+        Ok(observations)
+    }
+
+    /// Records a price sample of the pool in its observations account after
+    /// a change of the reserves. The account is optional so that clients
+    /// sending the accounts of before observations keep working; when passed
+    /// it must be the one the pool records into, or the default pubkey if it
+    /// has none.
+    fn record_observation(
+        amm_data: &state::AMM,
+        observations_account: Option<&AccountInfo>,
+    ) -> ProgramResult {
+        let observations_account = match observations_account {
+            Some(observations_account) => observations_account,
+            None => return Ok(()),
+        };
+        if *observations_account.key != amm_data.observations {
+            return Err(AMMErrors::ObservationsMismatch.into());
+        }
+        if amm_data.observations == Pubkey::default() {
+            return Ok(());
+        }
+        Self::check_writable(observations_account)?;
+
+        let mut observations = state::Observations::unpack(&observations_account.data.borrow())?;
+        observations.push(oracle::sample(amm_data));
+        observations.pack(&mut observations_account.data.borrow_mut())
+    }
+
+    /// Checks that the pool authority signed the transaction.
+    fn check_authority(amm_data: &state::AMM, authority_account: &AccountInfo) -> ProgramResult {
+        if !authority_account.is_signer || *authority_account.key != amm_data.authority {
+            return Err(AMMErrors::Unauthorized.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the upgrade authority of the program signed the
+    /// transaction, as set in its `bpf_loader_upgradeable` program data.
+    fn check_upgrade_authority(
+        authority_account: &AccountInfo,
+        program_data_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let (program_data_address, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        if *program_data_account.key != program_data_address {
+            return Err(AMMErrors::Unauthorized.into());
+        }
+        Self::check_owner(program_data_account, &bpf_loader_upgradeable::id())?;
+
+        // Bincode `UpgradeableLoaderState::ProgramData`: the variant index as
+        // u32, the deployment slot and the optional upgrade authority
+        let data = program_data_account.data.borrow();
+        let upgrade_authority = match data.get(..45) {
+            Some(header) if header[..4] == 3u32.to_le_bytes() && header[12] == 1 => {
+                Pubkey::new(&header[13..45])
+            }
+            _ => return Err(AMMErrors::Unauthorized.into()),
+        };
+        if !authority_account.is_signer || *authority_account.key != upgrade_authority {
+            return Err(AMMErrors::Unauthorized.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the trading fee is below 100% and the protocol share is a fraction of it.
+    fn check_fees(
+        (fee_numerator, fee_denominator): (u64, u64),
+        (protocol_fee_numerator, protocol_fee_denominator): (u64, u64),
+    ) -> ProgramResult {
+        if fee_denominator == 0
+            || fee_numerator >= fee_denominator
+            || protocol_fee_denominator == 0
+            || protocol_fee_numerator > protocol_fee_denominator
+        {
+            return Err(AMMErrors::InvalidFee.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the vaults and the LP mint belong to the pool.
+    fn check_pool_accounts(
+        amm_data: &state::AMM,
+        amm_data_account: &AccountInfo,
+        x_token_vault: &AccountInfo,
+        y_token_vault: &AccountInfo,
+        lp_mint_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        Self::check_vault_accounts(
+            amm_data_account,
+            x_token_vault,
+            y_token_vault,
+            amm_data.vault_bump_seeds(),
+            program_id,
+        )?;
+
+        if *lp_mint_account.key != amm_data.lp_mint {
+            return Err(AMMErrors::VaultAccountMismatch.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the vaults belong to the pool.
+    fn check_vault_accounts(
+        amm_data_account: &AccountInfo,
+        x_token_vault: &AccountInfo,
+        y_token_vault: &AccountInfo,
+        (x_vault_bump_seed, y_vault_bump_seed): (u8, u8),
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let x_vault_address =
+            pda::create_x_vault_address(program_id, amm_data_account.key, x_vault_bump_seed)?;
+        let y_vault_address =
+            pda::create_y_vault_address(program_id, amm_data_account.key, y_vault_bump_seed)?;
+
+        if *x_token_vault.key != x_vault_address || *y_token_vault.key != y_vault_address {
+            return Err(AMMErrors::VaultAccountMismatch.into());
+        }
+
+        Self::check_writable(x_token_vault)?;
+        Self::check_writable(y_token_vault)
+    }
+
+    /// Borrows the pool state in place once the data account is known to be
+    /// the writable, program-owned PDA of its mint pair. Changes to it are
+    /// written straight to the account data.
+    fn load_amm_data<'a>(
+        amm_data_account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<RefMut<'a, state::AMM>, ProgramError> {
+        Self::check_owner(amm_data_account, program_id)?;
+        Self::check_writable(amm_data_account)?;
+
+        let mut data = amm_data_account.try_borrow_mut_data()?;
+        let amm_data = state::AMM::load_mut(&mut data)?;
+        let amm_data_address = pda::create_pool_address(
+            program_id,
+            &amm_data.x_mint,
+            &amm_data.y_mint,
+            amm_data.pool_index,
+            amm_data.bump_seed,
+        )?;
+
+        if *amm_data_account.key != amm_data_address {
+            return Err(AMMErrors::DataAccountMismatch.into());
+        }
+
+        Ok(RefMut::map(data, |data| bytemuck::from_bytes_mut(data)))
+    }
+
+    /// Checks that `token_program` is spl-token and that the token accounts
+    /// of the caller are writable spl-token accounts. Every handler calling
+    /// the token program, and signing for the pool PDAs in particular, runs
+    /// this before its first CPI.
+    fn check_token_accounts(
+        token_program: &AccountInfo,
+        token_accounts: &[&AccountInfo],
+    ) -> ProgramResult {
+        Self::check_program(token_program, &spl_token::id())?;
+
+        for account in token_accounts {
+            Self::check_writable(account)?;
+            Self::check_owner(account, &spl_token::id())?;
+        }
+
+        Ok(())
+    }
+
+    fn check_signer(account: &AccountInfo) -> ProgramResult {
+        if !account.is_signer {
+            return Err(AMMErrors::AccountNotSigner.into());
+        }
+
+        Ok(())
+    }
+
+    fn check_writable(account: &AccountInfo) -> ProgramResult {
+        if !account.is_writable {
+            return Err(AMMErrors::AccountNotWritable.into());
+        }
+
+        Ok(())
+    }
+
+    fn check_owner(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+        if account.owner != owner {
+            return Err(AMMErrors::InvalidAccountOwner.into());
+        }
+
+        Ok(())
+    }
+
+    fn check_program(account: &AccountInfo, program_id: &Pubkey) -> ProgramResult {
+        if account.key != program_id {
+            return Err(AMMErrors::InvalidProgramAccount.into());
+        }
+
+        Ok(())
+    }
+
+    /// Prices `amount_in` on the pool curve and moves the reserves and the
+    /// protocol fees accordingly, returning the output amount. The price
+    /// accumulators are brought up to date first.
+    fn swap_reserves(
+        amm_data: &mut state::AMM,
+        amount_in: u64,
+        direction: TradeDirection,
+    ) -> Result<u64, ProgramError> {
+        let (current_in, current_out) = match direction {
+            TradeDirection::XtoY => (amm_data.x_amount, amm_data.y_amount),
+            TradeDirection::YtoX => (amm_data.y_amount, amm_data.x_amount),
+        };
+
+        // The fee is taken from the input and stays in the reserves, so K grows.
+        // Both the priced input and the output are rounded down.
+        let amount_in_after_fee = math::mul_div_floor(
+            amount_in.into(),
+            math::checked_sub(amm_data.fee_denominator, amm_data.fee_numerator)?.into(),
+            amm_data.fee_denominator.into(),
+        )?;
+        let now = Clock::get()?.unix_timestamp;
+        oracle::update(amm_data, now);
+
+        let amount_out = curve::swap_curve(amm_data, now)?.swap_out(
+            amount_in_after_fee,
+            current_in,
+            current_out,
+            direction,
+        )?;
+        let protocol_fee = Self::protocol_fee(amm_data, amount_in - amount_in_after_fee)?;
+        let reserve_in_increase = amount_in - protocol_fee;
+
+        match direction {
+            TradeDirection::XtoY => {
+                amm_data.x_amount = math::checked_add(amm_data.x_amount, reserve_in_increase)?;
+                amm_data.y_amount = math::checked_sub(amm_data.y_amount, amount_out)?;
+                amm_data.protocol_fees_x =
+                    math::checked_add(amm_data.protocol_fees_x, protocol_fee)?;
+            }
+            TradeDirection::YtoX => {
+                amm_data.y_amount = math::checked_add(amm_data.y_amount, reserve_in_increase)?;
+                amm_data.x_amount = math::checked_sub(amm_data.x_amount, amount_out)?;
+                amm_data.protocol_fees_y =
+                    math::checked_add(amm_data.protocol_fees_y, protocol_fee)?;
+            }
+        }
+
+        Ok(amount_out)
+    }
+
+    /// Part of the trading fee accrued to the protocol treasury.
+    fn protocol_fee(amm_data: &state::AMM, trading_fee: u64) -> Result<u64, ProgramError> {
+        math::mul_div_floor(
+            trading_fee.into(),
+            amm_data.protocol_fee_numerator.into(),
+            amm_data.protocol_fee_denominator.into(),
+        )
+    }
 }
@@ -5,71 +5,677 @@ use solana_program::{
 };
 
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use bytemuck::{Pod, Zeroable};
+use std::{convert::TryFrom, mem};
 
-#[derive(Debug)]
+use crate::{curve::CurveType, error::AMMErrors};
+
+/// LP tokens minted to the pool owner at `Initialize`
+pub const INITIAL_LP_SUPPLY: u64 = 1_000_000_000;
+pub const LP_DECIMALS: u8 = 9;
+
+/// Pool state, `repr(C)` and little-endian without any padding, so that the
+/// AMM data account is read in place and clients can cast its bytes. Fields
+/// are ordered by alignment, which matches on every target.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
 pub struct AMM {
-    pub is_initialized: bool,
+    /// Layout version, `AMM::VERSION` once initialized
+    pub version: u8,
+    /// 1 once `Initialize` ran
+    pub is_initialized: u8,
+    pub pool_index: u16,
+
+    /// 1 when set by `Pause`, blocks exchanges and deposits but not withdrawals
+    pub paused: u8,
+
+    /// `CurveType` used to price exchanges
+    pub curve_type: u8,
+
+    /// Decimals of the mints, used to normalize StableSwap reserves
+    pub x_decimals: u8,
+    pub y_decimals: u8,
+
+    /// Bump seeds of the pool PDAs, found once at `Initialize`
+    pub bump_seed: u8,
+    pub x_vault_bump_seed: u8,
+    pub y_vault_bump_seed: u8,
+    pub lp_mint_bump_seed: u8,
+
+    /// Zeroed, aligns the accumulators to 16 bytes
+    pub padding: [u8; 4],
+
+    /// Spot prices of X in Y and of Y in X, Q64.64, summed over every second
+    /// since `Initialize`. Wrap around, only differences are meaningful.
+    pub price_x_cumulative: u128,
+    pub price_y_cumulative: u128,
 
-    pub x_mint: Pubkey,
     pub x_amount: u64,
+    pub y_amount: u64,
+
+    /// Trading fee taken from the input amount, e.g. 30 / 10_000 for 0.3%
+    pub fee_numerator: u64,
+    pub fee_denominator: u64,
+
+    /// Share of the trading fee accrued to the protocol, e.g. 1 / 6
+    pub protocol_fee_numerator: u64,
+    pub protocol_fee_denominator: u64,
+
+    /// Protocol fees held in the vaults on top of `x_amount` / `y_amount`
+    pub protocol_fees_x: u64,
+    pub protocol_fees_y: u64,
+
+    /// StableSwap amplification, ramped linearly from `initial_amp` to
+    /// `target_amp` between the two unix timestamps
+    pub initial_amp: u64,
+    pub target_amp: u64,
+    pub ramp_start_ts: i64,
+    pub ramp_stop_ts: i64,
 
+    /// Weighted pool shares of X and Y in percent, zero for other curves
+    pub x_weight: u64,
+    pub y_weight: u64,
+
+    /// Unix timestamp the accumulators were last updated at
+    pub last_update_ts: i64,
+
+    pub x_mint: Pubkey,
     pub y_mint: Pubkey,
-    pub y_amount: u64,
+    pub lp_mint: Pubkey,
+
+    /// Pool admin, the account that initialized the pool
+    pub authority: Pubkey,
+    /// Authority proposed by `SetAuthority`, takes over on `AcceptAuthority`
+    pub pending_authority: Pubkey,
+
+    /// `Observations` ring buffer written on every change of the reserves,
+    /// default if none
+    pub observations: Pubkey,
+
+    /// Zeroed, taken by fields added later so that accounts never need to
+    /// grow
+    pub reserved: [u8; 120],
 }
 
+// Fails to compile if the fields, whose sizes add up to `LEN`, get padded
+const _: [(); AMM::LEN] = [(); mem::size_of::<AMM>()];
+
+// SAFETY: `AMM` is `repr(C)` without padding, as checked above, and its
+// fields are integers, byte arrays and `Pubkey`s, a transparent `[u8; 32]`,
+// so any bytes are a valid value and zero is a valid default.
+unsafe impl Zeroable for AMM {}
+unsafe impl Pod for AMM {}
+
+impl AMM {
+    /// Layout written by this version of the program, stored in the first
+    /// byte. Byte 0 used to be `is_initialized` before layouts were
+    /// versioned, so 0 means uninitialized, 1 an unversioned account and
+    /// versions start at 2.
+    pub const VERSION: u8 = 2;
+
+    /// Borrows the pool state in place from account data, which needs the
+    /// alignment of `AMM`. Account data passed to programs always has it,
+    /// elsewhere `AMM::unpack` copies the state out instead.
+    pub fn load(src: &[u8]) -> Result<&Self, ProgramError> {
+        let amm_data: &Self =
+            bytemuck::try_from_bytes(src).map_err(|_| ProgramError::InvalidAccountData)?;
+        amm_data.check()?;
+        Ok(amm_data)
+    }
+
+    /// Mutable version of `AMM::load`.
+    pub fn load_mut(src: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        let amm_data: &mut Self =
+            bytemuck::try_from_bytes_mut(src).map_err(|_| ProgramError::InvalidAccountData)?;
+        amm_data.check()?;
+        Ok(amm_data)
+    }
+
+    /// Bump seeds of the X and Y vaults.
+    pub fn vault_bump_seeds(&self) -> (u8, u8) {
+        (self.x_vault_bump_seed, self.y_vault_bump_seed)
+    }
+
+    /// Checks the fields that do not accept every value.
+    fn check(&self) -> Result<(), ProgramError> {
+        // Older layouts are upgraded by `MigrateState` before use
+        if self.version != 0 && self.version != AMM::VERSION {
+            return Err(AMMErrors::OutdatedState.into());
+        }
+        if self.is_initialized > 1 || self.paused > 1 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        CurveType::try_from(self.curve_type)?;
+
+        Ok(())
+    }
+}
 
 impl Sealed for AMM {}
 
 impl IsInitialized for AMM {
     fn is_initialized(&self) -> bool {
-        self.is_initialized
+        self.is_initialized == 1
     }
 }
 
 impl Pack for AMM {
-    const LEN: usize = 81;
+    const LEN: usize = 480;
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, AMM::LEN];
+        let amm_data: Self =
+            bytemuck::try_pod_read_unaligned(src).map_err(|_| ProgramError::InvalidAccountData)?;
+        amm_data.check()?;
+
+        Ok(amm_data)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst.copy_from_slice(bytemuck::bytes_of(self));
+    }
+}
+
+/// Pool state of version 1, before layouts were versioned: the program had a
+/// single pool, at the `pda::find_legacy_pool_address` PDA, and its amounts
+/// were big-endian. `MigrateState` moves it into a pool of the current layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolV1 {
+    pub x_mint: Pubkey,
+    pub x_amount: u64,
+    pub y_mint: Pubkey,
+    pub y_amount: u64,
+}
+
+impl PoolV1 {
+    pub const LEN: usize = 81;
+
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() != Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, PoolV1::LEN];
         let (is_initialized, x_mint, x_amount, y_mint, y_amount) =
             array_refs![src, 1, 32, 8, 32, 8];
+        if is_initialized != &[1] {
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        Ok(PoolV1 {
+            x_mint: Pubkey::new(x_mint),
+            x_amount: u64::from_be_bytes(*x_amount),
+            y_mint: Pubkey::new(y_mint),
+            y_amount: u64::from_be_bytes(*y_amount),
+        })
+    }
+}
+
+/// Ring buffer of past accumulators of an `AMM` pool, so that prices can be
+/// averaged over any window it covers. Accounts cannot grow once created, so
+/// the pool authority creates it with room for as many samples as it wants
+/// to keep and `GrowObservations` moves them to a larger account.
+///
+/// Little-endian like `AMM`, but packed field by field instead of cast: the
+/// number of samples follows from the account size, which a `repr(C)` struct
+/// cannot describe.
+#[derive(Debug)]
+pub struct Observations {
+    pub is_initialized: bool,
+    pub pool: Pubkey,
+    /// Index of the most recent sample
+    pub newest: u32,
+    /// Samples in storage order, the oldest follows `newest` once full
+    pub samples: Vec<PriceSample>,
+    /// Samples the account has room for, from its data length
+    pub capacity: usize,
+}
+
+impl Observations {
+    pub const HEADER_LEN: usize = 41;
+
+    /// Samples an account of `data_len` bytes has room for.
+    pub fn capacity(data_len: usize) -> usize {
+        data_len.saturating_sub(Self::HEADER_LEN) / PriceSample::LEN
+    }
+
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::HEADER_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let header = array_ref![src, 0, Observations::HEADER_LEN];
+        let (is_initialized, pool, newest, count) = array_refs![header, 1, 32, 4, 4];
         let is_initialized = match is_initialized {
             [0] => false,
             [1] => true,
             _ => return Err(ProgramError::InvalidAccountData),
         };
 
-        let x_mint = Pubkey::new(x_mint);
-        let x_amount = u64::from_be_bytes(*x_amount);
-        let y_mint = Pubkey::new(y_mint);
-        let y_amount = u64::from_be_bytes(*y_amount);
+        let capacity = Self::capacity(src.len());
+        let newest = u32::from_le_bytes(*newest);
+        let count = u32::from_le_bytes(*count) as usize;
+        if count > capacity || (count > 0 && newest as usize >= count) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let samples = src[Self::HEADER_LEN..Self::HEADER_LEN + count * PriceSample::LEN]
+            .chunks(PriceSample::LEN)
+            .map(PriceSample::unpack_from_slice)
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(AMM {
+        Ok(Observations {
             is_initialized,
-            x_mint,
-            x_amount,
-            y_mint,
-            y_amount,
+            pool: Pubkey::new(pool),
+            newest,
+            samples,
+            capacity,
+        })
+    }
+
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if Self::capacity(dst.len()) < self.samples.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let header = array_mut_ref![dst, 0, Observations::HEADER_LEN];
+        let (is_initialized_dst, pool_dst, newest_dst, count_dst) =
+            mut_array_refs![header, 1, 32, 4, 4];
+
+        is_initialized_dst[0] = self.is_initialized as u8;
+        pool_dst.copy_from_slice(&self.pool.to_bytes());
+        newest_dst.copy_from_slice(&self.newest.to_le_bytes());
+        // The capacity bounds the count and fits a u32 for any account size
+        count_dst.copy_from_slice(&(self.samples.len() as u32).to_le_bytes());
+        for (sample, sample_dst) in self
+            .samples
+            .iter()
+            .zip(dst[Self::HEADER_LEN..].chunks_mut(PriceSample::LEN))
+        {
+            sample.pack_into_slice(sample_dst);
+        }
+
+        Ok(())
+    }
+
+    /// Records `sample`, replacing the oldest one once the buffer is full.
+    /// Samples taken at the same second collapse into the latest one.
+    pub fn push(&mut self, sample: PriceSample) {
+        let newest = self.newest as usize;
+        match self.samples.get(newest) {
+            Some(last) if last.timestamp == sample.timestamp => {
+                self.samples[newest] = sample;
+            }
+            _ if self.samples.len() < self.capacity => {
+                self.newest = self.samples.len() as u32;
+                self.samples.push(sample);
+            }
+            _ => {
+                self.newest = ((newest + 1) % self.capacity) as u32;
+                self.samples[self.newest as usize] = sample;
+            }
+        }
+    }
+
+    /// Samples from the oldest to the newest.
+    pub fn chronological(&self) -> impl Iterator<Item = &PriceSample> {
+        let split = if self.samples.is_empty() {
+            0
+        } else {
+            self.newest as usize + 1
+        };
+        let (newer, older) = self.samples.split_at(split);
+        older.iter().chain(newer)
+    }
+}
+
+/// Sample of an `Observations` ring buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PriceSample {
+    pub timestamp: i64,
+    /// `AMM` price accumulators as of `timestamp`
+    pub price_x_cumulative: u128,
+    pub price_y_cumulative: u128,
+    /// Geometric mean of the reserves, how deep the pool was
+    pub liquidity: u64,
+}
+
+impl Sealed for PriceSample {}
+
+impl Pack for PriceSample {
+    const LEN: usize = 48;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, PriceSample::LEN];
+        let (timestamp, price_x_cumulative, price_y_cumulative, liquidity) =
+            array_refs![src, 8, 16, 16, 8];
+
+        Ok(PriceSample {
+            timestamp: i64::from_le_bytes(*timestamp),
+            price_x_cumulative: u128::from_le_bytes(*price_x_cumulative),
+            price_y_cumulative: u128::from_le_bytes(*price_y_cumulative),
+            liquidity: u64::from_le_bytes(*liquidity),
         })
     }
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
-        let dst = array_mut_ref![dst, 0, AMM::LEN];
-        let (is_initialized_dst, x_mint_dst, x_amount_dst, y_mint_dst, y_amount_dst) =
-            mut_array_refs![dst, 1, 32, 8, 32, 8];
+        let dst = array_mut_ref![dst, 0, PriceSample::LEN];
+        let (timestamp_dst, price_x_cumulative_dst, price_y_cumulative_dst, liquidity_dst) =
+            mut_array_refs![dst, 8, 16, 16, 8];
 
-        let AMM {
+        timestamp_dst.copy_from_slice(&self.timestamp.to_le_bytes());
+        price_x_cumulative_dst.copy_from_slice(&self.price_x_cumulative.to_le_bytes());
+        price_y_cumulative_dst.copy_from_slice(&self.price_y_cumulative.to_le_bytes());
+        liquidity_dst.copy_from_slice(&self.liquidity.to_le_bytes());
+    }
+}
+
+/// Initialized ticks a concentrated liquidity pool can hold. Accounts cannot
+/// grow once created, so the pool reserves room for all of them up front.
+/// Taking up the slots costs locked value whatever the range, see
+/// `concentrated::MIN_POSITION_LIQUIDITY`.
+pub const MAX_TICKS: usize = 64;
+
+const TICKS_LEN: usize = MAX_TICKS * Tick::LEN;
+
+/// Concentrated liquidity pool, a separate pool type next to `AMM`.
+/// Liquidity is provided by positions over tick ranges instead of being
+/// spread over the whole price curve. It has no authority, so neither the
+/// protocol fee nor `Pause` apply to it.
+///
+/// Packed field by field in little-endian rather than cast like `AMM`. The
+/// instructions work on the ticks as a sorted `Vec`, inserting and removing
+/// ticks, so the list is copied out and back whole anyway.
+#[derive(Debug)]
+pub struct ConcentratedPool {
+    pub is_initialized: bool,
+    pub pool_index: u16,
+
+    pub x_mint: Pubkey,
+    pub y_mint: Pubkey,
+
+    /// Trading fee taken from the input amount
+    pub fee_numerator: u64,
+    pub fee_denominator: u64,
+
+    /// Position bounds must be multiples of the spacing
+    pub tick_spacing: u16,
+
+    /// Square root of the Y per X price, Q64.64
+    pub sqrt_price: u128,
+    /// Greatest tick whose sqrt price is not above `sqrt_price`, or one
+    /// less right after the price crossed that tick downwards
+    pub tick_current: i32,
+    /// Liquidity of the positions in range of `tick_current`
+    pub liquidity: u128,
+
+    /// Fees earned per unit of liquidity since the pool was created, Q64.64.
+    /// Wraps around, only differences are meaningful.
+    pub fee_growth_global_x: u128,
+    pub fee_growth_global_y: u128,
+
+    /// Bump seeds of the pool PDA and its vaults, found once at
+    /// `InitializeConcentrated`
+    pub bump_seed: u8,
+    pub x_vault_bump_seed: u8,
+    pub y_vault_bump_seed: u8,
+
+    /// Initialized ticks sorted by index
+    pub ticks: Vec<Tick>,
+}
+
+impl ConcentratedPool {
+    /// Bump seeds of the X and Y vaults.
+    pub fn vault_bump_seeds(&self) -> (u8, u8) {
+        (self.x_vault_bump_seed, self.y_vault_bump_seed)
+    }
+}
+
+impl Sealed for ConcentratedPool {}
+
+impl IsInitialized for ConcentratedPool {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for ConcentratedPool {
+    const LEN: usize = 157 + TICKS_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, ConcentratedPool::LEN];
+        let (
             is_initialized,
+            pool_index,
             x_mint,
-            x_amount,
             y_mint,
-            y_amount,
-        } = self;
+            fee_numerator,
+            fee_denominator,
+            tick_spacing,
+            sqrt_price,
+            tick_current,
+            liquidity,
+            fee_growth_global_x,
+            fee_growth_global_y,
+            bump_seed,
+            x_vault_bump_seed,
+            y_vault_bump_seed,
+            tick_count,
+            ticks,
+        ) = array_refs![src, 1, 2, 32, 32, 8, 8, 2, 16, 4, 16, 16, 16, 1, 1, 1, 1, TICKS_LEN];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        let tick_count = tick_count[0] as usize;
+        if tick_count > MAX_TICKS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let ticks = ticks[..tick_count * Tick::LEN]
+            .chunks(Tick::LEN)
+            .map(Tick::unpack_from_slice)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ConcentratedPool {
+            is_initialized,
+            pool_index: u16::from_le_bytes(*pool_index),
+            x_mint: Pubkey::new(x_mint),
+            y_mint: Pubkey::new(y_mint),
+            fee_numerator: u64::from_le_bytes(*fee_numerator),
+            fee_denominator: u64::from_le_bytes(*fee_denominator),
+            tick_spacing: u16::from_le_bytes(*tick_spacing),
+            sqrt_price: u128::from_le_bytes(*sqrt_price),
+            tick_current: i32::from_le_bytes(*tick_current),
+            liquidity: u128::from_le_bytes(*liquidity),
+            fee_growth_global_x: u128::from_le_bytes(*fee_growth_global_x),
+            fee_growth_global_y: u128::from_le_bytes(*fee_growth_global_y),
+            bump_seed: bump_seed[0],
+            x_vault_bump_seed: x_vault_bump_seed[0],
+            y_vault_bump_seed: y_vault_bump_seed[0],
+            ticks,
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, ConcentratedPool::LEN];
+        let (
+            is_initialized_dst,
+            pool_index_dst,
+            x_mint_dst,
+            y_mint_dst,
+            fee_numerator_dst,
+            fee_denominator_dst,
+            tick_spacing_dst,
+            sqrt_price_dst,
+            tick_current_dst,
+            liquidity_dst,
+            fee_growth_global_x_dst,
+            fee_growth_global_y_dst,
+            bump_seed_dst,
+            x_vault_bump_seed_dst,
+            y_vault_bump_seed_dst,
+            tick_count_dst,
+            ticks_dst,
+        ) = mut_array_refs![dst, 1, 2, 32, 32, 8, 8, 2, 16, 4, 16, 16, 16, 1, 1, 1, 1, TICKS_LEN];
+
+        is_initialized_dst[0] = self.is_initialized as u8;
+        pool_index_dst.copy_from_slice(&self.pool_index.to_le_bytes());
+        x_mint_dst.copy_from_slice(&self.x_mint.to_bytes());
+        y_mint_dst.copy_from_slice(&self.y_mint.to_bytes());
+        fee_numerator_dst.copy_from_slice(&self.fee_numerator.to_le_bytes());
+        fee_denominator_dst.copy_from_slice(&self.fee_denominator.to_le_bytes());
+        tick_spacing_dst.copy_from_slice(&self.tick_spacing.to_le_bytes());
+        sqrt_price_dst.copy_from_slice(&self.sqrt_price.to_le_bytes());
+        tick_current_dst.copy_from_slice(&self.tick_current.to_le_bytes());
+        liquidity_dst.copy_from_slice(&self.liquidity.to_le_bytes());
+        fee_growth_global_x_dst.copy_from_slice(&self.fee_growth_global_x.to_le_bytes());
+        fee_growth_global_y_dst.copy_from_slice(&self.fee_growth_global_y.to_le_bytes());
+        bump_seed_dst[0] = self.bump_seed;
+        x_vault_bump_seed_dst[0] = self.x_vault_bump_seed;
+        y_vault_bump_seed_dst[0] = self.y_vault_bump_seed;
+
+        // The processor never lets the list grow past `MAX_TICKS`
+        tick_count_dst[0] = self.ticks.len() as u8;
+        ticks_dst.fill(0);
+        for (tick, tick_dst) in self.ticks.iter().zip(ticks_dst.chunks_mut(Tick::LEN)) {
+            tick.pack_into_slice(tick_dst);
+        }
+    }
+}
+
+/// Initialized tick of a `ConcentratedPool`, a bound of at least one position.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Tick {
+    pub index: i32,
+    /// Liquidity added to the pool when the price crosses the tick upwards
+    pub liquidity_net: i128,
+    /// Liquidity of all positions bounded by the tick
+    pub liquidity_gross: u128,
+    /// Fee growth on the other side of the tick from the current one, Q64.64
+    pub fee_growth_outside_x: u128,
+    pub fee_growth_outside_y: u128,
+}
+
+impl Sealed for Tick {}
 
-        is_initialized_dst[0] = *is_initialized as u8;
+impl Pack for Tick {
+    const LEN: usize = 68;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, Tick::LEN];
+        let (index, liquidity_net, liquidity_gross, fee_growth_outside_x, fee_growth_outside_y) =
+            array_refs![src, 4, 16, 16, 16, 16];
+
+        Ok(Tick {
+            index: i32::from_le_bytes(*index),
+            liquidity_net: i128::from_le_bytes(*liquidity_net),
+            liquidity_gross: u128::from_le_bytes(*liquidity_gross),
+            fee_growth_outside_x: u128::from_le_bytes(*fee_growth_outside_x),
+            fee_growth_outside_y: u128::from_le_bytes(*fee_growth_outside_y),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Tick::LEN];
+        let (
+            index_dst,
+            liquidity_net_dst,
+            liquidity_gross_dst,
+            fee_growth_outside_x_dst,
+            fee_growth_outside_y_dst,
+        ) = mut_array_refs![dst, 4, 16, 16, 16, 16];
+
+        index_dst.copy_from_slice(&self.index.to_le_bytes());
+        liquidity_net_dst.copy_from_slice(&self.liquidity_net.to_le_bytes());
+        liquidity_gross_dst.copy_from_slice(&self.liquidity_gross.to_le_bytes());
+        fee_growth_outside_x_dst.copy_from_slice(&self.fee_growth_outside_x.to_le_bytes());
+        fee_growth_outside_y_dst.copy_from_slice(&self.fee_growth_outside_y.to_le_bytes());
+    }
+}
+
+/// Liquidity of one owner in a `ConcentratedPool` between two ticks, packed
+/// in little-endian like its pool. Every position instruction reads and
+/// writes it whole.
+#[derive(Debug)]
+pub struct Position {
+    pub is_initialized: bool,
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+
+    /// Fee growth inside the range as of the last update, Q64.64
+    pub fee_growth_inside_x_last: u128,
+    pub fee_growth_inside_y_last: u128,
+
+    /// Fees earned and not collected yet
+    pub tokens_owed_x: u64,
+    pub tokens_owed_y: u64,
+}
+
+impl Sealed for Position {}
+
+impl IsInitialized for Position {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Position {
+    const LEN: usize = 137;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, Position::LEN];
+        let (
+            is_initialized,
+            pool,
+            owner,
+            tick_lower,
+            tick_upper,
+            liquidity,
+            fee_growth_inside_x_last,
+            fee_growth_inside_y_last,
+            tokens_owed_x,
+            tokens_owed_y,
+        ) = array_refs![src, 1, 32, 32, 4, 4, 16, 16, 16, 8, 8];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        Ok(Position {
+            is_initialized,
+            pool: Pubkey::new(pool),
+            owner: Pubkey::new(owner),
+            tick_lower: i32::from_le_bytes(*tick_lower),
+            tick_upper: i32::from_le_bytes(*tick_upper),
+            liquidity: u128::from_le_bytes(*liquidity),
+            fee_growth_inside_x_last: u128::from_le_bytes(*fee_growth_inside_x_last),
+            fee_growth_inside_y_last: u128::from_le_bytes(*fee_growth_inside_y_last),
+            tokens_owed_x: u64::from_le_bytes(*tokens_owed_x),
+            tokens_owed_y: u64::from_le_bytes(*tokens_owed_y),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Position::LEN];
+        let (
+            is_initialized_dst,
+            pool_dst,
+            owner_dst,
+            tick_lower_dst,
+            tick_upper_dst,
+            liquidity_dst,
+            fee_growth_inside_x_last_dst,
+            fee_growth_inside_y_last_dst,
+            tokens_owed_x_dst,
+            tokens_owed_y_dst,
+        ) = mut_array_refs![dst, 1, 32, 32, 4, 4, 16, 16, 16, 8, 8];
 
-        x_mint_dst.copy_from_slice(&x_mint.to_bytes());
-        x_amount_dst.copy_from_slice(&x_amount.to_be_bytes());
-        y_mint_dst.copy_from_slice(&y_mint.to_bytes());
-        y_amount_dst.copy_from_slice(&y_amount.to_be_bytes());
+        is_initialized_dst[0] = self.is_initialized as u8;
+        pool_dst.copy_from_slice(&self.pool.to_bytes());
+        owner_dst.copy_from_slice(&self.owner.to_bytes());
+        tick_lower_dst.copy_from_slice(&self.tick_lower.to_le_bytes());
+        tick_upper_dst.copy_from_slice(&self.tick_upper.to_le_bytes());
+        liquidity_dst.copy_from_slice(&self.liquidity.to_le_bytes());
+        fee_growth_inside_x_last_dst.copy_from_slice(&self.fee_growth_inside_x_last.to_le_bytes());
+        fee_growth_inside_y_last_dst.copy_from_slice(&self.fee_growth_inside_y_last.to_le_bytes());
+        tokens_owed_x_dst.copy_from_slice(&self.tokens_owed_x.to_le_bytes());
+        tokens_owed_y_dst.copy_from_slice(&self.tokens_owed_y.to_le_bytes());
     }
 }
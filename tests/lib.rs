@@ -1,12 +1,28 @@
-use solana_program::{native_token::LAMPORTS_PER_SOL, system_instruction, system_program};
+use solana_amm::{
+    curve::{CurveParameters, CurveType},
+    error::AMMErrors,
+};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable,
+    entrypoint::ProgramResult,
+    native_token::LAMPORTS_PER_SOL,
+    program::invoke,
+    program_option::COption,
+    system_instruction, system_program,
+};
 use solana_program_test::*;
 use solana_sdk::{
     account::{Account, ReadableAccount},
     hash::Hash,
+    instruction::{AccountMeta, InstructionError},
+    process_instruction::BpfComputeBudget,
+    program_error::ProgramError,
     program_pack::Pack,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
-    transaction::Transaction,
+    transaction::{Transaction, TransactionError},
+    transport::TransportError,
 };
 
 struct TestUser {
@@ -33,30 +49,6 @@ impl TestUser {
             main_y,
         }
     }
-
-    async fn temp_acc(
-        &self,
-        banks_client: &mut BanksClient,
-        recent_blockhash: Hash,
-        mint: &Pubkey,
-        is_x: bool,
-        amount: u64,
-    ) -> Pubkey {
-        let temp_acc =
-            create_token_account(&self.keypair, mint, banks_client, recent_blockhash).await;
-
-        transfer_tokens(
-            &self.keypair,
-            banks_client,
-            recent_blockhash,
-            if is_x { &self.main_x } else { &self.main_y },
-            &temp_acc,
-            amount,
-        )
-        .await;
-
-        temp_acc
-    }
 }
 
 const X_DECIMALS: u32 = 8;
@@ -139,21 +131,28 @@ async fn test_amm() {
     )
     .await;
 
+    let owner_lp_keypair = Keypair::new();
     let init_instruction = solana_amm::instruction::initialize_amm(
         &payer.pubkey(),
         &temp_x_address,
         &x_mint,
         &temp_y_address,
         &y_mint,
+        &owner_lp_keypair.pubkey(),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
         &program_id,
         &spl_token::id(),
     );
 
     let mut transaction = Transaction::new_with_payer(&[init_instruction], Some(&payer.pubkey()));
-    transaction.sign(&[&payer], recent_blockhash);
+    transaction.sign(&[&payer, &owner_lp_keypair], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
 
-    let (amm_data_account, _) = Pubkey::find_program_address(&[b"data"], &program_id);
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
     let amm_data = solana_amm::state::AMM::unpack(
         banks_client
             .get_account(amm_data_account)
@@ -168,8 +167,14 @@ async fn test_amm() {
     assert_eq!(amm_data.y_amount, 10_000 * ONE_Y);
     assert_eq!(amm_data.x_mint, x_mint);
     assert_eq!(amm_data.y_mint, y_mint);
+    assert_eq!(amm_data.curve_type, CurveType::ConstantProduct as u8);
+    assert_eq!(
+        balance_of(owner_lp_keypair.pubkey(), &mut banks_client).await,
+        solana_amm::state::INITIAL_LP_SUPPLY
+    );
 
-    let (x_vault_address, _) = Pubkey::find_program_address(&[b"x_vault"], &program_id);
+    let (x_vault_address, _) =
+        solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account);
 
     let x_vault_data = spl_token::state::Account::unpack(
         banks_client
@@ -183,7 +188,8 @@ async fn test_amm() {
 
     assert_eq!(x_vault_data.amount, 100 * ONE_X);
 
-    let (y_vault_address, _) = Pubkey::find_program_address(&[b"y_vault"], &program_id);
+    let (y_vault_address, _) =
+        solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account);
 
     let y_vault_data = spl_token::state::Account::unpack(
         banks_client
@@ -259,20 +265,15 @@ async fn test_amm() {
         println!("Alice Y balance: {}", alice_y_balance as f64 / ONE_Y as f64);
         println!("===================================================");
 
-        let alice_temp_y = alice
-            .temp_acc(
-                &mut banks_client,
-                recent_blockhash,
-                &y_mint,
-                false,
-                100 * ONE_Y,
-            )
-            .await;
-
         let exchange_instruction = solana_amm::instruction::exchange(
             &alice.keypair.pubkey(),
-            &alice_temp_y,
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
             &alice.main_x,
+            100 * ONE_Y,
+            0,
+            None,
             &spl_token::id(),
             &program_id,
         );
@@ -306,20 +307,15 @@ async fn test_amm() {
         println!("bob Y balance: {}", bob_y_balance as f64 / ONE_Y as f64);
         println!("===================================================");
 
-        let bob_temp_y = bob
-            .temp_acc(
-                &mut banks_client,
-                recent_blockhash,
-                &y_mint,
-                false,
-                100 * ONE_Y,
-            )
-            .await;
-
         let exchange_instruction = solana_amm::instruction::exchange(
             &bob.keypair.pubkey(),
-            &bob_temp_y,
+            &amm_data_account,
+            &Pubkey::default(),
+            &bob.main_y,
             &bob.main_x,
+            100 * ONE_Y,
+            0,
+            None,
             &spl_token::id(),
             &program_id,
         );
@@ -348,20 +344,15 @@ async fn test_amm() {
     {
         let alice_x_balance = balance_of(alice.main_x, &mut banks_client).await;
 
-        let alice_temp_x = alice
-            .temp_acc(
-                &mut banks_client,
-                recent_blockhash,
-                &x_mint,
-                true,
-                alice_x_balance,
-            )
-            .await;
-
         let exchange_instruction = solana_amm::instruction::exchange(
             &alice.keypair.pubkey(),
-            &alice_temp_x,
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
             &alice.main_y,
+            alice_x_balance,
+            0,
+            None,
             &spl_token::id(),
             &program_id,
         );
@@ -390,20 +381,15 @@ async fn test_amm() {
     {
         let bob_x_balance = balance_of(bob.main_x, &mut banks_client).await;
 
-        let bob_temp_x = bob
-            .temp_acc(
-                &mut banks_client,
-                recent_blockhash,
-                &x_mint,
-                true,
-                bob_x_balance,
-            )
-            .await;
-
         let exchange_instruction = solana_amm::instruction::exchange(
             &bob.keypair.pubkey(),
-            &bob_temp_x,
+            &amm_data_account,
+            &Pubkey::default(),
+            &bob.main_x,
             &bob.main_y,
+            bob_x_balance,
+            0,
+            None,
             &spl_token::id(),
             &program_id,
         );
@@ -435,9 +421,11 @@ async fn test_amm() {
     println!("Alice Y balance: {}", alice_y_balance as f64 / ONE_Y as f64);
     println!("Bob Y balance: {}", bob_y_balance as f64 / ONE_Y as f64);
     println!("===================================================");
-    assert_eq!(alice_y_balance + bob_y_balance, 200 * ONE_Y);
+    // Rounding favours the pool, so the round trip leaves it the dust
+    assert_eq!(alice_y_balance + bob_y_balance, 200 * ONE_Y - 1);
 
-    let (amm_data_account, _) = Pubkey::find_program_address(&[b"data"], &program_id);
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
     let amm_data = solana_amm::state::AMM::unpack(
         banks_client
             .get_account(amm_data_account)
@@ -449,158 +437,5483 @@ async fn test_amm() {
     .unwrap();
 
     assert_eq!(amm_data.x_amount, 100 * ONE_X);
-    assert_eq!(amm_data.y_amount, 10_000 * ONE_Y);
+    assert_eq!(amm_data.y_amount, 10_000 * ONE_Y + 1);
     assert_eq!(amm_data.x_mint, x_mint);
     assert_eq!(amm_data.y_mint, y_mint);
 }
 
-async fn mint_token(
-    payer: &Keypair,
-    banks_client: &mut BanksClient,
-    recent_blockhash: Hash,
-    decimals: u8,
-) -> (Pubkey, Pubkey) {
-    let mint_keypair = Keypair::new();
-
-    let rent = banks_client.get_rent().await.unwrap();
-    let minimum_balance = rent.minimum_balance(spl_token::state::Mint::LEN);
+#[tokio::test]
+async fn test_multiple_pools() {
+    let program_id = Pubkey::new_unique();
 
-    let create_mint_account_instruction = system_instruction::create_account(
-        &payer.pubkey(),
-        &mint_keypair.pubkey(),
-        minimum_balance,
-        spl_token::state::Mint::LEN as u64,
-        &spl_token::ID,
+    let program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
     );
 
-    let init_mint_instruction = spl_token::instruction::initialize_mint(
-        &spl_token::ID,
-        &mint_keypair.pubkey(),
-        &payer.pubkey(),
-        None,
-        decimals,
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
     )
-    .unwrap();
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
 
-    let token_account_keypair = Keypair::new();
-    let minimum_balance = rent.minimum_balance(spl_token::state::Account::LEN);
+    // Two pools for the same pair with different indexes
+    for (pool_index, x_amount, y_amount) in [(0, 100 * ONE_X, 10_000 * ONE_Y), (1, ONE_X, ONE_Y)] {
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (&x_mint, &x_acc, x_amount),
+            (&y_mint, &y_acc, y_amount),
+            pool_index,
+            (0, 10_000),
+            (0, 1),
+            CurveParameters::ConstantProduct,
+        )
+        .await
+        .unwrap();
+    }
 
-    let create_token_account_instruction = system_instruction::create_account(
-        &payer.pubkey(),
-        &token_account_keypair.pubkey(),
-        minimum_balance,
-        spl_token::state::Account::LEN as u64,
-        &spl_token::ID,
-    );
+    let (first_pool, _) = solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let (second_pool, _) = solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 1);
+    assert_ne!(first_pool, second_pool);
 
-    let init_acccount_instruction = spl_token::instruction::initialize_account(
-        &spl_token::ID,
-        &token_account_keypair.pubkey(),
-        &mint_keypair.pubkey(),
-        &payer.pubkey(),
-    )
-    .unwrap();
+    let first_pool_data = amm_data_of(first_pool, &mut banks_client).await;
+    assert_eq!(first_pool_data.pool_index, 0);
+    assert_eq!(first_pool_data.x_amount, 100 * ONE_X);
+    assert_eq!(first_pool_data.y_amount, 10_000 * ONE_Y);
 
-    let mint_instruction = spl_token::instruction::mint_to(
-        &spl_token::ID,
-        &mint_keypair.pubkey(),
-        &token_account_keypair.pubkey(),
-        &payer.pubkey(),
-        &[&payer.pubkey()],
-        10_000_000 * 10u64.pow(decimals as u32),
-    )
-    .unwrap();
+    let second_pool_data = amm_data_of(second_pool, &mut banks_client).await;
+    assert_eq!(second_pool_data.pool_index, 1);
+    assert_eq!(second_pool_data.x_amount, ONE_X);
+    assert_eq!(second_pool_data.y_amount, ONE_Y);
 
-    let mut transaction = Transaction::new_with_payer(
-        &[
-            create_mint_account_instruction,
-            init_mint_instruction,
-            create_token_account_instruction,
-            init_acccount_instruction,
-            mint_instruction,
-        ],
-        Some(&payer.pubkey()),
+    let (second_x_vault, _) = solana_amm::pda::find_x_vault_address(&program_id, &second_pool);
+    assert_eq!(balance_of(second_x_vault, &mut banks_client).await, ONE_X);
+
+    // The reversed pair resolves to the already existing pool
+    assert_eq!(
+        solana_amm::pda::find_pool_address(&program_id, &y_mint, &x_mint, 0).0,
+        first_pool
     );
-    transaction.sign(
-        &[&payer, &mint_keypair, &token_account_keypair],
+    assert!(initialize_pool(
+        &payer,
+        &mut banks_client,
         recent_blockhash,
-    );
-    banks_client.process_transaction(transaction).await.unwrap();
-
-    (mint_keypair.pubkey(), token_account_keypair.pubkey())
+        &program_id,
+        (&y_mint, &y_acc, ONE_Y),
+        (&x_mint, &x_acc, ONE_X),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .is_err());
 }
 
-async fn create_token_account(
-    payer: &Keypair,
-    mint: &Pubkey,
-    banks_client: &mut BanksClient,
-    recent_blockhash: Hash,
-) -> Pubkey {
-    let rent = banks_client.get_rent().await.unwrap();
+#[tokio::test]
+async fn test_liquidity() {
+    let program_id = Pubkey::new_unique();
 
-    let token_account_keypair = Keypair::new();
-    let minimum_balance = rent.minimum_balance(spl_token::state::Account::LEN);
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
 
-    let create_token_account_instruction = system_instruction::create_account(
-        &payer.pubkey(),
-        &token_account_keypair.pubkey(),
-        minimum_balance,
-        spl_token::state::Account::LEN as u64,
-        &spl_token::ID,
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
     );
 
-    let init_acccount_instruction = spl_token::instruction::initialize_account(
-        &spl_token::ID,
-        &token_account_keypair.pubkey(),
-        &mint,
-        &payer.pubkey(),
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
     )
-    .unwrap();
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
 
-    let mut transaction = Transaction::new_with_payer(
-        &[create_token_account_instruction, init_acccount_instruction],
-        Some(&payer.pubkey()),
+    // A pool without reserves would mint LP tokens for nothing
+    assert_eq!(
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (&x_mint, &x_acc, 100 * ONE_X),
+            (&y_mint, &y_acc, 0),
+            0,
+            (0, 10_000),
+            (0, 1),
+            CurveParameters::ConstantProduct,
+        )
+        .await
+        .unwrap_err()
+        .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(AMMErrors::ZeroDeposit as u32)
+        )
     );
 
-    transaction.sign(&[payer, &token_account_keypair], recent_blockhash);
-    banks_client.process_transaction(transaction).await.unwrap();
-
-    token_account_keypair.pubkey()
-}
-
-async fn transfer_tokens(
-    payer: &Keypair,
-    banks_client: &mut BanksClient,
-    recent_blockhash: Hash,
-    sender: &Pubkey,
-    recipient: &Pubkey,
-    amount: u64,
-) {
-    let transfer_instruction = spl_token::instruction::transfer(
-        &spl_token::ID,
-        &sender,
-        &recipient,
-        &payer.pubkey(),
-        &[&payer.pubkey()],
-        amount as u64,
+    let owner_lp = initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
     )
+    .await
     .unwrap();
 
-    let mut transaction =
-        Transaction::new_with_payer(&[transfer_instruction], Some(&payer.pubkey()));
-    transaction.sign(&[payer], recent_blockhash);
-    banks_client.process_transaction(transaction).await.unwrap();
-}
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let (lp_mint, _) = solana_amm::pda::find_lp_mint_address(&program_id, &amm_data_account);
 
-async fn balance_of(address: Pubkey, banks_client: &mut BanksClient) -> u64 {
-    spl_token::state::Account::unpack(
-        banks_client
-            .get_account(address)
-            .await
-            .unwrap()
-            .unwrap()
-            .data(),
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
     )
-    .unwrap()
-    .amount as u64
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &x_acc,
+        &alice.main_x,
+        10 * ONE_X,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        1_000 * ONE_Y,
+    )
+    .await;
+    let alice_lp = create_token_account(
+        &alice.keypair,
+        &lp_mint,
+        &mut banks_client,
+        recent_blockhash,
+    )
+    .await;
+
+    let lp_amount = solana_amm::state::INITIAL_LP_SUPPLY / 10;
+
+    // Alice asks for 10% of the pool but caps the Y deposit too low
+    {
+        let add_liquidity_instruction = solana_amm::instruction::add_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            10 * ONE_X,
+            999 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[add_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::ExceededSlippage as u32)
+            )
+        );
+    }
+
+    // A fake token program would mint LP tokens for nothing
+    {
+        let add_liquidity_instruction = solana_amm::instruction::add_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            10 * ONE_X,
+            1_000 * ONE_Y,
+            &Pubkey::new_unique(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[add_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidProgramAccount,
+        )
+        .await;
+        assert_eq!(balance_of(alice_lp, &mut banks_client).await, 0);
+    }
+
+    // Alice deposits 10 X and 1000 Y for 10% of the pool
+    {
+        let add_liquidity_instruction = solana_amm::instruction::add_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            10 * ONE_X,
+            1_000 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[add_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 0);
+        assert_eq!(balance_of(alice.main_y, &mut banks_client).await, 0);
+        assert_eq!(balance_of(alice_lp, &mut banks_client).await, lp_amount);
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.x_amount, 110 * ONE_X);
+        assert_eq!(amm_data.y_amount, 11_000 * ONE_Y);
+    }
+
+    // Alice withdraws with a minimum above her share
+    {
+        let remove_liquidity_instruction = solana_amm::instruction::remove_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            10 * ONE_X + 1,
+            1_000 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[remove_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::ExceededSlippage as u32)
+            )
+        );
+    }
+
+    // A fake token program would drain the vaults without burning
+    {
+        let remove_liquidity_instruction = solana_amm::instruction::remove_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            0,
+            0,
+            &Pubkey::new_unique(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[remove_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidProgramAccount,
+        )
+        .await;
+        assert_eq!(balance_of(alice_lp, &mut banks_client).await, lp_amount);
+    }
+
+    // Alice burns all her LP tokens and gets her deposit back
+    {
+        let remove_liquidity_instruction = solana_amm::instruction::remove_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            10 * ONE_X,
+            1_000 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[remove_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_x, &mut banks_client).await,
+            10 * ONE_X
+        );
+        assert_eq!(
+            balance_of(alice.main_y, &mut banks_client).await,
+            1_000 * ONE_Y
+        );
+        assert_eq!(balance_of(alice_lp, &mut banks_client).await, 0);
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.x_amount, 100 * ONE_X);
+        assert_eq!(amm_data.y_amount, 10_000 * ONE_Y);
+    }
+
+    // The owner drains the pool
+    {
+        let remove_liquidity_instruction = solana_amm::instruction::remove_liquidity(
+            &payer.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &x_acc,
+            &y_acc,
+            &owner_lp,
+            solana_amm::state::INITIAL_LP_SUPPLY,
+            100 * ONE_X,
+            10_000 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[remove_liquidity_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!((amm_data.x_amount, amm_data.y_amount), (0, 0));
+    }
+
+    // Alice funds it again at her own ratio, but not with nothing
+    for (maximum_y_amount, result) in [(0, Err(AMMErrors::ZeroDeposit)), (500 * ONE_Y, Ok(()))] {
+        let add_liquidity_instruction = solana_amm::instruction::add_liquidity(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            &alice.main_y,
+            &alice_lp,
+            lp_amount,
+            10 * ONE_X,
+            maximum_y_amount,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[add_liquidity_instruction],
+            Some(&alice.keypair.pubkey()),
+        );
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        match result {
+            Err(error) => assert_amm_error(&mut banks_client, transaction, error).await,
+            Ok(()) => banks_client.process_transaction(transaction).await.unwrap(),
+        }
+    }
+
+    assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 0);
+    assert_eq!(
+        balance_of(alice.main_y, &mut banks_client).await,
+        500 * ONE_Y
+    );
+    assert_eq!(balance_of(alice_lp, &mut banks_client).await, lp_amount);
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.x_amount, 10 * ONE_X);
+    assert_eq!(amm_data.y_amount, 500 * ONE_Y);
+}
+
+#[tokio::test]
+async fn test_exchange_slippage() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        100 * ONE_Y,
+    )
+    .await;
+
+    // 100 Y buys 100 X * 100 Y / 10_100 Y = 0.990099009.. X, rounded down
+    let expected_x = 99_009_900;
+
+    // Minimum output one unit above the quote
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            100 * ONE_Y,
+            expected_x + 1,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::InsufficientOutputAmount as u32)
+            )
+        );
+        assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 0);
+    }
+
+    // Minimum output equal to the quote
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            100 * ONE_Y,
+            expected_x,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+        assert_eq!(
+            balance_of(alice.main_x, &mut banks_client).await,
+            expected_x
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_exchange_expiry() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        100 * ONE_Y,
+    )
+    .await;
+
+    // The pool was initialized at the current clock time
+    let now = amm_data_of(amm_data_account, &mut banks_client)
+        .await
+        .last_update_ts;
+
+    // Deadline a second in the past
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            100 * ONE_Y,
+            0,
+            Some(now - 1),
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::Expired as u32)
+            )
+        );
+        assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 0);
+    }
+
+    // Deadline an hour ahead
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            100 * ONE_Y,
+            0,
+            Some(now + 3_600),
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+        assert_eq!(balance_of(alice.main_y, &mut banks_client).await, 0);
+    }
+}
+
+#[tokio::test]
+async fn test_exchange_exact_out() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        110 * ONE_Y,
+    )
+    .await;
+
+    // 1 X costs 10_000 Y * 100 X / 99 X - 10_000 Y = 101.0101.. Y, rounded up
+    let expected_y = 1_010_102;
+
+    // Maximum input one unit below the quote
+    {
+        let exchange_instruction = solana_amm::instruction::exchange_exact_out(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            ONE_X,
+            expected_y - 1,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::ExcessiveInputAmount as u32)
+            )
+        );
+    }
+
+    // Alice receives exactly 1 X and keeps the rest of her Y
+    {
+        let exchange_instruction = solana_amm::instruction::exchange_exact_out(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            ONE_X,
+            110 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(balance_of(alice.main_x, &mut banks_client).await, ONE_X);
+        assert_eq!(
+            balance_of(alice.main_y, &mut banks_client).await,
+            110 * ONE_Y - expected_y
+        );
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.x_amount, 99 * ONE_X);
+        assert_eq!(amm_data.y_amount, 10_000 * ONE_Y + expected_y);
+    }
+}
+
+#[tokio::test]
+async fn test_fees() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    // A fee of 100% is rejected
+    assert_eq!(
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (&x_mint, &x_acc, 100 * ONE_X),
+            (&y_mint, &y_acc, 10_000 * ONE_Y),
+            0,
+            (10_000, 10_000),
+            (0, 1),
+            CurveParameters::ConstantProduct,
+        )
+        .await
+        .unwrap_err()
+        .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(AMMErrors::InvalidFee as u32)
+        )
+    );
+
+    // 0.3% fee
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (30, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.fee_numerator, 30);
+    assert_eq!(amm_data.fee_denominator, 10_000);
+    let initial_k = amm_data.x_amount as u128 * amm_data.y_amount as u128;
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        100 * ONE_Y,
+    )
+    .await;
+
+    // Only 99.7 Y are priced: 100 X * 99.7 Y / 10_099.7 Y = 0.98715803 X
+    let expected_x = 98_715_803;
+
+    let exchange_instruction = solana_amm::instruction::exchange(
+        &alice.keypair.pubkey(),
+        &amm_data_account,
+        &Pubkey::default(),
+        &alice.main_y,
+        &alice.main_x,
+        100 * ONE_Y,
+        expected_x,
+        None,
+        &spl_token::id(),
+        &program_id,
+    );
+
+    let mut transaction =
+        Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+    transaction.sign(&[&alice.keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(
+        balance_of(alice.main_x, &mut banks_client).await,
+        expected_x
+    );
+
+    // The whole input, fee included, stays in the reserves
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.x_amount, 100 * ONE_X - expected_x);
+    assert_eq!(amm_data.y_amount, 10_100 * ONE_Y);
+    assert!(amm_data.x_amount as u128 * amm_data.y_amount as u128 > initial_k);
+}
+
+#[tokio::test]
+async fn test_protocol_fees() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    // 0.3% fee, a sixth of it goes to the protocol
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (30, 10_000),
+        (1, 6),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let (y_vault_address, _) =
+        solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account);
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.authority, payer.pubkey());
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        100 * ONE_Y,
+    )
+    .await;
+
+    let exchange_instruction = solana_amm::instruction::exchange(
+        &alice.keypair.pubkey(),
+        &amm_data_account,
+        &Pubkey::default(),
+        &alice.main_y,
+        &alice.main_x,
+        100 * ONE_Y,
+        0,
+        None,
+        &spl_token::id(),
+        &program_id,
+    );
+
+    let mut transaction =
+        Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+    transaction.sign(&[&alice.keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The protocol share does not change the price for the trader
+    assert_eq!(
+        balance_of(alice.main_x, &mut banks_client).await,
+        98_715_803
+    );
+
+    // 0.3 Y of fees, 0.05 Y of them accrued to the protocol
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.y_amount, 10_100 * ONE_Y - 500);
+    assert_eq!(amm_data.protocol_fees_y, 500);
+    assert_eq!(amm_data.protocol_fees_x, 0);
+    assert_eq!(
+        balance_of(y_vault_address, &mut banks_client).await,
+        10_100 * ONE_Y
+    );
+
+    let x_treasury =
+        create_token_account(&payer, &x_mint, &mut banks_client, recent_blockhash).await;
+    let y_treasury =
+        create_token_account(&payer, &y_mint, &mut banks_client, recent_blockhash).await;
+
+    // Only the pool authority can collect
+    {
+        let collect_instruction = solana_amm::instruction::collect_protocol_fees(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &x_treasury,
+            &y_treasury,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[collect_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::Unauthorized as u32)
+            )
+        );
+    }
+
+    // The vaults only sign for the real token program
+    {
+        let collect_instruction = solana_amm::instruction::collect_protocol_fees(
+            &payer.pubkey(),
+            &amm_data_account,
+            &x_treasury,
+            &y_treasury,
+            &Pubkey::new_unique(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[collect_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidProgramAccount,
+        )
+        .await;
+    }
+
+    {
+        let collect_instruction = solana_amm::instruction::collect_protocol_fees(
+            &payer.pubkey(),
+            &amm_data_account,
+            &x_treasury,
+            &y_treasury,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[collect_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    assert_eq!(balance_of(x_treasury, &mut banks_client).await, 0);
+    assert_eq!(balance_of(y_treasury, &mut banks_client).await, 500);
+    assert_eq!(
+        balance_of(y_vault_address, &mut banks_client).await,
+        10_100 * ONE_Y - 500
+    );
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.protocol_fees_y, 0);
+    assert_eq!(amm_data.y_amount, 10_100 * ONE_Y - 500);
+}
+
+#[tokio::test]
+async fn test_authority() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+    let bob_keypair = Keypair::new();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (30, 10_000),
+        (1, 6),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    // Only the pool authority can change the fees
+    {
+        let set_fees_instruction = solana_amm::instruction::set_fees(
+            &alice_keypair.pubkey(),
+            &amm_data_account,
+            (0, 10_000),
+            (0, 1),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[set_fees_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::Unauthorized as u32)
+            )
+        );
+    }
+
+    // Owner proposes Alice as the new authority
+    {
+        let set_authority_instruction = solana_amm::instruction::set_authority(
+            &payer.pubkey(),
+            &amm_data_account,
+            &alice_keypair.pubkey(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[set_authority_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.authority, payer.pubkey());
+        assert_eq!(amm_data.pending_authority, alice_keypair.pubkey());
+    }
+
+    // Nobody but Alice can accept
+    {
+        let accept_instruction = solana_amm::instruction::accept_authority(
+            &bob_keypair.pubkey(),
+            &amm_data_account,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[accept_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &bob_keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::Unauthorized as u32)
+            )
+        );
+    }
+
+    {
+        let accept_instruction = solana_amm::instruction::accept_authority(
+            &alice_keypair.pubkey(),
+            &amm_data_account,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[accept_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.authority, alice_keypair.pubkey());
+        assert_eq!(amm_data.pending_authority, Pubkey::default());
+    }
+
+    // The previous owner lost its rights
+    {
+        let set_fees_instruction = solana_amm::instruction::set_fees(
+            &payer.pubkey(),
+            &amm_data_account,
+            (0, 10_000),
+            (0, 1),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[set_fees_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::Unauthorized as u32)
+            )
+        );
+    }
+
+    // New fees are validated like at `Initialize`
+    {
+        let set_fees_instruction = solana_amm::instruction::set_fees(
+            &alice_keypair.pubkey(),
+            &amm_data_account,
+            (10_000, 10_000),
+            (0, 1),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[set_fees_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::InvalidFee as u32)
+            )
+        );
+    }
+
+    {
+        let set_fees_instruction = solana_amm::instruction::set_fees(
+            &alice_keypair.pubkey(),
+            &amm_data_account,
+            (5, 10_000),
+            (1, 2),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[set_fees_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.fee_numerator, 5);
+        assert_eq!(amm_data.fee_denominator, 10_000);
+        assert_eq!(amm_data.protocol_fee_numerator, 1);
+        assert_eq!(amm_data.protocol_fee_denominator, 2);
+    }
+}
+
+#[tokio::test]
+async fn test_pause() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    let owner_lp = initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        100 * ONE_Y,
+    )
+    .await;
+
+    // Only the pool authority can pause
+    {
+        let pause_instruction =
+            solana_amm::instruction::pause(&alice.keypair.pubkey(), &amm_data_account, &program_id);
+
+        let mut transaction =
+            Transaction::new_with_payer(&[pause_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::Unauthorized as u32)
+            )
+        );
+    }
+
+    {
+        let pause_instruction =
+            solana_amm::instruction::pause(&payer.pubkey(), &amm_data_account, &program_id);
+
+        let mut transaction =
+            Transaction::new_with_payer(&[pause_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            amm_data_of(amm_data_account, &mut banks_client)
+                .await
+                .paused,
+            1
+        );
+    }
+
+    // Exchanges are halted
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            100 * ONE_Y,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::PoolPaused as u32)
+            )
+        );
+    }
+
+    // Deposits are halted
+    {
+        let add_liquidity_instruction = solana_amm::instruction::add_liquidity(
+            &payer.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &x_acc,
+            &y_acc,
+            &owner_lp,
+            solana_amm::state::INITIAL_LP_SUPPLY / 10,
+            10 * ONE_X,
+            1_000 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[add_liquidity_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(AMMErrors::PoolPaused as u32)
+            )
+        );
+    }
+
+    // Liquidity providers can still withdraw
+    {
+        let remove_liquidity_instruction = solana_amm::instruction::remove_liquidity(
+            &payer.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &x_acc,
+            &y_acc,
+            &owner_lp,
+            solana_amm::state::INITIAL_LP_SUPPLY / 10,
+            10 * ONE_X,
+            1_000 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[remove_liquidity_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+        assert_eq!(amm_data.x_amount, 90 * ONE_X);
+        assert_eq!(amm_data.y_amount, 9_000 * ONE_Y);
+    }
+
+    {
+        let unpause_instruction =
+            solana_amm::instruction::unpause(&payer.pubkey(), &amm_data_account, &program_id);
+
+        let mut transaction =
+            Transaction::new_with_payer(&[unpause_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            amm_data_of(amm_data_account, &mut banks_client)
+                .await
+                .paused,
+            0
+        );
+    }
+
+    // Trading resumes, 100 Y buys 90 X * 100 Y / 9_100 Y = 0.989010989.. X, rounded down
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            100 * ONE_Y,
+            1,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_x, &mut banks_client).await,
+            98_901_098
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_stable_swap() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    assert_eq!(
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (&x_mint, &x_acc, 100 * ONE_X),
+            (&y_mint, &y_acc, 100 * ONE_Y),
+            0,
+            (0, 10_000),
+            (0, 1),
+            CurveParameters::StableSwap { amp: 0 },
+        )
+        .await
+        .unwrap_err()
+        .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(AMMErrors::InvalidAmplification as u32)
+        )
+    );
+
+    // X and Y are pegged 1:1 despite their different decimals
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 100 * ONE_Y),
+        1,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::StableSwap { amp: 100 },
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 1);
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.curve_type, CurveType::StableSwap as u8);
+    assert_eq!(amm_data.initial_amp, 100);
+    assert_eq!(amm_data.target_amp, 100);
+    assert_eq!(amm_data.x_decimals, X_DECIMALS as u8);
+    assert_eq!(amm_data.y_decimals, Y_DECIMALS as u8);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        20 * ONE_Y,
+    )
+    .await;
+
+    // 10 Y buy 9.99001108.. X, less the rounding in favour of the pool.
+    // A constant product pool would give 9.09 X.
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            10 * ONE_Y,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_x, &mut banks_client).await,
+            999_001_106
+        );
+    }
+
+    // Buying 1 X back from the moved pool costs 1.00212630.. Y, rounded up to Y precision
+    {
+        let exchange_instruction = solana_amm::instruction::exchange_exact_out(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            ONE_X,
+            2 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_y, &mut banks_client).await,
+            10 * ONE_Y - 10_022
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_weighted_pool() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    assert_eq!(
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (&x_mint, &x_acc, 400 * ONE_X),
+            (&y_mint, &y_acc, 100 * ONE_Y),
+            0,
+            (0, 10_000),
+            (0, 1),
+            CurveParameters::Weighted {
+                x_weight: 80,
+                y_weight: 30,
+            },
+        )
+        .await
+        .unwrap_err()
+        .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(AMMErrors::InvalidWeights as u32)
+        )
+    );
+
+    // 80% of the value in X: 400 X and 100 Y price 1 X at 1 Y
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 400 * ONE_X),
+        (&y_mint, &y_acc, 100 * ONE_Y),
+        1,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::Weighted {
+            x_weight: 80,
+            y_weight: 20,
+        },
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 1);
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.curve_type, CurveType::Weighted as u8);
+    assert_eq!((amm_data.x_weight, amm_data.y_weight), (80, 20));
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        20 * ONE_Y,
+    )
+    .await;
+
+    // 10 Y buy 400 * (1 - (100 / 110)^(1 / 4)) = 9.41836412.. X, less the
+    // rounding of the power in favour of the pool
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            10 * ONE_Y,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_x, &mut banks_client).await,
+            941_836_373
+        );
+    }
+
+    // Buying 1 X back costs 1.13377270.. Y, rounded up to Y precision
+    {
+        let exchange_instruction = solana_amm::instruction::exchange_exact_out(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            ONE_X,
+            2 * ONE_Y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_y, &mut banks_client).await,
+            10 * ONE_Y - 11_338
+        );
+    }
+
+    // More than half of the 111 Y reserve at once is rejected
+    {
+        transfer_tokens(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &y_acc,
+            &alice.main_y,
+            60 * ONE_Y,
+        )
+        .await;
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice.keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_y,
+            &alice.main_x,
+            60 * ONE_Y,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InsufficientLiquidity,
+        )
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn test_ramp_amplification() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    for (pool_index, curve) in [
+        CurveParameters::ConstantProduct,
+        CurveParameters::StableSwap { amp: 100 },
+    ]
+    .iter()
+    .enumerate()
+    {
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (&x_mint, &x_acc, 100 * ONE_X),
+            (&y_mint, &y_acc, 100 * ONE_Y),
+            pool_index as u16,
+            (0, 10_000),
+            (0, 1),
+            *curve,
+        )
+        .await
+        .unwrap();
+    }
+
+    let (constant_product_pool, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let (stable_pool, _) = solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 1);
+
+    // Only StableSwap pools have an amplification
+    {
+        let ramp_instruction = solana_amm::instruction::ramp_amplification(
+            &payer.pubkey(),
+            &constant_product_pool,
+            200,
+            0,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[ramp_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::InvalidCurve).await;
+    }
+
+    {
+        let ramp_instruction = solana_amm::instruction::ramp_amplification(
+            &alice_keypair.pubkey(),
+            &stable_pool,
+            200,
+            0,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[ramp_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::Unauthorized).await;
+    }
+
+    {
+        let ramp_instruction = solana_amm::instruction::ramp_amplification(
+            &payer.pubkey(),
+            &stable_pool,
+            solana_amm::curve::MAX_AMP + 1,
+            0,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[ramp_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidAmplification,
+        )
+        .await;
+    }
+
+    // A stop time in the past applies the target at once
+    {
+        let ramp_instruction = solana_amm::instruction::ramp_amplification(
+            &payer.pubkey(),
+            &stable_pool,
+            200,
+            0,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[ramp_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let amm_data = amm_data_of(stable_pool, &mut banks_client).await;
+        assert_eq!(amm_data.initial_amp, 100);
+        assert_eq!(amm_data.target_amp, 200);
+        assert_eq!(amm_data.ramp_stop_ts, amm_data.ramp_start_ts);
+        assert_eq!(
+            solana_amm::curve::amplification(&amm_data, amm_data.ramp_start_ts),
+            200
+        );
+    }
+
+    // Ramp down over a day
+    {
+        let ramp_instruction = solana_amm::instruction::ramp_amplification(
+            &payer.pubkey(),
+            &stable_pool,
+            100,
+            i64::MAX,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[ramp_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let mut amm_data = amm_data_of(stable_pool, &mut banks_client).await;
+        assert_eq!(amm_data.initial_amp, 200);
+        assert_eq!(amm_data.target_amp, 100);
+
+        let start = amm_data.ramp_start_ts;
+        amm_data.ramp_stop_ts = start + 86_400;
+        assert_eq!(solana_amm::curve::amplification(&amm_data, start), 200);
+        assert_eq!(
+            solana_amm::curve::amplification(&amm_data, start + 43_200),
+            150
+        );
+        assert_eq!(
+            solana_amm::curve::amplification(&amm_data, start + 86_400),
+            100
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_price_oracle() {
+    use solana_amm::{
+        math,
+        oracle::{self, Observation},
+    };
+
+    let program_id = Pubkey::new_unique();
+
+    let program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let payer = Keypair::from_bytes(&context.payer.to_bytes()).unwrap();
+    let recent_blockhash = context.last_blockhash;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 400 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let initial = amm_data_of(amm_data_account, &mut context.banks_client).await;
+    assert_eq!(initial.price_x_cumulative, 0);
+    assert_eq!(initial.price_y_cumulative, 0);
+    let start = Observation::of(&initial, initial.last_update_ts);
+
+    // 4 Y per X at first, in raw units 400 * 10^4 / (100 * 10^8)
+    let spot_prices = |amm_data: &solana_amm::state::AMM| {
+        let price_x = math::mul_div_wide(
+            amm_data.y_amount.into(),
+            math::Q64,
+            amm_data.x_amount.into(),
+        )
+        .unwrap();
+        (
+            price_x,
+            math::mul_div_wide(math::Q64, math::Q64, price_x).unwrap(),
+        )
+    };
+    let initial_prices = spot_prices(&initial);
+    assert_eq!(initial_prices.0, (4 << 64) / 10_000);
+
+    let mut states = vec![initial];
+    for _ in 0..2 {
+        let slot = context.banks_client.get_root_slot().await.unwrap();
+        context.warp_to_slot(slot + 1_000).unwrap();
+        let recent_blockhash = context.last_blockhash;
+
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &payer.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &x_acc,
+            &y_acc,
+            10 * ONE_X,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+
+        // Each exchange accumulates the prices from before it, and a client
+        // projecting the previous state forward reads the same values
+        let previous = states.last().unwrap();
+        let amm_data = amm_data_of(amm_data_account, &mut context.banks_client).await;
+        let elapsed = amm_data.last_update_ts - previous.last_update_ts;
+        assert!(elapsed > 0);
+
+        let (price_x, price_y) = spot_prices(previous);
+        assert_eq!(
+            amm_data.price_x_cumulative,
+            previous.price_x_cumulative + price_x * elapsed as u128
+        );
+        assert_eq!(
+            amm_data.price_y_cumulative,
+            previous.price_y_cumulative + price_y * elapsed as u128
+        );
+        assert_eq!(
+            Observation::of(previous, amm_data.last_update_ts),
+            Observation::of(&amm_data, amm_data.last_update_ts)
+        );
+
+        states.push(amm_data);
+    }
+
+    // The average over both exchanges weighs each price by how long it held
+    let end = Observation::of(&states[2], states[2].last_update_ts);
+    let elapsed = |i: usize| (states[i + 1].last_update_ts - states[i].last_update_ts) as u128;
+    let (first, second) = (spot_prices(&states[0]), spot_prices(&states[1]));
+    assert_eq!(
+        oracle::twap(&start, &end),
+        Some((
+            (first.0 * elapsed(0) + second.0 * elapsed(1)) / (elapsed(0) + elapsed(1)),
+            (first.1 * elapsed(0) + second.1 * elapsed(1)) / (elapsed(0) + elapsed(1)),
+        ))
+    );
+    assert_eq!(oracle::twap(&end, &start), None);
+    assert_eq!(oracle::twap(&end, &end), None);
+}
+
+#[tokio::test]
+async fn test_price_observations() {
+    use solana_amm::oracle::{self, Observation};
+
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let payer = Keypair::from_bytes(&context.payer.to_bytes()).unwrap();
+    let recent_blockhash = context.last_blockhash;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 400 * ONE_Y),
+        0,
+        (30, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let observations_account = create_observations_account(
+        &payer,
+        &mut context.banks_client,
+        recent_blockhash,
+        &program_id,
+        2,
+    )
+    .await;
+
+    // Only the pool authority can attach an observations account
+    {
+        let initialize_instruction = solana_amm::instruction::initialize_observations(
+            &alice_keypair.pubkey(),
+            &amm_data_account,
+            &observations_account,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[initialize_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        assert_amm_error(
+            &mut context.banks_client,
+            transaction,
+            AMMErrors::Unauthorized,
+        )
+        .await;
+    }
+
+    // The first sample is taken right away
+    {
+        let initialize_instruction = solana_amm::instruction::initialize_observations(
+            &payer.pubkey(),
+            &amm_data_account,
+            &observations_account,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[initialize_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut context.banks_client).await;
+        assert_eq!(amm_data.observations, observations_account);
+
+        let observations = observations_of(observations_account, &mut context.banks_client).await;
+        assert_eq!(observations.pool, amm_data_account);
+        assert_eq!(observations.capacity, 2);
+        assert_eq!(observations.samples, vec![oracle::sample(&amm_data)]);
+        // sqrt(100 * 10^8 * 400 * 10^4)
+        assert_eq!(observations.samples[0].liquidity, 200_000_000);
+    }
+
+    // Every exchange records a sample, the oldest ones are overwritten once
+    // the buffer is full
+    let mut pool_states = vec![];
+    for _ in 0..3 {
+        let slot = context.banks_client.get_root_slot().await.unwrap();
+        context.warp_to_slot(slot + 1_000).unwrap();
+        let recent_blockhash = context.last_blockhash;
+
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &payer.pubkey(),
+            &amm_data_account,
+            &observations_account,
+            &x_acc,
+            &y_acc,
+            10 * ONE_X,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+
+        pool_states.push(amm_data_of(amm_data_account, &mut context.banks_client).await);
+    }
+
+    let observations = observations_of(observations_account, &mut context.banks_client).await;
+    assert_eq!(
+        observations.chronological().copied().collect::<Vec<_>>(),
+        vec![
+            oracle::sample(&pool_states[1]),
+            oracle::sample(&pool_states[2])
+        ]
+    );
+
+    // Any other account in its place is rejected
+    {
+        let recent_blockhash = context.last_blockhash;
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &payer.pubkey(),
+            &amm_data_account,
+            &x_acc,
+            &x_acc,
+            &y_acc,
+            ONE_X,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(
+            &mut context.banks_client,
+            transaction,
+            AMMErrors::ObservationsMismatch,
+        )
+        .await;
+    }
+
+    // Clients leaving the account out, as before observations, still trade,
+    // only without a sample
+    {
+        let recent_blockhash = context.last_blockhash;
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &payer.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &x_acc,
+            &y_acc,
+            ONE_X,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+        assert_eq!(exchange_instruction.accounts.len(), 7);
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+
+        let unchanged = observations_of(observations_account, &mut context.banks_client).await;
+        assert_eq!(unchanged.newest, observations.newest);
+        assert_eq!(unchanged.samples, observations.samples);
+    }
+
+    // Growing needs a larger account, the samples move over oldest first
+    let recent_blockhash = context.last_blockhash;
+    for (capacity, result) in [
+        (2, Err(AMMErrors::InvalidObservationsCapacity)),
+        (4, Ok(())),
+    ] {
+        let new_observations_account = create_observations_account(
+            &payer,
+            &mut context.banks_client,
+            recent_blockhash,
+            &program_id,
+            capacity,
+        )
+        .await;
+
+        let grow_instruction = solana_amm::instruction::grow_observations(
+            &payer.pubkey(),
+            &amm_data_account,
+            &observations_account,
+            &new_observations_account,
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[grow_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        match result {
+            Err(error) => assert_amm_error(&mut context.banks_client, transaction, error).await,
+            Ok(()) => {
+                context
+                    .banks_client
+                    .process_transaction(transaction)
+                    .await
+                    .unwrap();
+
+                let amm_data = amm_data_of(amm_data_account, &mut context.banks_client).await;
+                assert_eq!(amm_data.observations, new_observations_account);
+                assert!(context
+                    .banks_client
+                    .get_account(observations_account)
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                let grown =
+                    observations_of(new_observations_account, &mut context.banks_client).await;
+                assert_eq!(grown.capacity, 4);
+                assert_eq!(grown.newest, 1);
+                assert_eq!(
+                    grown.samples,
+                    observations.chronological().copied().collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+
+    // Accumulators between two samples are interpolated, before the oldest
+    // one there is nothing to tell
+    let pool = &pool_states[2];
+    let (older, newer) = (&pool_states[1], &pool_states[2]);
+    let middle = (older.last_update_ts + newer.last_update_ts) / 2;
+    let elapsed = (middle - older.last_update_ts) as u128;
+    let window = (newer.last_update_ts - older.last_update_ts) as u128;
+    assert_eq!(
+        oracle::observe(pool, &observations, middle),
+        Some(Observation {
+            timestamp: middle,
+            price_x_cumulative: older.price_x_cumulative
+                + (newer.price_x_cumulative - older.price_x_cumulative) / window * elapsed,
+            price_y_cumulative: older.price_y_cumulative
+                + (newer.price_y_cumulative - older.price_y_cumulative) / window * elapsed,
+        })
+    );
+    assert_eq!(
+        oracle::observe(pool, &observations, older.last_update_ts),
+        Some(Observation::of(older, older.last_update_ts))
+    );
+    assert_eq!(
+        oracle::observe(pool, &observations, older.last_update_ts - 1),
+        None
+    );
+    assert_eq!(
+        oracle::observe(pool, &observations, pool.last_update_ts + 60),
+        Some(Observation::of(pool, pool.last_update_ts + 60))
+    );
+
+    // The price did not move between the two samples, so the average over
+    // any window in between is the spot price the older one left
+    let start = oracle::observe(pool, &observations, middle).unwrap();
+    let end = oracle::observe(pool, &observations, newer.last_update_ts).unwrap();
+    assert_eq!(
+        oracle::twap(&start, &end).map(|(price_x, _)| price_x),
+        Some((u128::from(older.y_amount) << 64) / u128::from(older.x_amount))
+    );
+
+    // The other instructions changing the reserves record samples as well
+    let observations_account = amm_data_of(amm_data_account, &mut context.banks_client)
+        .await
+        .observations;
+    for instruction in [
+        solana_amm::instruction::exchange_exact_out(
+            &payer.pubkey(),
+            &amm_data_account,
+            &observations_account,
+            &x_acc,
+            &y_acc,
+            ONE_Y,
+            ONE_X,
+            &spl_token::id(),
+            &program_id,
+        ),
+        solana_amm::instruction::sync(&amm_data_account, &observations_account, &program_id),
+    ] {
+        let slot = context.banks_client.get_root_slot().await.unwrap();
+        context.warp_to_slot(slot + 1_000).unwrap();
+        let recent_blockhash = context.last_blockhash;
+
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+
+        let amm_data = amm_data_of(amm_data_account, &mut context.banks_client).await;
+        let observations = observations_of(observations_account, &mut context.banks_client).await;
+        assert_eq!(
+            observations.samples[observations.newest as usize],
+            oracle::sample(&amm_data)
+        );
+    }
+    let observations = observations_of(observations_account, &mut context.banks_client).await;
+    assert_eq!(observations.samples.len(), 4);
+}
+
+#[tokio::test]
+async fn test_flash_loan() {
+    let program_id = Pubkey::new_unique();
+    let receiver_id = Pubkey::new_unique();
+    let defaulting_receiver_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+    program_test.add_program(
+        "flash_loan_receiver",
+        receiver_id,
+        processor!(flash_loan_receiver),
+    );
+    program_test.add_program(
+        "defaulting_flash_loan_receiver",
+        defaulting_receiver_id,
+        processor!(defaulting_flash_loan_receiver),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 400 * ONE_Y),
+        0,
+        (30, 10_000),
+        (1, 6),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let (x_vault, _) = solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &x_acc,
+        &alice.main_x,
+        ONE_X,
+    )
+    .await;
+
+    // The receivers repay out of the borrower token account Alice signs for
+    let flash_loan = |receiver_program_id: &Pubkey, amount: u64, token: &Pubkey| {
+        let flash_loan_instruction = solana_amm::instruction::flash_loan(
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice.main_x,
+            receiver_program_id,
+            &[AccountMeta::new_readonly(alice.keypair.pubkey(), true)],
+            amount,
+            token,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[flash_loan_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        transaction
+    };
+
+    // 0.3% of 10 X
+    let fee = 3_000_000;
+
+    assert_amm_error(
+        &mut banks_client,
+        flash_loan(&defaulting_receiver_id, 10 * ONE_X, &x_mint),
+        AMMErrors::FlashLoanNotRepaid,
+    )
+    .await;
+    assert_amm_error(
+        &mut banks_client,
+        flash_loan(&receiver_id, 10 * ONE_X, &Pubkey::new_unique()),
+        AMMErrors::TokenMintMismatch,
+    )
+    .await;
+    assert_amm_error(
+        &mut banks_client,
+        flash_loan(&receiver_id, 100 * ONE_X + 1, &x_mint),
+        AMMErrors::InsufficientLiquidity,
+    )
+    .await;
+    assert_amm_error(
+        &mut banks_client,
+        flash_loan(&program_id, 10 * ONE_X, &x_mint),
+        AMMErrors::InvalidProgramAccount,
+    )
+    .await;
+
+    // The fee is split between the reserves and the protocol like a
+    // trading fee
+    banks_client
+        .process_transaction(flash_loan(&receiver_id, 10 * ONE_X, &x_mint))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        balance_of(alice.main_x, &mut banks_client).await,
+        ONE_X - fee
+    );
+    assert_eq!(
+        balance_of(x_vault, &mut banks_client).await,
+        100 * ONE_X + fee
+    );
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.x_amount, 100 * ONE_X + fee - fee / 6);
+    assert_eq!(amm_data.protocol_fees_x, fee / 6);
+    assert_eq!(amm_data.y_amount, 400 * ONE_Y);
+}
+
+#[tokio::test]
+async fn test_sync_and_skim() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 400 * ONE_Y),
+        0,
+        (30, 10_000),
+        (1, 6),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let (x_vault, _) = solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account);
+    let (y_vault, _) = solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+
+    // A trade leaves protocol fees in the X vault on top of the reserves
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &payer.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &x_acc,
+            &y_acc,
+            10 * ONE_X,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+    let traded = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(traded.protocol_fees_x, 500_000);
+
+    let skim = |recent_blockhash| {
+        let skim_instruction = solana_amm::instruction::skim(
+            &amm_data_account,
+            &alice.main_x,
+            &alice.main_y,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[skim_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        transaction
+    };
+
+    // Tokens sent straight to the vaults are not part of the reserves, and
+    // anyone can skim them
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &x_acc,
+        &x_vault,
+        5 * ONE_X,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &y_vault,
+        2 * ONE_Y,
+    )
+    .await;
+
+    banks_client
+        .process_transaction(skim(recent_blockhash))
+        .await
+        .unwrap();
+
+    assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 5 * ONE_X);
+    assert_eq!(balance_of(alice.main_y, &mut banks_client).await, 2 * ONE_Y);
+    assert_eq!(
+        balance_of(x_vault, &mut banks_client).await,
+        traded.x_amount + traded.protocol_fees_x
+    );
+    assert_eq!(
+        balance_of(y_vault, &mut banks_client).await,
+        traded.y_amount
+    );
+
+    // Sync takes a donation into the reserves instead, protocol fees stay
+    // apart
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &x_acc,
+        &x_vault,
+        3 * ONE_X,
+    )
+    .await;
+
+    {
+        let sync_instruction =
+            solana_amm::instruction::sync(&amm_data_account, &Pubkey::default(), &program_id);
+
+        let mut transaction =
+            Transaction::new_with_payer(&[sync_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    let synced = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(synced.x_amount, traded.x_amount + 3 * ONE_X);
+    assert_eq!(synced.y_amount, traded.y_amount);
+    assert_eq!(synced.protocol_fees_x, traded.protocol_fees_x);
+
+    // Nothing is left to skim
+    let recent_blockhash = banks_client.get_recent_blockhash().await.unwrap();
+    banks_client
+        .process_transaction(skim(recent_blockhash))
+        .await
+        .unwrap();
+
+    assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 5 * ONE_X);
+    assert_eq!(balance_of(alice.main_y, &mut banks_client).await, 2 * ONE_Y);
+}
+
+#[tokio::test]
+async fn test_migrate_state() {
+    let program_id = Pubkey::new_unique();
+
+    let program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 400 * ONE_Y),
+        0,
+        (30, 10_000),
+        (1, 6),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+    let pool_data = banks_client
+        .get_account(amm_data_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+
+    // New pools carry the version and zeroed reserved bytes
+    assert_eq!(pool_data.len(), solana_amm::state::AMM::LEN);
+    assert_eq!(pool_data[0], solana_amm::state::AMM::VERSION);
+    assert!(pool_data[pool_data.len() - 120..].iter().all(|&b| b == 0));
+
+    // and the bump seeds of the pool PDAs
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(
+        amm_data.bump_seed,
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0).1
+    );
+    assert_eq!(
+        amm_data.vault_bump_seeds(),
+        (
+            solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account).1,
+            solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account).1,
+        )
+    );
+    assert_eq!(
+        amm_data.lp_mint_bump_seed,
+        solana_amm::pda::find_lp_mint_address(&program_id, &amm_data_account).1
+    );
+
+    // Fields sit at fixed little-endian offsets, and aligned bytes are read
+    // in place
+    assert_eq!(pool_data[48..56], amm_data.x_amount.to_le_bytes());
+    let mut aligned = vec![0u128; solana_amm::state::AMM::LEN / 16];
+    bytemuck::cast_slice_mut(&mut aligned).copy_from_slice(&pool_data);
+    let loaded = solana_amm::state::AMM::load(bytemuck::cast_slice(&aligned)).unwrap();
+    assert_eq!(loaded.x_amount, amm_data.x_amount);
+    assert_eq!(loaded.authority, amm_data.authority);
+
+    let migrate = |address: &Pubkey| {
+        let migrate_instruction = solana_amm::instruction::migrate_state(address, &program_id);
+
+        let mut transaction =
+            Transaction::new_with_payer(&[migrate_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+
+    // Current pools are left as they are
+    banks_client
+        .process_transaction(migrate(&amm_data_account))
+        .await
+        .unwrap();
+    assert_eq!(
+        banks_client
+            .get_account(amm_data_account)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+        pool_data
+    );
+
+    // A layout of another version is not read as the current one
+    let mut other_version = pool_data.clone();
+    other_version[0] = solana_amm::state::AMM::VERSION + 1;
+    assert_eq!(
+        solana_amm::state::AMM::unpack(&other_version).unwrap_err(),
+        ProgramError::Custom(AMMErrors::OutdatedState as u32)
+    );
+
+    // Layouts this program never wrote are refused
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+    program_test.add_account(
+        amm_data_account,
+        Account {
+            lamports: LAMPORTS_PER_SOL,
+            data: other_version,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let migrate_instruction =
+        solana_amm::instruction::migrate_state(&amm_data_account, &program_id);
+
+    let mut transaction =
+        Transaction::new_with_payer(&[migrate_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+    );
+}
+
+#[tokio::test]
+async fn test_migrate_v1_state() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    // Program data of the upgradeable loader, the bincode
+    // `UpgradeableLoaderState::ProgramData` header naming the authority
+    let upgrade_authority = Keypair::new();
+    program_test.add_account(
+        upgrade_authority.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    let mut program_data_header = 3u32.to_le_bytes().to_vec();
+    program_data_header.extend_from_slice(&0u64.to_le_bytes());
+    program_data_header.push(1);
+    program_data_header.extend_from_slice(upgrade_authority.pubkey().as_ref());
+    program_test.add_account(
+        program_data,
+        Account {
+            lamports: LAMPORTS_PER_SOL,
+            data: program_data_header,
+            owner: bpf_loader_upgradeable::id(),
+            ..Account::default()
+        },
+    );
+
+    let x_mint = Pubkey::new_unique();
+    let y_mint = Pubkey::new_unique();
+    for (mint, decimals) in [(x_mint, X_DECIMALS), (y_mint, Y_DECIMALS)] {
+        let mut data = vec![0; spl_token::state::Mint::LEN];
+        spl_token::state::Mint {
+            mint_authority: COption::Some(alice_keypair.pubkey()),
+            supply: 1_000 * 10u64.pow(decimals),
+            decimals: decimals as u8,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        }
+        .pack_into_slice(&mut data);
+        program_test.add_account(
+            mint,
+            Account {
+                lamports: LAMPORTS_PER_SOL,
+                data,
+                owner: spl_token::id(),
+                ..Account::default()
+            },
+        );
+    }
+
+    // The single pool of version 1: `is_initialized`, then the mints and
+    // big-endian amounts, with vaults that are their own authority. 1 X
+    // was sent to the X vault directly and is not part of the reserves.
+    let (legacy_amm_data_account, _) = solana_amm::pda::find_legacy_pool_address(&program_id);
+    let (legacy_x_vault, _) = solana_amm::pda::find_legacy_x_vault_address(&program_id);
+    let (legacy_y_vault, _) = solana_amm::pda::find_legacy_y_vault_address(&program_id);
+
+    let mut legacy_data = vec![1];
+    legacy_data.extend_from_slice(x_mint.as_ref());
+    legacy_data.extend_from_slice(&(100 * ONE_X).to_be_bytes());
+    legacy_data.extend_from_slice(y_mint.as_ref());
+    legacy_data.extend_from_slice(&(400 * ONE_Y).to_be_bytes());
+    assert_eq!(legacy_data.len(), solana_amm::state::PoolV1::LEN);
+
+    program_test.add_account(
+        legacy_amm_data_account,
+        Account {
+            lamports: LAMPORTS_PER_SOL,
+            data: legacy_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        legacy_x_vault,
+        token_account(x_mint, legacy_x_vault, 101 * ONE_X),
+    );
+    program_test.add_account(
+        legacy_y_vault,
+        token_account(y_mint, legacy_y_vault, 400 * ONE_Y),
+    );
+
+    let alice_x = Pubkey::new_unique();
+    program_test.add_account(
+        alice_x,
+        token_account(x_mint, alice_keypair.pubkey(), 10 * ONE_X),
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 1);
+    let (x_vault, _) = solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account);
+    let (y_vault, _) = solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account);
+    let (lp_mint, _) = solana_amm::pda::find_lp_mint_address(&program_id, &amm_data_account);
+
+    let migrate = |pool_index, authority: &Keypair, lp_keypair: &Keypair, recent_blockhash| {
+        let migrate_instruction = solana_amm::instruction::migrate_v1_state(
+            &authority.pubkey(),
+            &x_mint,
+            &y_mint,
+            pool_index,
+            &lp_keypair.pubkey(),
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[migrate_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, authority, lp_keypair], recent_blockhash);
+        transaction
+    };
+
+    // Anyone else would pick the pool index and become its authority
+    assert_amm_error(
+        &mut banks_client,
+        migrate(1, &alice_keypair, &Keypair::new(), recent_blockhash),
+        AMMErrors::Unauthorized,
+    )
+    .await;
+
+    // The new pool has to be the one derived from the mints and index
+    {
+        let lp_keypair = Keypair::new();
+        let mut migrate_instruction = solana_amm::instruction::migrate_v1_state(
+            &upgrade_authority.pubkey(),
+            &x_mint,
+            &y_mint,
+            1,
+            &lp_keypair.pubkey(),
+            &spl_token::id(),
+            &program_id,
+        );
+        migrate_instruction.accounts[2].pubkey =
+            solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0).0;
+
+        let mut transaction =
+            Transaction::new_with_payer(&[migrate_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &upgrade_authority, &lp_keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::DataAccountMismatch,
+        )
+        .await;
+    }
+
+    let authority_lamports = banks_client
+        .get_balance(upgrade_authority.pubkey())
+        .await
+        .unwrap();
+    let lp_keypair = Keypair::new();
+    banks_client
+        .process_transaction(migrate(
+            1,
+            &upgrade_authority,
+            &lp_keypair,
+            recent_blockhash,
+        ))
+        .await
+        .unwrap();
+
+    // The old accounts are closed, their lamports go to the authority
+    for address in [legacy_amm_data_account, legacy_x_vault, legacy_y_vault] {
+        assert!(banks_client.get_account(address).await.unwrap().is_none());
+    }
+    assert!(
+        banks_client
+            .get_balance(upgrade_authority.pubkey())
+            .await
+            .unwrap()
+            > authority_lamports
+    );
+
+    let amm_data = amm_data_of(amm_data_account, &mut banks_client).await;
+    assert_eq!(amm_data.version, solana_amm::state::AMM::VERSION);
+    assert_eq!(amm_data.pool_index, 1);
+    assert_eq!((amm_data.x_mint, amm_data.y_mint), (x_mint, y_mint));
+    assert_eq!(
+        (amm_data.x_amount, amm_data.y_amount),
+        (100 * ONE_X, 400 * ONE_Y)
+    );
+    assert_eq!(amm_data.lp_mint, lp_mint);
+    assert_eq!(amm_data.curve_type, CurveType::ConstantProduct as u8);
+    assert_eq!(
+        (amm_data.fee_numerator, amm_data.protocol_fee_numerator),
+        (0, 0)
+    );
+    assert_eq!(amm_data.authority, upgrade_authority.pubkey());
+    assert_eq!(
+        (amm_data.x_decimals, amm_data.y_decimals),
+        (X_DECIMALS as u8, Y_DECIMALS as u8)
+    );
+    assert_eq!(
+        amm_data.vault_bump_seeds(),
+        (
+            solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account).1,
+            solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account).1,
+        )
+    );
+
+    // Every token moved, the donation stays above the reserves
+    assert_eq!(balance_of(x_vault, &mut banks_client).await, 101 * ONE_X);
+    assert_eq!(balance_of(y_vault, &mut banks_client).await, 400 * ONE_Y);
+
+    // The LP tokens of the reserves are held by the pool itself
+    let lp_account = spl_token::state::Account::unpack(
+        &banks_client
+            .get_account(lp_keypair.pubkey())
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(lp_account.mint, lp_mint);
+    assert_eq!(lp_account.owner, amm_data_account);
+    assert_eq!(lp_account.amount, solana_amm::state::INITIAL_LP_SUPPLY);
+
+    // There is nothing left to migrate
+    let recent_blockhash = banks_client.get_recent_blockhash().await.unwrap();
+    assert_amm_error(
+        &mut banks_client,
+        migrate(2, &upgrade_authority, &Keypair::new(), recent_blockhash),
+        AMMErrors::InvalidAccountOwner,
+    )
+    .await;
+
+    // and the moved pool trades
+    let alice_y =
+        create_token_account(&alice_keypair, &y_mint, &mut banks_client, recent_blockhash).await;
+    {
+        let exchange_instruction = solana_amm::instruction::exchange(
+            &alice_keypair.pubkey(),
+            &amm_data_account,
+            &Pubkey::default(),
+            &alice_x,
+            &alice_y,
+            10 * ONE_X,
+            0,
+            None,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice_keypair.pubkey()));
+        transaction.sign(&[&alice_keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    // 400 * 10 / 110 Y, without fees
+    assert_eq!(
+        balance_of(alice_y, &mut banks_client).await,
+        4_000 * ONE_Y / 110
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_compute_units() {
+    use solana_amm::pda;
+
+    let program_id = Pubkey::new_unique();
+    let mints = (Pubkey::new_unique(), Pubkey::new_unique());
+    let (first_mint, second_mint) = pda::sort_mints(&mints.0, &mints.1);
+    let units = BpfComputeBudget::default().create_program_address_units;
+
+    // `find_program_address` tries the seeds from 255 down, one
+    // `create_program_address` each, up to the bump seed. Also returns the
+    // next valid seed below it.
+    let search = |seeds: &[&[u8]]| {
+        let mut bump_seeds = (0..=u8::MAX).rev().filter(|&bump_seed| {
+            Pubkey::create_program_address(&[seeds, &[&[bump_seed]]].concat(), &program_id).is_ok()
+        });
+        let bump_seed = bump_seeds.next().unwrap();
+        (
+            u64::from(u8::MAX - bump_seed) + 1,
+            bump_seed,
+            bump_seeds.next().unwrap(),
+        )
+    };
+    let pool_searches = |pool_index: u16| {
+        let (pool, _) = pda::find_pool_address(&program_id, &mints.0, &mints.1, pool_index);
+        [
+            search(&[
+                pda::POOL_SEED,
+                first_mint.as_ref(),
+                second_mint.as_ref(),
+                &pool_index.to_le_bytes(),
+            ]),
+            search(&[pda::X_VAULT_SEED, pool.as_ref()]),
+            search(&[pda::Y_VAULT_SEED, pool.as_ref()]),
+        ]
+    };
+
+    // A pool whose addresses take a few tries to find
+    let (pool_index, searches) = (0..)
+        .map(|pool_index| (pool_index, pool_searches(pool_index)))
+        .find(|(_, searches)| searches.iter().map(|(tries, _, _)| tries).sum::<u64>() >= 6)
+        .unwrap();
+    let bump_seeds = [searches[0].1, searches[1].1, searches[2].1];
+
+    // The exchange derives the addresses from the stored bump seeds: another
+    // valid seed in their place gives another address and fails it
+    assert!(
+        exchange_on_pool(program_id, mints, pool_index, bump_seeds, None)
+            .await
+            .is_ok()
+    );
+    let mismatches = [
+        AMMErrors::DataAccountMismatch,
+        AMMErrors::VaultAccountMismatch,
+        AMMErrors::VaultAccountMismatch,
+    ];
+    for (position, ((_, _, other_bump_seed), mismatch)) in
+        searches.iter().zip(mismatches.iter()).enumerate()
+    {
+        let mut wrong_bump_seeds = bump_seeds;
+        wrong_bump_seeds[position] = *other_bump_seed;
+        assert_eq!(
+            exchange_on_pool(program_id, mints, pool_index, wrong_bump_seeds, None)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(*mismatch as u32))
+        );
+    }
+
+    // so it pays one `create_program_address` per address, where searching
+    // would pay every try
+    let stored_units = searches.len() as u64 * units;
+    let search_units = searches
+        .iter()
+        .map(|(tries, _, _)| tries * units)
+        .sum::<u64>();
+    assert_eq!(units, 1_500);
+    assert!(search_units - stored_units >= 3 * units);
+}
+
+// Compute units are only metered for the BPF build, run with `cargo test-bpf`
+#[cfg(feature = "test-bpf")]
+#[tokio::test]
+async fn test_exchange_compute_units_bpf() {
+    let program_id = Pubkey::new_unique();
+    let mints = (Pubkey::new_unique(), Pubkey::new_unique());
+
+    // Seeds `find_program_address` would try before the bump seeds of the
+    // pool and its vaults
+    let bump_seeds = |pool_index| {
+        let (pool, bump_seed) =
+            solana_amm::pda::find_pool_address(&program_id, &mints.0, &mints.1, pool_index);
+        [
+            bump_seed,
+            solana_amm::pda::find_x_vault_address(&program_id, &pool).1,
+            solana_amm::pda::find_y_vault_address(&program_id, &pool).1,
+        ]
+    };
+    let search_len = |pool_index| {
+        bump_seeds(pool_index)
+            .iter()
+            .map(|&bump_seed| u32::from(u8::MAX - bump_seed))
+            .sum::<u32>()
+    };
+    let quick_pool = (0..)
+        .find(|&pool_index| search_len(pool_index) == 0)
+        .unwrap();
+    let slow_pool = (0..)
+        .find(|&pool_index| search_len(pool_index) >= 3)
+        .unwrap();
+    let fits = |pool_index, max_units| async move {
+        match exchange_on_pool(
+            program_id,
+            mints,
+            pool_index,
+            bump_seeds(pool_index),
+            Some(max_units),
+        )
+        .await
+        {
+            Ok(()) => true,
+            Err(TransportError::TransactionError(TransactionError::InstructionError(
+                0,
+                InstructionError::ComputationalBudgetExceeded,
+            ))) => false,
+            Err(err) => panic!("exchange failed: {:?}", err),
+        }
+    };
+
+    // Units of an exchange on the pool found at the first try, bisected
+    let (mut too_few, mut enough) = (0, 200_000);
+    assert!(fits(quick_pool, enough).await);
+    while enough - too_few > 1 {
+        let units = (too_few + enough) / 2;
+        if fits(quick_pool, units).await {
+            enough = units;
+        } else {
+            too_few = units;
+        }
+    }
+
+    // The other pool costs the same, searching for its seeds would take at
+    // least three more `create_program_address` calls
+    assert!(fits(slow_pool, enough + 1_499).await);
+}
+
+#[tokio::test]
+async fn test_route_exchange() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+    let (z_mint, z_acc) = mint_token(&payer, &mut banks_client, recent_blockhash, 6).await;
+
+    // X -> Y -> Z, there is no X/Z pool
+    for ((first_mint, first_acc, first_amount), (second_mint, second_acc, second_amount)) in [
+        (
+            (&x_mint, &x_acc, 100 * ONE_X),
+            (&y_mint, &y_acc, 10_000 * ONE_Y),
+        ),
+        (
+            (&y_mint, &y_acc, 10_000 * ONE_Y),
+            (&z_mint, &z_acc, 1_000_000_000),
+        ),
+    ] {
+        initialize_pool(
+            &payer,
+            &mut banks_client,
+            recent_blockhash,
+            &program_id,
+            (first_mint, first_acc, first_amount),
+            (second_mint, second_acc, second_amount),
+            0,
+            (30, 10_000),
+            (0, 1),
+            CurveParameters::ConstantProduct,
+        )
+        .await
+        .unwrap();
+    }
+    let route = [
+        (
+            solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0).0,
+            Pubkey::default(),
+        ),
+        (
+            solana_amm::pda::find_pool_address(&program_id, &y_mint, &z_mint, 0).0,
+            Pubkey::default(),
+        ),
+    ];
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    let alice_z =
+        create_token_account(&alice.keypair, &z_mint, &mut banks_client, recent_blockhash).await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &x_acc,
+        &alice.main_x,
+        10 * ONE_X,
+    )
+    .await;
+
+    // The destination account has to hold the last token of the route
+    // and the minimum applies to the end of the route only
+    for (destination, minimum_amount_out, error) in [
+        (alice.main_y, 0, AMMErrors::TokenMintMismatch),
+        (alice_z, 9_746_040, AMMErrors::InsufficientOutputAmount),
+    ] {
+        let route_instruction = solana_amm::instruction::route_exchange(
+            &alice.keypair.pubkey(),
+            &alice.main_x,
+            &destination,
+            &route,
+            ONE_X,
+            minimum_amount_out,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[route_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, error).await;
+    }
+
+    // 1 X buys 98.7158 Y in the first pool, which buy 9.746039 Z in the second
+    let route_instruction = solana_amm::instruction::route_exchange(
+        &alice.keypair.pubkey(),
+        &alice.main_x,
+        &alice_z,
+        &route,
+        ONE_X,
+        9_746_039,
+        &spl_token::id(),
+        &program_id,
+    );
+
+    let mut transaction =
+        Transaction::new_with_payer(&[route_instruction], Some(&alice.keypair.pubkey()));
+    transaction.sign(&[&alice.keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 9 * ONE_X);
+    assert_eq!(balance_of(alice.main_y, &mut banks_client).await, 0);
+    assert_eq!(balance_of(alice_z, &mut banks_client).await, 9_746_039);
+
+    // Y only moved between the vaults of the two pools
+    let first_pool = amm_data_of(route[0].0, &mut banks_client).await;
+    let second_pool = amm_data_of(route[1].0, &mut banks_client).await;
+    let (first_pool_y, second_pool_y) = if first_pool.y_mint == y_mint {
+        (first_pool.y_amount, second_pool.x_amount)
+    } else {
+        (first_pool.x_amount, second_pool.y_amount)
+    };
+    assert_eq!(first_pool_y, 10_000 * ONE_Y - 987_158);
+    assert_eq!(second_pool_y, 10_000 * ONE_Y + 987_158);
+
+    // Hops record into the observations accounts passed along with them
+    let observations_account =
+        create_observations_account(&payer, &mut banks_client, recent_blockhash, &program_id, 4)
+            .await;
+    let initialize_instruction = solana_amm::instruction::initialize_observations(
+        &payer.pubkey(),
+        &route[1].0,
+        &observations_account,
+        &program_id,
+    );
+    let route = [route[0], (route[1].0, observations_account)];
+    let route_instruction = solana_amm::instruction::route_exchange(
+        &alice.keypair.pubkey(),
+        &alice.main_x,
+        &alice_z,
+        &route,
+        ONE_X,
+        0,
+        &spl_token::id(),
+        &program_id,
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[initialize_instruction, route_instruction],
+        Some(&alice.keypair.pubkey()),
+    );
+    transaction.sign(&[&alice.keypair, &payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let second_pool = amm_data_of(route[1].0, &mut banks_client).await;
+    let observations = observations_of(observations_account, &mut banks_client).await;
+    assert_eq!(
+        observations.samples[observations.newest as usize],
+        solana_amm::oracle::sample(&second_pool)
+    );
+}
+
+#[tokio::test]
+async fn test_concentrated_liquidity() {
+    use solana_amm::concentrated;
+
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    // Starts at tick 0, one X unit for one Y unit
+    let initialize_instruction = solana_amm::instruction::initialize_concentrated(
+        &payer.pubkey(),
+        &x_mint,
+        &y_mint,
+        0,
+        (30, 10_000),
+        10,
+        concentrated::sqrt_price_at_tick(0).unwrap(),
+        &program_id,
+        &spl_token::id(),
+    );
+    let mut transaction =
+        Transaction::new_with_payer(&[initialize_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (pool_account, pool_bump_seed) =
+        solana_amm::pda::find_concentrated_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    // The pool keeps the bump seeds of its PDAs
+    let pool = concentrated_pool_of(pool_account, &mut banks_client).await;
+    assert_eq!(pool.bump_seed, pool_bump_seed);
+    assert_eq!(
+        pool.vault_bump_seeds(),
+        (
+            solana_amm::pda::find_x_vault_address(&program_id, &pool_account).1,
+            solana_amm::pda::find_y_vault_address(&program_id, &pool_account).1,
+        )
+    );
+
+    // Bounds have to be multiples of the tick spacing
+    {
+        let open_instruction = solana_amm::instruction::open_position(
+            &payer.pubkey(),
+            &pool_account,
+            5,
+            100,
+            &program_id,
+        );
+        let mut transaction =
+            Transaction::new_with_payer(&[open_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::InvalidTickRange).await;
+    }
+
+    // A spans the current price, B sits above it and holds X only
+    let mut positions = vec![];
+    for (tick_lower, tick_upper) in [(-100, 100), (100, 200)] {
+        let open_instruction = solana_amm::instruction::open_position(
+            &payer.pubkey(),
+            &pool_account,
+            tick_lower,
+            tick_upper,
+            &program_id,
+        );
+        let mut transaction =
+            Transaction::new_with_payer(&[open_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        positions.push(
+            solana_amm::pda::find_position_address(
+                &program_id,
+                &pool_account,
+                &payer.pubkey(),
+                tick_lower,
+                tick_upper,
+            )
+            .0,
+        );
+    }
+    let (position_a, position_b) = (positions[0], positions[1]);
+
+    // Positions are little-endian like the pools, the ticks follow the pool
+    // and owner keys
+    let position_data = banks_client
+        .get_account(position_a)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    assert_eq!(position_data[65..69], (-100i32).to_le_bytes());
+    assert_eq!(position_data[69..73], 100i32.to_le_bytes());
+
+    let x_balance = balance_of(x_acc, &mut banks_client).await;
+    let y_balance = balance_of(y_acc, &mut banks_client).await;
+    for (position, maximum_x_amount, maximum_y_amount, error) in [
+        (position_a, 498_727_208, 498_727_208, None),
+        (
+            position_b,
+            496_239_919,
+            0,
+            Some(AMMErrors::ExcessiveInputAmount),
+        ),
+        (position_b, 496_239_920, 0, None),
+    ] {
+        let increase_instruction = solana_amm::instruction::increase_liquidity(
+            &payer.pubkey(),
+            &pool_account,
+            &position,
+            &x_acc,
+            &y_acc,
+            100_000_000_000,
+            maximum_x_amount,
+            maximum_y_amount,
+            &spl_token::id(),
+            &program_id,
+        );
+        let mut transaction =
+            Transaction::new_with_payer(&[increase_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+
+        match error {
+            Some(error) => assert_amm_error(&mut banks_client, transaction, error).await,
+            None => banks_client.process_transaction(transaction).await.unwrap(),
+        }
+    }
+    assert_eq!(
+        balance_of(x_acc, &mut banks_client).await,
+        x_balance - 498_727_208 - 496_239_920
+    );
+    assert_eq!(
+        balance_of(y_acc, &mut banks_client).await,
+        y_balance - 498_727_208
+    );
+
+    let pool = concentrated_pool_of(pool_account, &mut banks_client).await;
+    assert_eq!(pool.tick_current, 0);
+    assert_eq!(pool.liquidity, 100_000_000_000);
+    assert_eq!(
+        pool.ticks.iter().map(|tick| tick.index).collect::<Vec<_>>(),
+        vec![-100, 100, 200]
+    );
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        700_000_000,
+    )
+    .await;
+
+    // Buying X pushes the price through A's upper tick into B's range
+    {
+        let exchange_instruction = solana_amm::instruction::exchange_concentrated(
+            &alice.keypair.pubkey(),
+            &pool_account,
+            &alice.main_y,
+            &alice.main_x,
+            700_000_000,
+            693_063_110,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(
+            balance_of(alice.main_x, &mut banks_client).await,
+            693_063_110
+        );
+
+        let pool = concentrated_pool_of(pool_account, &mut banks_client).await;
+        assert_eq!(pool.tick_current, 139);
+        assert_eq!(pool.liquidity, 100_000_000_000);
+    }
+
+    // Positions can only be changed by their owner
+    {
+        let decrease_instruction = solana_amm::instruction::decrease_liquidity(
+            &alice.keypair.pubkey(),
+            &pool_account,
+            &position_a,
+            &alice.main_x,
+            &alice.main_y,
+            100_000_000_000,
+            0,
+            0,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[decrease_instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::PositionMismatch).await;
+    }
+
+    // Fees are only paid out to token accounts
+    {
+        let collect_instruction = solana_amm::instruction::collect_position_fees(
+            &payer.pubkey(),
+            &pool_account,
+            &position_a,
+            &x_acc,
+            &payer.pubkey(),
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[collect_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidAccountOwner,
+        )
+        .await;
+    }
+
+    // A earned the 0.3% Y fee of the swap until its upper tick, B the rest
+    for (position, fees) in [(position_a, 1_508_205), (position_b, 591_794)] {
+        let y_balance = balance_of(y_acc, &mut banks_client).await;
+        let collect_instruction = solana_amm::instruction::collect_position_fees(
+            &payer.pubkey(),
+            &pool_account,
+            &position,
+            &x_acc,
+            &y_acc,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[collect_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(balance_of(y_acc, &mut banks_client).await, y_balance + fees);
+        let position = position_of(position, &mut banks_client).await;
+        assert_eq!((position.tokens_owed_x, position.tokens_owed_y), (0, 0));
+    }
+
+    // Out of range, A is all Y now and its ticks are dropped
+    {
+        let x_balance = balance_of(x_acc, &mut banks_client).await;
+        let y_balance = balance_of(y_acc, &mut banks_client).await;
+        let decrease_instruction = solana_amm::instruction::decrease_liquidity(
+            &payer.pubkey(),
+            &pool_account,
+            &position_a,
+            &x_acc,
+            &y_acc,
+            100_000_000_000,
+            0,
+            999_954_169,
+            &spl_token::id(),
+            &program_id,
+        );
+
+        let mut transaction =
+            Transaction::new_with_payer(&[decrease_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        assert_eq!(balance_of(x_acc, &mut banks_client).await, x_balance);
+        assert_eq!(
+            balance_of(y_acc, &mut banks_client).await,
+            y_balance + 999_954_169
+        );
+
+        let pool = concentrated_pool_of(pool_account, &mut banks_client).await;
+        assert_eq!(
+            pool.ticks.iter().map(|tick| tick.index).collect::<Vec<_>>(),
+            vec![100, 200]
+        );
+        assert_eq!(
+            position_of(position_a, &mut banks_client).await.liquidity,
+            0
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_concentrated_tick_cap() {
+    use solana_amm::concentrated;
+
+    let program_id = Pubkey::new_unique();
+
+    let program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    let initialize_instruction = solana_amm::instruction::initialize_concentrated(
+        &payer.pubkey(),
+        &x_mint,
+        &y_mint,
+        0,
+        (30, 10_000),
+        10,
+        concentrated::sqrt_price_at_tick(0).unwrap(),
+        &program_id,
+        &spl_token::id(),
+    );
+    let mut transaction =
+        Transaction::new_with_payer(&[initialize_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (pool_account, _) =
+        solana_amm::pda::find_concentrated_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let open_position = |tick_lower: i32, tick_upper: i32| {
+        let position = solana_amm::pda::find_position_address(
+            &program_id,
+            &pool_account,
+            &payer.pubkey(),
+            tick_lower,
+            tick_upper,
+        )
+        .0;
+        let instruction = solana_amm::instruction::open_position(
+            &payer.pubkey(),
+            &pool_account,
+            tick_lower,
+            tick_upper,
+            &program_id,
+        );
+        (position, instruction)
+    };
+    let change_liquidity = |position: &Pubkey, liquidity: u128, increase: bool| {
+        if increase {
+            solana_amm::instruction::increase_liquidity(
+                &payer.pubkey(),
+                &pool_account,
+                position,
+                &x_acc,
+                &y_acc,
+                liquidity,
+                u64::MAX,
+                u64::MAX,
+                &spl_token::id(),
+                &program_id,
+            )
+        } else {
+            solana_amm::instruction::decrease_liquidity(
+                &payer.pubkey(),
+                &pool_account,
+                position,
+                &x_acc,
+                &y_acc,
+                liquidity,
+                0,
+                0,
+                &spl_token::id(),
+                &program_id,
+            )
+        }
+    };
+
+    // Dust positions are refused
+    let (first_position, open_instruction) = open_position(10, 20);
+    {
+        let mut transaction = Transaction::new_with_payer(
+            &[
+                open_instruction,
+                change_liquidity(
+                    &first_position,
+                    concentrated::MIN_POSITION_LIQUIDITY - 1,
+                    true,
+                ),
+            ],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                1,
+                InstructionError::Custom(AMMErrors::LiquidityBelowMinimum as u32)
+            )
+        );
+    }
+
+    // Far from the price the minimum liquidity is worth next to nothing, so
+    // the slots cannot be filled with far away ranges on either side
+    for i in 0..solana_amm::state::MAX_TICKS as i32 / 4 {
+        for (tick_lower, tick_upper) in [
+            (443_000 - 20 * i, 443_010 - 20 * i),
+            (-443_010 + 20 * i, -443_000 + 20 * i),
+        ] {
+            let (position, open_instruction) = open_position(tick_lower, tick_upper);
+            let mut transaction = Transaction::new_with_payer(
+                &[
+                    open_instruction,
+                    change_liquidity(&position, concentrated::MIN_POSITION_LIQUIDITY, true),
+                ],
+                Some(&payer.pubkey()),
+            );
+            transaction.sign(&[&payer], recent_blockhash);
+            assert_eq!(
+                banks_client
+                    .process_transaction(transaction)
+                    .await
+                    .unwrap_err()
+                    .unwrap(),
+                TransactionError::InstructionError(
+                    1,
+                    InstructionError::Custom(AMMErrors::LiquidityBelowMinimum as u32)
+                )
+            );
+        }
+    }
+    assert!(concentrated_pool_of(pool_account, &mut banks_client)
+        .await
+        .ticks
+        .is_empty());
+
+    // A far away range takes as much as the minimum liquidity over a range
+    // as wide at the price
+    {
+        let minimum_x = concentrated::x_amount_delta(
+            concentrated::sqrt_price_at_tick(0).unwrap(),
+            concentrated::sqrt_price_at_tick(10).unwrap(),
+            concentrated::MIN_POSITION_LIQUIDITY,
+            false,
+        )
+        .unwrap() as u64;
+        let liquidity = concentrated::MIN_POSITION_LIQUIDITY * 10_000_000_000;
+
+        let (position, open_instruction) = open_position(443_000, 443_010);
+        let x_balance = balance_of(x_acc, &mut banks_client).await;
+        let mut transaction = Transaction::new_with_payer(
+            &[
+                open_instruction,
+                change_liquidity(&position, liquidity, true),
+            ],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+        assert!(x_balance - balance_of(x_acc, &mut banks_client).await >= minimum_x);
+
+        // Nor can it be shrunk back to the minimum liquidity
+        let mut transaction = Transaction::new_with_payer(
+            &[change_liquidity(
+                &position,
+                liquidity - concentrated::MIN_POSITION_LIQUIDITY,
+                false,
+            )],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::LiquidityBelowMinimum,
+        )
+        .await;
+
+        let mut transaction = Transaction::new_with_payer(
+            &[change_liquidity(&position, liquidity, false)],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    // Positions of twice the minimum liquidity just above the price take
+    // every tick slot
+    let liquidity = 2 * concentrated::MIN_POSITION_LIQUIDITY;
+    for i in 0..solana_amm::state::MAX_TICKS as i32 / 2 {
+        let (position, open_instruction) = open_position(20 * i + 10, 20 * i + 20);
+        let mut transaction = Transaction::new_with_payer(
+            &[
+                open_instruction,
+                change_liquidity(&position, liquidity, true),
+            ],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    let pool = concentrated_pool_of(pool_account, &mut banks_client).await;
+    assert_eq!(pool.ticks.len(), solana_amm::state::MAX_TICKS);
+
+    let (last_position, open_instruction) = open_position(1_000, 1_010);
+    {
+        let mut transaction =
+            Transaction::new_with_payer(&[open_instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let mut transaction = Transaction::new_with_payer(
+            &[change_liquidity(&last_position, liquidity, true)],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::TickListFull).await;
+    }
+
+    // A position cannot be shrunk into dust either, only emptied
+    {
+        let mut transaction = Transaction::new_with_payer(
+            &[change_liquidity(
+                &first_position,
+                liquidity - concentrated::MIN_POSITION_LIQUIDITY + 1,
+                false,
+            )],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::LiquidityBelowMinimum,
+        )
+        .await;
+    }
+
+    // Emptying a position frees its ticks for another range
+    {
+        let mut transaction = Transaction::new_with_payer(
+            &[
+                change_liquidity(&first_position, liquidity, false),
+                change_liquidity(&last_position, liquidity, true),
+            ],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let pool = concentrated_pool_of(pool_account, &mut banks_client).await;
+        assert_eq!(pool.ticks.len(), solana_amm::state::MAX_TICKS);
+        assert_eq!(pool.ticks.first().unwrap().index, 30);
+        assert_eq!(pool.ticks.last().unwrap().index, 1_010);
+    }
+}
+
+#[tokio::test]
+async fn test_initialize_account_validation() {
+    let program_id = Pubkey::new_unique();
+
+    let program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    let owner_lp_keypair = Keypair::new();
+    let init_instruction = solana_amm::instruction::initialize_amm(
+        &payer.pubkey(),
+        &x_acc,
+        &x_mint,
+        &y_acc,
+        &y_mint,
+        &owner_lp_keypair.pubkey(),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+        &program_id,
+        &spl_token::id(),
+    );
+
+    // Vault that is not the pool PDA
+    {
+        let mut instruction = init_instruction.clone();
+        instruction.accounts[3].pubkey = Pubkey::new_unique();
+
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &owner_lp_keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::VaultAccountMismatch,
+        )
+        .await;
+    }
+
+    // Fake system program
+    {
+        let mut instruction = init_instruction.clone();
+        instruction.accounts[10].pubkey = Pubkey::new_unique();
+
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &owner_lp_keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidProgramAccount,
+        )
+        .await;
+    }
+
+    // Initial X account that is not a token account
+    {
+        let mut instruction = init_instruction.clone();
+        instruction.accounts[2].pubkey = payer.pubkey();
+
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &owner_lp_keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidAccountOwner,
+        )
+        .await;
+    }
+
+    // Owner LP account without its signature
+    {
+        let mut instruction = init_instruction.clone();
+        instruction.accounts[9].is_signer = false;
+
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::AccountNotSigner).await;
+    }
+
+    let mut transaction = Transaction::new_with_payer(&[init_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_lp_keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_exchange_account_validation() {
+    let program_id = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+
+    let alice_keypair = Keypair::new();
+    program_test.add_account(
+        alice_keypair.pubkey(),
+        Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let (x_mint, x_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        X_DECIMALS as u8,
+    )
+    .await;
+    let (y_mint, y_acc) = mint_token(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        Y_DECIMALS as u8,
+    )
+    .await;
+
+    initialize_pool(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &program_id,
+        (&x_mint, &x_acc, 100 * ONE_X),
+        (&y_mint, &y_acc, 10_000 * ONE_Y),
+        0,
+        (0, 10_000),
+        (0, 1),
+        CurveParameters::ConstantProduct,
+    )
+    .await
+    .unwrap();
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, 0);
+
+    let alice = TestUser::new(
+        alice_keypair,
+        &mut banks_client,
+        recent_blockhash,
+        x_mint,
+        y_mint,
+    )
+    .await;
+    transfer_tokens(
+        &payer,
+        &mut banks_client,
+        recent_blockhash,
+        &y_acc,
+        &alice.main_y,
+        100 * ONE_Y,
+    )
+    .await;
+
+    let exchange_instruction = solana_amm::instruction::exchange(
+        &alice.keypair.pubkey(),
+        &amm_data_account,
+        &Pubkey::default(),
+        &alice.main_y,
+        &alice.main_x,
+        100 * ONE_Y,
+        0,
+        None,
+        &spl_token::id(),
+        &program_id,
+    );
+
+    // Someone else submits a swap from Alice's account
+    {
+        let mut instruction = exchange_instruction.clone();
+        instruction.accounts[0].is_signer = false;
+
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_amm_error(&mut banks_client, transaction, AMMErrors::AccountNotSigner).await;
+    }
+
+    // Data account not owned by the program
+    {
+        let mut instruction = exchange_instruction.clone();
+        instruction.accounts[1].pubkey = alice.main_x;
+
+        let mut transaction =
+            Transaction::new_with_payer(&[instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidAccountOwner,
+        )
+        .await;
+    }
+
+    // Token account of the right mint posing as the X vault
+    {
+        let mut instruction = exchange_instruction.clone();
+        instruction.accounts[4].pubkey = x_acc;
+
+        let mut transaction =
+            Transaction::new_with_payer(&[instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::VaultAccountMismatch,
+        )
+        .await;
+    }
+
+    // Read-only vault
+    {
+        let mut instruction = exchange_instruction.clone();
+        instruction.accounts[5].is_writable = false;
+
+        let mut transaction =
+            Transaction::new_with_payer(&[instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::AccountNotWritable,
+        )
+        .await;
+    }
+
+    // Fake token program
+    {
+        let mut instruction = exchange_instruction.clone();
+        instruction.accounts[6].pubkey = Pubkey::new_unique();
+
+        let mut transaction =
+            Transaction::new_with_payer(&[instruction], Some(&alice.keypair.pubkey()));
+        transaction.sign(&[&alice.keypair], recent_blockhash);
+        assert_amm_error(
+            &mut banks_client,
+            transaction,
+            AMMErrors::InvalidProgramAccount,
+        )
+        .await;
+    }
+
+    assert_eq!(
+        balance_of(alice.main_y, &mut banks_client).await,
+        100 * ONE_Y
+    );
+    assert_eq!(balance_of(alice.main_x, &mut banks_client).await, 0);
+
+    let mut transaction =
+        Transaction::new_with_payer(&[exchange_instruction], Some(&alice.keypair.pubkey()));
+    transaction.sign(&[&alice.keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(balance_of(alice.main_y, &mut banks_client).await, 0);
+}
+
+#[test]
+fn test_math() {
+    use solana_amm::math;
+
+    let overflow = Err(ProgramError::Custom(AMMErrors::MathOverflow as u32));
+
+    assert_eq!(math::mul_div_floor(10, 2, 3), Ok(6));
+    assert_eq!(math::mul_div_ceil(10, 2, 3), Ok(7));
+    assert_eq!(math::mul_div_ceil(10, 3, 3), Ok(10));
+
+    // Intermediate products of u64 reserves do not overflow
+    let max = u128::from(u64::MAX);
+    assert_eq!(math::mul_div_floor(max, max, max), Ok(u64::MAX));
+    assert_eq!(math::mul_div_floor(max, 1_000, max + 1_000), Ok(999));
+    assert_eq!(math::mul_div_ceil(max, 1_000, max + 1_000), Ok(1_000));
+
+    // Results that do not fit into u64 are errors, not wrapped values
+    assert_eq!(math::mul_div_floor(max, 2, 1), overflow);
+    assert_eq!(math::mul_div_ceil(max, max, 0), overflow);
+    assert_eq!(math::checked_add(u64::MAX, 1), overflow);
+    assert_eq!(math::checked_sub(0, 1), overflow);
+
+    // Powers are raised by their error bound, never below the exact value
+    let one = math::FIXED_ONE;
+    assert_eq!(
+        math::pow_ceil(one, 3, 7),
+        Ok(one + one / 1_000_000_000 + 10_000)
+    );
+    assert!(math::pow_ceil(one / 2, 1, 1).unwrap() >= one / 2);
+    assert!(math::pow_ceil(one / 2, 1, 2).unwrap() >= 707_106_781_186_547_525);
+    assert!(math::pow_ceil(one / 2, 1, 2).unwrap() < 707_106_782_000_000_000);
+    assert!(math::pow_ceil(2 * one, 1, 2).is_err());
+
+    // Square roots round down, up to the product of two u64 reserves
+    assert_eq!(math::sqrt(0), 0);
+    assert_eq!(math::sqrt(15), 3);
+    assert_eq!(math::sqrt(16), 4);
+    assert_eq!(math::sqrt(max * max), max);
+    assert_eq!(math::sqrt(u128::MAX), max);
+}
+
+#[test]
+fn test_instruction_packing() {
+    use solana_amm::instruction::{AMMInstruction, FlashLoanCallback};
+
+    let key = Pubkey::new_unique();
+    let instructions = vec![
+        AMMInstruction::Initialize {
+            pool_index: 1,
+            fee_numerator: 30,
+            fee_denominator: 10_000,
+            protocol_fee_numerator: 1,
+            protocol_fee_denominator: 6,
+            curve: CurveParameters::ConstantProduct,
+        },
+        AMMInstruction::Initialize {
+            pool_index: 2,
+            fee_numerator: 4,
+            fee_denominator: 10_000,
+            protocol_fee_numerator: 0,
+            protocol_fee_denominator: 1,
+            curve: CurveParameters::StableSwap { amp: 100 },
+        },
+        AMMInstruction::Initialize {
+            pool_index: u16::MAX,
+            fee_numerator: 0,
+            fee_denominator: 1,
+            protocol_fee_numerator: 0,
+            protocol_fee_denominator: 1,
+            curve: CurveParameters::Weighted {
+                x_weight: 80,
+                y_weight: 20,
+            },
+        },
+        AMMInstruction::Exchange {
+            amount_in: 10,
+            minimum_amount_out: 9,
+            expires_at: None,
+        },
+        AMMInstruction::Exchange {
+            amount_in: 10,
+            minimum_amount_out: 9,
+            expires_at: Some(-1),
+        },
+        AMMInstruction::ExchangeExactOut {
+            amount_out: 8,
+            maximum_amount_in: u64::MAX,
+        },
+        AMMInstruction::AddLiquidity {
+            lp_amount: 7,
+            maximum_x_amount: 6,
+            maximum_y_amount: 5,
+        },
+        AMMInstruction::RemoveLiquidity {
+            lp_amount: 4,
+            minimum_x_amount: 3,
+            minimum_y_amount: 2,
+        },
+        AMMInstruction::CollectProtocolFees,
+        AMMInstruction::SetAuthority { new_authority: key },
+        AMMInstruction::AcceptAuthority,
+        AMMInstruction::SetFees {
+            fee_numerator: 25,
+            fee_denominator: 10_000,
+            protocol_fee_numerator: 1,
+            protocol_fee_denominator: 5,
+        },
+        AMMInstruction::Pause,
+        AMMInstruction::Unpause,
+        AMMInstruction::RampAmplification {
+            target_amp: 200,
+            ramp_stop_ts: -1,
+        },
+        AMMInstruction::InitializeConcentrated {
+            pool_index: 3,
+            fee_numerator: 5,
+            fee_denominator: 10_000,
+            tick_spacing: 60,
+            initial_sqrt_price: u128::MAX,
+        },
+        AMMInstruction::OpenPosition {
+            tick_lower: -120,
+            tick_upper: 60,
+        },
+        AMMInstruction::IncreaseLiquidity {
+            liquidity: 1 << 100,
+            maximum_x_amount: 11,
+            maximum_y_amount: 12,
+        },
+        AMMInstruction::DecreaseLiquidity {
+            liquidity: 13,
+            minimum_x_amount: 14,
+            minimum_y_amount: 15,
+        },
+        AMMInstruction::CollectPositionFees,
+        AMMInstruction::ExchangeConcentrated {
+            amount_in: 16,
+            minimum_amount_out: 17,
+        },
+        AMMInstruction::RouteExchange {
+            amount_in: 18,
+            minimum_amount_out: 19,
+        },
+        AMMInstruction::InitializeObservations,
+        AMMInstruction::GrowObservations,
+        AMMInstruction::FlashLoan {
+            amount: 20,
+            token: key,
+        },
+        AMMInstruction::Sync,
+        AMMInstruction::Skim,
+        AMMInstruction::MigrateState { pool_index: 2 },
+    ];
+
+    let invalid_data = ProgramError::Custom(AMMErrors::InvalidInstructionData as u32);
+
+    for instruction in instructions {
+        let data = instruction.pack();
+        assert_eq!(AMMInstruction::unpack(&data), Ok(instruction));
+
+        // Every field is required and nothing may follow them
+        for len in 1..data.len() {
+            assert_eq!(
+                AMMInstruction::unpack(&data[..len]),
+                Err(invalid_data.clone())
+            );
+        }
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert_eq!(AMMInstruction::unpack(&trailing), Err(invalid_data.clone()));
+    }
+
+    // A tag byte followed by the fields in little-endian
+    assert_eq!(
+        AMMInstruction::Exchange {
+            amount_in: 0x0102,
+            minimum_amount_out: 3,
+            expires_at: None,
+        }
+        .pack(),
+        [1, 2, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+
+    assert_eq!(AMMInstruction::unpack(&[]), Err(invalid_data.clone()));
+
+    // Tags past the last variant, with or without data after them
+    for data in [&[25][..], &[25, 0, 0], &[u8::MAX]] {
+        assert_eq!(
+            AMMInstruction::unpack(data),
+            Err(ProgramError::Custom(
+                AMMErrors::InvalidInstructionMethodID as u32
+            ))
+        );
+    }
+
+    let callback = FlashLoanCallback { amount: 10, fee: 1 };
+    let mut data = callback.pack();
+    assert_eq!(FlashLoanCallback::unpack(&data), Ok(callback));
+    data.push(0);
+    assert_eq!(FlashLoanCallback::unpack(&data), Err(invalid_data));
+}
+
+#[test]
+fn test_constant_product_curve() {
+    use solana_amm::curve::{ConstantProductCurve, SwapCurve, TradeDirection};
+
+    let curve = ConstantProductCurve;
+
+    // 100 Y buys 100 X * 100 Y / 10_100 Y, 1 X costs 10_000 Y * 1 X / 99 X
+    assert_eq!(
+        curve.swap_out(
+            100 * ONE_Y,
+            10_000 * ONE_Y,
+            100 * ONE_X,
+            TradeDirection::YtoX
+        ),
+        Ok(99_009_900)
+    );
+    assert_eq!(
+        curve.swap_in(ONE_X, 10_000 * ONE_Y, 100 * ONE_X, TradeDirection::YtoX),
+        Ok(1_010_102)
+    );
+
+    // Deposits round up and withdrawals round down
+    assert_eq!(curve.deposit_amounts(1, 3, 10, 20), Ok((4, 7)));
+    assert_eq!(curve.withdraw_amounts(1, 3, 10, 20), Ok((3, 6)));
+
+    // Unknown curve types are rejected when unpacking `Initialize`
+    let mut data = solana_amm::instruction::AMMInstruction::Initialize {
+        pool_index: 0,
+        fee_numerator: 0,
+        fee_denominator: 1,
+        protocol_fee_numerator: 0,
+        protocol_fee_denominator: 1,
+        curve: CurveParameters::ConstantProduct,
+    }
+    .pack();
+    *data.last_mut().unwrap() = u8::MAX;
+    assert_eq!(
+        solana_amm::instruction::AMMInstruction::unpack(&data).unwrap_err(),
+        ProgramError::Custom(AMMErrors::InvalidInstructionData as u32)
+    );
+}
+
+#[test]
+fn test_weighted_curve() {
+    use solana_amm::curve::{SwapCurve, TradeDirection, WeightedCurve};
+
+    // Even weights trade like the constant product, less the power rounding
+    let even = WeightedCurve {
+        x_weight: 50,
+        y_weight: 50,
+    };
+    assert_eq!(even.swap_out(33, 100, 1_000, TradeDirection::XtoY), Ok(248));
+
+    // 10 Y out of 100 in an 80/20 pool cost 100 * ((100 / 90)^(1 / 4) - 1) = 2.67 X
+    let weighted = WeightedCurve {
+        x_weight: 80,
+        y_weight: 20,
+    };
+    assert_eq!(weighted.swap_in(10, 100, 100, TradeDirection::XtoY), Ok(3));
+
+    // Trades are capped at half of the input and a third of the output reserve
+    let too_large = Err(ProgramError::Custom(
+        AMMErrors::InsufficientLiquidity as u32,
+    ));
+    assert_eq!(
+        weighted.swap_out(51, 100, 100, TradeDirection::XtoY),
+        too_large
+    );
+    assert_eq!(
+        weighted.swap_in(34, 100, 100, TradeDirection::XtoY),
+        too_large
+    );
+}
+
+#[test]
+fn test_spot_price() {
+    use solana_amm::{
+        curve::{ConstantProductCurve, StableSwapCurve, SwapCurve, WeightedCurve},
+        math::Q64,
+        oracle::{self, Observation},
+    };
+
+    assert_eq!(ConstantProductCurve.spot_price(100, 400), Ok(4 * Q64));
+
+    // An 80/20 pool holding four times more value in X is at its 1:1 price
+    let weighted = WeightedCurve {
+        x_weight: 80,
+        y_weight: 20,
+    };
+    assert_eq!(weighted.spot_price(400, 100), Ok(Q64));
+
+    // A balanced StableSwap pool trades at the peg in whole tokens, and an
+    // unbalanced one stays much closer to it than the reserve ratio
+    let stable = StableSwapCurve {
+        amp: 100,
+        x_decimals: X_DECIMALS as u8,
+        y_decimals: Y_DECIMALS as u8,
+    };
+    assert_eq!(
+        stable.spot_price(100 * ONE_X, 100 * ONE_Y),
+        Ok(Q64 / 10_000)
+    );
+    let price = stable.spot_price(110 * ONE_X, 90 * ONE_Y).unwrap() * 10_000;
+    assert!(price < Q64 && price > Q64 * 99 / 100);
+
+    // Accumulators wrap around, their difference still gives the average
+    let start = Observation {
+        timestamp: 100,
+        price_x_cumulative: u128::MAX - 9,
+        price_y_cumulative: 0,
+    };
+    let end = Observation {
+        timestamp: 104,
+        price_x_cumulative: 10,
+        price_y_cumulative: 8,
+    };
+    assert_eq!(oracle::twap(&start, &end), Some((5, 2)));
+}
+
+#[test]
+fn test_tick_math() {
+    use solana_amm::concentrated::{
+        sqrt_price_at_tick, tick_at_sqrt_price, MAX_SQRT_PRICE, MAX_TICK, MIN_SQRT_PRICE, MIN_TICK,
+    };
+
+    assert_eq!(sqrt_price_at_tick(0), Ok(1 << 64));
+    assert_eq!(sqrt_price_at_tick(MIN_TICK), Ok(MIN_SQRT_PRICE));
+    assert_eq!(sqrt_price_at_tick(MAX_TICK), Ok(MAX_SQRT_PRICE));
+    assert_eq!(
+        sqrt_price_at_tick(MAX_TICK + 1),
+        Err(ProgramError::Custom(AMMErrors::InvalidTickRange as u32))
+    );
+
+    // 1.0001^(100 / 2) = 1.00501227..
+    assert_eq!(sqrt_price_at_tick(100), Ok(18_539_204_128_674_405_814));
+
+    for tick in [MIN_TICK, -100_001, -1, 0, 1, 139, 100_001, MAX_TICK - 1] {
+        let sqrt_price = sqrt_price_at_tick(tick).unwrap();
+        assert_eq!(tick_at_sqrt_price(sqrt_price), Ok(tick));
+        assert_eq!(
+            tick_at_sqrt_price(sqrt_price_at_tick(tick + 1).unwrap() - 1),
+            Ok(tick)
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn initialize_pool(
+    payer: &Keypair,
+    banks_client: &mut BanksClient,
+    recent_blockhash: Hash,
+    program_id: &Pubkey,
+    (x_mint, x_acc, x_amount): (&Pubkey, &Pubkey, u64),
+    (y_mint, y_acc, y_amount): (&Pubkey, &Pubkey, u64),
+    pool_index: u16,
+    fees: (u64, u64),
+    protocol_fees: (u64, u64),
+    curve: CurveParameters,
+) -> Result<Pubkey, TransportError> {
+    let temp_x_address = create_token_account(payer, x_mint, banks_client, recent_blockhash).await;
+    transfer_tokens(
+        payer,
+        banks_client,
+        recent_blockhash,
+        x_acc,
+        &temp_x_address,
+        x_amount,
+    )
+    .await;
+
+    let temp_y_address = create_token_account(payer, y_mint, banks_client, recent_blockhash).await;
+    transfer_tokens(
+        payer,
+        banks_client,
+        recent_blockhash,
+        y_acc,
+        &temp_y_address,
+        y_amount,
+    )
+    .await;
+
+    let owner_lp_keypair = Keypair::new();
+    let init_instruction = solana_amm::instruction::initialize_amm(
+        &payer.pubkey(),
+        &temp_x_address,
+        x_mint,
+        &temp_y_address,
+        y_mint,
+        &owner_lp_keypair.pubkey(),
+        pool_index,
+        fees,
+        protocol_fees,
+        curve,
+        program_id,
+        &spl_token::id(),
+    );
+
+    let mut transaction = Transaction::new_with_payer(&[init_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[payer, &owner_lp_keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await?;
+
+    Ok(owner_lp_keypair.pubkey())
+}
+
+async fn amm_data_of(address: Pubkey, banks_client: &mut BanksClient) -> solana_amm::state::AMM {
+    solana_amm::state::AMM::unpack(
+        banks_client
+            .get_account(address)
+            .await
+            .unwrap()
+            .unwrap()
+            .data(),
+    )
+    .unwrap()
+}
+
+async fn observations_of(
+    address: Pubkey,
+    banks_client: &mut BanksClient,
+) -> solana_amm::state::Observations {
+    solana_amm::state::Observations::unpack(
+        banks_client
+            .get_account(address)
+            .await
+            .unwrap()
+            .unwrap()
+            .data(),
+    )
+    .unwrap()
+}
+
+async fn concentrated_pool_of(
+    address: Pubkey,
+    banks_client: &mut BanksClient,
+) -> solana_amm::state::ConcentratedPool {
+    solana_amm::state::ConcentratedPool::unpack(
+        banks_client
+            .get_account(address)
+            .await
+            .unwrap()
+            .unwrap()
+            .data(),
+    )
+    .unwrap()
+}
+
+async fn position_of(
+    address: Pubkey,
+    banks_client: &mut BanksClient,
+) -> solana_amm::state::Position {
+    solana_amm::state::Position::unpack(
+        banks_client
+            .get_account(address)
+            .await
+            .unwrap()
+            .unwrap()
+            .data(),
+    )
+    .unwrap()
+}
+
+async fn mint_token(
+    payer: &Keypair,
+    banks_client: &mut BanksClient,
+    recent_blockhash: Hash,
+    decimals: u8,
+) -> (Pubkey, Pubkey) {
+    let mint_keypair = Keypair::new();
+
+    let rent = banks_client.get_rent().await.unwrap();
+    let minimum_balance = rent.minimum_balance(spl_token::state::Mint::LEN);
+
+    let create_mint_account_instruction = system_instruction::create_account(
+        &payer.pubkey(),
+        &mint_keypair.pubkey(),
+        minimum_balance,
+        spl_token::state::Mint::LEN as u64,
+        &spl_token::ID,
+    );
+
+    let init_mint_instruction = spl_token::instruction::initialize_mint(
+        &spl_token::ID,
+        &mint_keypair.pubkey(),
+        &payer.pubkey(),
+        None,
+        decimals,
+    )
+    .unwrap();
+
+    let token_account_keypair = Keypair::new();
+    let minimum_balance = rent.minimum_balance(spl_token::state::Account::LEN);
+
+    let create_token_account_instruction = system_instruction::create_account(
+        &payer.pubkey(),
+        &token_account_keypair.pubkey(),
+        minimum_balance,
+        spl_token::state::Account::LEN as u64,
+        &spl_token::ID,
+    );
+
+    let init_acccount_instruction = spl_token::instruction::initialize_account(
+        &spl_token::ID,
+        &token_account_keypair.pubkey(),
+        &mint_keypair.pubkey(),
+        &payer.pubkey(),
+    )
+    .unwrap();
+
+    let mint_instruction = spl_token::instruction::mint_to(
+        &spl_token::ID,
+        &mint_keypair.pubkey(),
+        &token_account_keypair.pubkey(),
+        &payer.pubkey(),
+        &[&payer.pubkey()],
+        10_000_000 * 10u64.pow(decimals as u32),
+    )
+    .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_mint_account_instruction,
+            init_mint_instruction,
+            create_token_account_instruction,
+            init_acccount_instruction,
+            mint_instruction,
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(
+        &[payer, &mint_keypair, &token_account_keypair],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    (mint_keypair.pubkey(), token_account_keypair.pubkey())
+}
+
+async fn create_token_account(
+    payer: &Keypair,
+    mint: &Pubkey,
+    banks_client: &mut BanksClient,
+    recent_blockhash: Hash,
+) -> Pubkey {
+    let rent = banks_client.get_rent().await.unwrap();
+
+    let token_account_keypair = Keypair::new();
+    let minimum_balance = rent.minimum_balance(spl_token::state::Account::LEN);
+
+    let create_token_account_instruction = system_instruction::create_account(
+        &payer.pubkey(),
+        &token_account_keypair.pubkey(),
+        minimum_balance,
+        spl_token::state::Account::LEN as u64,
+        &spl_token::ID,
+    );
+
+    let init_acccount_instruction = spl_token::instruction::initialize_account(
+        &spl_token::ID,
+        &token_account_keypair.pubkey(),
+        mint,
+        &payer.pubkey(),
+    )
+    .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[create_token_account_instruction, init_acccount_instruction],
+        Some(&payer.pubkey()),
+    );
+
+    transaction.sign(&[payer, &token_account_keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    token_account_keypair.pubkey()
+}
+
+async fn create_observations_account(
+    payer: &Keypair,
+    banks_client: &mut BanksClient,
+    recent_blockhash: Hash,
+    program_id: &Pubkey,
+    capacity: usize,
+) -> Pubkey {
+    let rent = banks_client.get_rent().await.unwrap();
+
+    let observations_keypair = Keypair::new();
+    let space = solana_amm::state::Observations::HEADER_LEN
+        + capacity * solana_amm::state::PriceSample::LEN;
+
+    let create_account_instruction = system_instruction::create_account(
+        &payer.pubkey(),
+        &observations_keypair.pubkey(),
+        rent.minimum_balance(space),
+        space as u64,
+        program_id,
+    );
+
+    let mut transaction =
+        Transaction::new_with_payer(&[create_account_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[payer, &observations_keypair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    observations_keypair.pubkey()
+}
+
+async fn transfer_tokens(
+    payer: &Keypair,
+    banks_client: &mut BanksClient,
+    recent_blockhash: Hash,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    amount: u64,
+) {
+    let transfer_instruction = spl_token::instruction::transfer(
+        &spl_token::ID,
+        sender,
+        recipient,
+        &payer.pubkey(),
+        &[&payer.pubkey()],
+        amount,
+    )
+    .unwrap();
+
+    let mut transaction =
+        Transaction::new_with_payer(&[transfer_instruction], Some(&payer.pubkey()));
+    transaction.sign(&[payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+/// Token account holding `amount`, to add before the test starts.
+fn token_account(mint: Pubkey, owner: Pubkey, amount: u64) -> Account {
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint,
+        owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..spl_token::state::Account::default()
+    }
+    .pack_into_slice(&mut data);
+
+    Account {
+        lamports: LAMPORTS_PER_SOL,
+        data,
+        owner: spl_token::id(),
+        ..Account::default()
+    }
+}
+
+/// Runs an `Exchange` on the `pool_index`-th pool of the mints, stored with
+/// the given pool, X vault and Y vault bump seeds, in a compute budget of
+/// `max_units` if any. The budget applies to every transaction, so the pool
+/// and token accounts are added as they would be after `Initialize` and the
+/// exchange is the only instruction run.
+async fn exchange_on_pool(
+    program_id: Pubkey,
+    (x_mint, y_mint): (Pubkey, Pubkey),
+    pool_index: u16,
+    [bump_seed, x_vault_bump_seed, y_vault_bump_seed]: [u8; 3],
+    max_units: Option<u64>,
+) -> Result<(), TransportError> {
+    let mut program_test = ProgramTest::new(
+        "solana_amm",
+        program_id,
+        processor!(solana_amm::entrypoint::process_instruction),
+    );
+    if let Some(max_units) = max_units {
+        program_test.set_bpf_compute_max_units(max_units);
+    }
+
+    let (amm_data_account, _) =
+        solana_amm::pda::find_pool_address(&program_id, &x_mint, &y_mint, pool_index);
+    let (x_vault, _) = solana_amm::pda::find_x_vault_address(&program_id, &amm_data_account);
+    let (y_vault, _) = solana_amm::pda::find_y_vault_address(&program_id, &amm_data_account);
+    let (lp_mint, lp_mint_bump_seed) =
+        solana_amm::pda::find_lp_mint_address(&program_id, &amm_data_account);
+
+    let mut amm_data = vec![0; solana_amm::state::AMM::LEN];
+    solana_amm::state::AMM {
+        version: solana_amm::state::AMM::VERSION,
+        is_initialized: 1,
+        pool_index,
+        x_mint,
+        x_amount: 100 * ONE_X,
+        y_mint,
+        y_amount: 400 * ONE_Y,
+        lp_mint,
+        fee_numerator: 30,
+        fee_denominator: 10_000,
+        protocol_fee_numerator: 1,
+        protocol_fee_denominator: 6,
+        authority: Pubkey::new_unique(),
+        curve_type: CurveType::ConstantProduct as u8,
+        x_decimals: X_DECIMALS as u8,
+        y_decimals: Y_DECIMALS as u8,
+        bump_seed,
+        x_vault_bump_seed,
+        y_vault_bump_seed,
+        lp_mint_bump_seed,
+        ..bytemuck::Zeroable::zeroed()
+    }
+    .pack_into_slice(&mut amm_data);
+
+    program_test.add_account(
+        amm_data_account,
+        Account {
+            lamports: LAMPORTS_PER_SOL,
+            data: amm_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(x_vault, token_account(x_mint, x_vault, 100 * ONE_X));
+    program_test.add_account(y_vault, token_account(y_mint, y_vault, 400 * ONE_Y));
+
+    let user_keypair = Keypair::new();
+    let (user_x, user_y) = (Pubkey::new_unique(), Pubkey::new_unique());
+    program_test.add_account(
+        user_keypair.pubkey(),
+        Account {
+            lamports: LAMPORTS_PER_SOL,
+            data: vec![],
+            owner: system_program::ID,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(user_x, token_account(x_mint, user_keypair.pubkey(), ONE_X));
+    program_test.add_account(user_y, token_account(y_mint, user_keypair.pubkey(), 0));
+
+    let (mut banks_client, _, recent_blockhash) = program_test.start().await;
+
+    let exchange_instruction = solana_amm::instruction::exchange(
+        &user_keypair.pubkey(),
+        &amm_data_account,
+        &Pubkey::default(),
+        &user_x,
+        &user_y,
+        ONE_X,
+        0,
+        None,
+        &spl_token::id(),
+        &program_id,
+    );
+    let mut transaction =
+        Transaction::new_with_payer(&[exchange_instruction], Some(&user_keypair.pubkey()));
+    transaction.sign(&[&user_keypair], recent_blockhash);
+
+    banks_client.process_transaction(transaction).await
+}
+
+async fn balance_of(address: Pubkey, banks_client: &mut BanksClient) -> u64 {
+    spl_token::state::Account::unpack(
+        banks_client
+            .get_account(address)
+            .await
+            .unwrap()
+            .unwrap()
+            .data(),
+    )
+    .unwrap()
+    .amount as u64
+}
+
+fn flash_loan_receiver(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    input: &[u8],
+) -> ProgramResult {
+    repay_flash_loan(accounts, input, true)
+}
+
+fn defaulting_flash_loan_receiver(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    input: &[u8],
+) -> ProgramResult {
+    repay_flash_loan(accounts, input, false)
+}
+
+/// Pays a flash loan back from the borrower token account, with or without
+/// the fee.
+fn repay_flash_loan(accounts: &[AccountInfo], input: &[u8], with_fee: bool) -> ProgramResult {
+    let callback = solana_amm::instruction::FlashLoanCallback::unpack(input)?;
+    let accounts_iter = &mut accounts.iter();
+
+    let borrower_token_account = next_account_info(accounts_iter)?;
+    let token_vault = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let borrower_account = next_account_info(accounts_iter)?;
+
+    let amount = if with_fee {
+        callback.amount + callback.fee
+    } else {
+        callback.amount
+    };
+
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            borrower_token_account.key,
+            token_vault.key,
+            borrower_account.key,
+            &[],
+            amount,
+        )?,
+        &[
+            borrower_token_account.clone(),
+            token_vault.clone(),
+            borrower_account.clone(),
+            token_program.clone(),
+        ],
+    )
+}
+
+async fn assert_amm_error(
+    banks_client: &mut BanksClient,
+    transaction: Transaction,
+    error: AMMErrors,
+) {
+    assert_eq!(
+        banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+    );
 }